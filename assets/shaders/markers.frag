@@ -0,0 +1,10 @@
+#version 410 core
+
+in vec4 passColor;
+
+out vec4 Color;
+
+void main()
+{
+    Color = passColor;
+}
\ No newline at end of file
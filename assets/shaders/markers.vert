@@ -0,0 +1,14 @@
+#version 410 core
+
+layout (location = 0) in vec3 Position;
+layout (location = 1) in vec3 Color;
+
+out vec4 passColor;
+
+uniform mat4 mvp_transform;
+
+void main()
+{
+    gl_Position = mvp_transform * vec4(Position, 1.0);
+    passColor = vec4(Color, 1.);
+}
\ No newline at end of file
@@ -15,6 +15,8 @@ vec4 remap(in float iMin, in float iMax, in vec4 oMin, in vec4 oMax, in float v)
     return mix(oMin, oMax, t);
 }
 
+const vec4 DEEP = vec4(0.1, 0.15, 0.3, 1.);
+const vec4 SAND = vec4(0.76, 0.7, 0.5, 1.);
 const vec4 GRASS = vec4(0., .8, 0., 1.);
 const vec4 ROCK = vec4(0.3, 0.22, 0.2, 1.);
 const vec4 SNOW = vec4(0.9, 0.9, 0.9, 1.);
@@ -32,7 +34,10 @@ void main()
 //
 //    passColor = vec4(red, green, blue, 1.);
 
-    if (height < ROCK_LEVEL) { // TODO: add to uniform
+    if (height < 0.) { // Sea floor
+        passColor = remap(-1., 0., DEEP, SAND, height);
+    }
+    else if (height < ROCK_LEVEL) { // TODO: add to uniform
         passColor = remap(0., ROCK_LEVEL, GRASS, ROCK, height);
     }
     else {
@@ -19,6 +19,7 @@ impl From<io::Error> for Error {
     }
 }
 
+#[derive(Clone)]
 pub struct Resources {
     root_path: PathBuf,
 }
@@ -34,6 +35,21 @@ impl Resources {
         })
     }
 
+    pub fn resource_path(&self, resource_name: &str) -> PathBuf {
+        resource_name_to_path(&self.root_path, resource_name)
+    }
+
+    pub fn load_bytes(&self, resource_name: &str) -> Result<Vec<u8>, Error> {
+        let mut file = fs::File::open(
+            resource_name_to_path(&self.root_path, resource_name)
+        )?;
+        let mut buffer: Vec<u8> = Vec::with_capacity(
+            file.metadata()?.len() as usize
+        );
+        file.read_to_end(&mut buffer)?;
+        Ok(buffer)
+    }
+
     pub fn load_cstring(&self, resource_name: &str) -> Result<ffi::CString, Error> {
         let mut file = fs::File::open(
             resource_name_to_path(&self.root_path, resource_name)
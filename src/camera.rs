@@ -1,4 +1,9 @@
 use na;
+use crate::game_data::grid::Aspect;
+
+const FRAME_HALF_WIDTH: f32 = 1.41;
+const FRAME_BOTTOM: f32 = -2.5;
+const FRAME_TOP: f32 = 1.;
 
 #[derive(Copy, Clone, Debug)]
 #[repr(C, packed)]
@@ -7,28 +12,26 @@ pub struct MVP {
     view_rotation: na::Matrix4<f32>,
     view_translation: na::Matrix4<f32>,
     projection: na::Matrix4<f32>,
+    frame_scale: f32,
 }
 
 impl MVP {
-    pub fn new() -> MVP {
-
-        let model = na::Isometry3::identity();
-
-        let mut model: na::Matrix4<f32> = model.to_homogeneous() * 1.;
-        model[15] = 1.;
+    // The model is stretched to the world aspect, the frame shrinks with its diagonal
+    pub fn new(aspect: &Aspect) -> MVP {
+        let (model, frame_scale) = aspect_model(aspect);
 
         let view_rotation: na::Matrix4<f32> = na::Isometry3::rotation(na::Vector3::y() * 3.14 / 3.).to_homogeneous();
         let view_rotation = na::Isometry3::rotation(na::Vector3::x() * 3.14 / 3.).to_homogeneous() * view_rotation;
         let view_translation: na::Matrix4<f32> = na::Isometry3::translation(0., -1., -2.).to_homogeneous();
 
-        let projection: na::Matrix4<f32> = na::Orthographic3::new(-1.41, 1.41, -2.5, 1., -30., 30.)
-            .to_homogeneous();
+        let projection = frame_projection(frame_scale, 1.);
 
         MVP {
             model,
             view_rotation,
             view_translation,
             projection,
+            frame_scale,
         }
     }
 
@@ -36,11 +39,26 @@ impl MVP {
         self.projection * self.view_translation * self.view_rotation * self.model
     }
 
+    // Segment between the near and far planes under a point of the screen, in model space.
+    // ndc_x and ndc_y are in [-1;1], y goes up
+    pub fn unproject(&self, ndc_x: f32, ndc_y: f32) -> Option<(na::Point3<f32>, na::Point3<f32>)> {
+        let inverse = self.get_transform().try_inverse()?;
+        let near = inverse.transform_point(&na::Point3::new(ndc_x, ndc_y, -1.));
+        let far = inverse.transform_point(&na::Point3::new(ndc_x, ndc_y, 1.));
+        Some((near, far))
+    }
+
+    // Projection_recalc has to follow, the frame depends on the aspect
+    pub fn set_aspect(&mut self, aspect: &Aspect) {
+        let (model, frame_scale) = aspect_model(aspect);
+        self.model = model;
+        self.frame_scale = frame_scale;
+    }
+
     pub fn projection_recalc(&mut self, w: i32, h: i32) {
         let aspect: f32 = (w) as f32 / (h) as f32;
         println!("aspect: {}", aspect);
-        self.projection = na::Orthographic3::new(-1.41  * aspect, 1.41 * aspect, -2.5, 1., -30., 30.)
-            .to_homogeneous();
+        self.projection = frame_projection(self.frame_scale, aspect);
     }
 
     pub fn view_rotate_naviball(&mut self, naviball: na::Vector2<f32>) {
@@ -50,3 +68,15 @@ impl MVP {
         self.view_rotation = rot_total * self.view_rotation;
     }
 }
+
+fn aspect_model(aspect: &Aspect) -> (na::Matrix4<f32>, f32) {
+    let model: na::Matrix4<f32> = na::Matrix4::new_nonuniform_scaling(&na::Vector3::new(aspect.x, 1., aspect.z));
+    let frame_scale = (aspect.x * aspect.x + aspect.z * aspect.z).sqrt() / 2_f32.sqrt();
+    (model, frame_scale)
+}
+
+fn frame_projection(frame_scale: f32, aspect: f32) -> na::Matrix4<f32> {
+    let half_width = FRAME_HALF_WIDTH * aspect * frame_scale;
+    na::Orthographic3::new(-half_width, half_width, FRAME_BOTTOM * frame_scale, FRAME_TOP * frame_scale, -30., 30.)
+        .to_homogeneous()
+}
@@ -0,0 +1,176 @@
+use std::str::FromStr;
+use crate::game_data::Resolution;
+use crate::game_data::grid::procedural::TerrainKind;
+use crate::game_data::grid::filters::Filter;
+use crate::game_data::UpdateMode;
+use crate::game_data::grid::{Aspect, BoundaryMode, GridOptions, Normalization, PointFile, VariogramModel};
+
+pub const USAGE: &str = "\
+Usage: mod1 [grid_file [placement]]... [options]
+    grid_file               .mod1 point file, .pgm/.png heightmap or .asc/.xyz raster inside assets/grids
+                            (default: grid.mod1). Several .mod1 files are merged into one set of points
+Placement of the preceding grid file, in its source units:
+    --translate <x>,<y>,<z> move the points
+    --rotate <degrees>      rotate the points around Y, counterclockwise seen from above
+    --scale <factor>        scale the points around the file origin
+Options:
+    --generate <kind>       procedural terrain instead of a file: noise, diamond-square, valley
+    --terrain-seed <value>  seed of the procedural terrain (random if omitted)
+    --seed <value>          seed of the water simulation, rain and particle moves (random if omitted)
+    --update <mode>         water update: in-place (particles see the moves made before them in the same
+                            step) or double-buffered (every particle reads the previous step) (default: in-place)
+    --resolution <r>[:<h>]  terrain samples, <r> is <size> for the longer side or <x>x<z>, and water layers
+                            (default: 200:100, <h> is half of the longer side if omitted)
+    --aspect <x>:<z>        world proportions of the terrain sides (default: from the source)
+    --normalize <mode>      point coordinates: strict (must be in the canonical cube), uniform, per-axis
+                            (default: strict)
+    --boundary <mode>       terrain border: zero[:count], clamp[:count], mirror, none (default: zero:30)
+    --variogram <model>     kriging variogram model: spherical, exponential, gaussian
+    --nugget <value>        kriging variogram nugget (fitted if omitted)
+    --sill <value>          kriging variogram sill (fitted if omitted)
+    --range <value>         kriging variogram range (fitted if omitted)
+    --rbf-epsilon <value>   RBF kernel shape parameter (derived from pole spacing if omitted)
+    --rbf-smoothing <value> RBF smoothing, 0 interpolates the points exactly (default: 0)
+    --neighbours <count>    evaluate every cell over its nearest points only (default: all points)
+    --search-radius <value> evaluate every cell over the points within this distance (default: unlimited)
+    --sea-level <height>    fill still water up to this height at startup and on flush, in the height units
+                            of the source (point files may go down to -1 for sea floors)
+    --filter <filter>       post-process the terrain, repeat to chain: gaussian[:sigma], median[:radius],
+                            terrace[:levels], scale:<factor>[:<offset>], floor:<height> (sizes in cells)
+    --snapshot <file>       start from a snapshot inside assets/snapshots, saved with F5
+    --record <file>         write every simulation action with its frame to a replay file inside assets/replays
+    --replay <file>         feed the actions of a replay file inside assets/replays back at their frames,
+                            with its seeds
+    --cross-validate        print leave-one-out errors of every griding algorithm for the point file and exit
+    --cross-validate-csv <file>
+                            same, also write per point predictions to a CSV file";
+
+#[derive(Fail, Debug)]
+pub enum Error {
+    #[fail(display = "Unknown option: {}", name)]
+    UnknownOption { name: String },
+    #[fail(display = "Option {} requires a value", name)]
+    MissingValue { name: String },
+    #[fail(display = "Invalid value for option {}: {}", name, value)]
+    InvalidValue { name: String, value: String },
+    #[fail(display = "Option {} must follow a grid file", name)]
+    NoGridFile { name: String },
+}
+
+pub struct Config {
+    pub grid_files: Vec<PointFile>,
+    pub grid_options: GridOptions,
+    pub resolution: Resolution,
+    pub procedural: Option<TerrainKind>,
+    pub terrain_seed: Option<u64>,
+    pub seed: Option<u64>,
+    pub update_mode: UpdateMode,
+    pub filters: Vec<Filter>,
+    pub sea_level: Option<f64>,
+    pub snapshot: Option<String>,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    pub cross_validation: bool,
+    pub cross_validation_csv: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            grid_files: vec![],
+            grid_options: GridOptions::default(),
+            resolution: Resolution::default(),
+            procedural: None,
+            terrain_seed: None,
+            seed: None,
+            update_mode: UpdateMode::InPlace,
+            filters: vec![],
+            sea_level: None,
+            snapshot: None,
+            record: None,
+            replay: None,
+            cross_validation: false,
+            cross_validation_csv: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args(args: &[String]) -> Result<Config, Error> {
+        let mut config = Config::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let options = &mut config.grid_options;
+            match arg.as_str() {
+                "--resolution" => config.resolution = parse_value(arg, args.next())?,
+                "--generate" => config.procedural = Some(parse_value(arg, args.next())?),
+                "--terrain-seed" => config.terrain_seed = Some(parse_value(arg, args.next())?),
+                "--seed" => config.seed = Some(parse_value(arg, args.next())?),
+                "--update" => config.update_mode = parse_value(arg, args.next())?,
+                "--sea-level" => config.sea_level = Some(parse_value(arg, args.next())?),
+                "--snapshot" => config.snapshot = Some("snapshots/".to_owned() + &parse_value::<String>(arg, args.next())?),
+                "--record" => config.record = Some("replays/".to_owned() + &parse_value::<String>(arg, args.next())?),
+                "--replay" => config.replay = Some("replays/".to_owned() + &parse_value::<String>(arg, args.next())?),
+                "--filter" => config.filters.push(parse_value(arg, args.next())?),
+                "--cross-validate" => config.cross_validation = true,
+                "--cross-validate-csv" => {
+                    config.cross_validation = true;
+                    config.cross_validation_csv = Some(parse_value(arg, args.next())?);
+                },
+                "--aspect" => options.aspect = Some(parse_value::<Aspect>(arg, args.next())?),
+                "--normalize" => options.normalization = parse_value::<Normalization>(arg, args.next())?,
+                "--boundary" => options.boundary = parse_value::<BoundaryMode>(arg, args.next())?,
+                "--variogram" => options.variogram.model = parse_value::<VariogramModel>(arg, args.next())?,
+                "--nugget" => options.variogram.nugget = Some(parse_value(arg, args.next())?),
+                "--sill" => options.variogram.sill = Some(parse_value(arg, args.next())?),
+                "--range" => options.variogram.range = Some(parse_value(arg, args.next())?),
+                "--rbf-epsilon" => options.rbf_epsilon = Some(parse_value(arg, args.next())?),
+                "--rbf-smoothing" => options.rbf_smoothing = parse_value(arg, args.next())?,
+                "--neighbours" => options.neighbourhood.count = Some(parse_value(arg, args.next())?),
+                "--search-radius" => options.neighbourhood.radius = Some(parse_value(arg, args.next())?),
+                "--translate" => {
+                    let translation = parse_translation(arg, args.next())?;
+                    last_file(&mut config.grid_files, arg)?.transform.translation = translation;
+                },
+                "--rotate" => {
+                    let degrees = parse_value(arg, args.next())?;
+                    last_file(&mut config.grid_files, arg)?.transform.rotation_y = degrees;
+                },
+                "--scale" => {
+                    let scale = parse_value(arg, args.next())?;
+                    last_file(&mut config.grid_files, arg)?.transform.scale = scale;
+                },
+                name if name.starts_with("--") => return Err(Error::UnknownOption { name: name.into() }),
+                _ => config.grid_files.push(PointFile::new(&("grids/".to_owned() + arg))),
+            }
+        }
+
+        if config.grid_files.is_empty() {
+            config.grid_files.push(PointFile::new("grids/grid.mod1"));
+        }
+        Ok(config)
+    }
+}
+
+fn last_file<'a>(files: &'a mut [PointFile], name: &str) -> Result<&'a mut PointFile, Error> {
+    files.last_mut().ok_or_else(|| Error::NoGridFile { name: name.into() })
+}
+
+// "<x>,<y>,<z>"
+fn parse_translation(name: &str, value: Option<&String>) -> Result<na::Vector3<f64>, Error> {
+    let value = value.ok_or_else(|| Error::MissingValue { name: name.into() })?;
+    let invalid = || Error::InvalidValue { name: name.into(), value: value.clone() };
+    let components: Vec<f64> = value.split(',')
+        .map(|component| component.trim().parse::<f64>().map_err(|_| invalid()))
+        .collect::<Result<_, _>>()?;
+    match components.as_slice() {
+        [x, y, z] if components.iter().all(|c| c.is_finite()) => Ok(na::Vector3::new(*x, *y, *z)),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: Option<&String>) -> Result<T, Error> {
+    let value = value.ok_or_else(|| Error::MissingValue { name: name.into() })?;
+    value.parse::<T>().map_err(|_| Error::InvalidValue { name: name.into(), value: value.clone() })
+}
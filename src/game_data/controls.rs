@@ -1,9 +1,23 @@
 use failure::err_msg;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
 use sdl2::keyboard::Keycode;
 use sdl2::mouse::MouseButton;
-use crate::game_data::{GameData, GRID_WIDTH};
-use crate::game_data::grid::GridingAlgo;
-use crate::game_data::water::Direction;
+use crate::game_data::{GameData, RESOLUTION_PRESETS};
+use crate::game_data::{pole_markers, water};
+use crate::game_data::grid::{Grid, GridingAlgo};
+use crate::game_data::{mesh_export, surface};
+use crate::game_data::water::{Direction, UpdateMode};
+use crate::game_data::pole_markers::PoleDrag;
+use crate::game_data::replay::{self, Event};
+use crate::game_data::grid::sculpt::Brush;
+use crate::debug;
+
+// In world units, the longer side of the terrain is 2
+const POLE_PICK_DISTANCE: f32 = 0.05;
+const POLE_HEIGHT_STEP: f32 = 0.02;
+const QUICK_SNAPSHOT: &str = "snapshots/quick.snap";
 
 #[derive(PartialEq)]
 #[derive(Copy, Clone)]
@@ -31,6 +45,31 @@ pub enum Actions {
     Rain,
     Kriging,
     RadialBasis,
+    ThinPlateSpline,
+    Multiquadric,
+    InverseMultiquadric,
+    GaussianRbf,
+    Linear,
+    NaturalNeighbour,
+    ExportGrid,
+    ExportMeshes,
+    ResolutionUp,
+    ResolutionDown,
+    NextTerrain,
+    ToggleFilters,
+    ToggleUpdateMode,
+    BrushMode,
+    BrushSmaller,
+    BrushBigger,
+    BrushWeaker,
+    BrushStronger,
+    EditPoles,
+    PoleUp,
+    PoleDown,
+    DeletePole,
+    SavePoles,
+    SaveSnapshot,
+    LoadSnapshot,
 }
 
 #[derive(Copy, Clone)]
@@ -45,8 +84,34 @@ pub struct Controls {
     pub rain:           KeyStatus,
     pub kriging:        KeyStatus,
     pub radial_basis:   KeyStatus,
+    pub thin_plate:     KeyStatus,
+    pub multiquadric:   KeyStatus,
+    pub inv_multiquadric: KeyStatus,
+    pub gaussian_rbf:   KeyStatus,
+    pub linear:         KeyStatus,
+    pub natural_neighbour: KeyStatus,
+    pub export_grid:    KeyStatus,
+    pub export_meshes:  KeyStatus,
+    pub resolution_up:  KeyStatus,
+    pub resolution_down: KeyStatus,
+    pub next_terrain:   KeyStatus,
+    pub toggle_filters: KeyStatus,
+    pub toggle_update_mode: KeyStatus,
+    pub brush_mode:     KeyStatus,
+    pub brush_smaller:  KeyStatus,
+    pub brush_bigger:   KeyStatus,
+    pub brush_weaker:   KeyStatus,
+    pub brush_stronger: KeyStatus,
+    pub edit_poles:     KeyStatus,
+    pub pole_up:        KeyStatus,
+    pub pole_down:      KeyStatus,
+    pub delete_pole:    KeyStatus,
+    pub save_poles:     KeyStatus,
+    pub save_snapshot:  KeyStatus,
+    pub load_snapshot:  KeyStatus,
     pub is_rain:        bool,
     pub cam_capture:    KeyStatus,
+    pub sculpt:         KeyStatus,
     mouse_left_clk: na::Vector2<i32>,
     mouse_cur_pos: na::Vector2<i32>,
 }
@@ -66,9 +131,35 @@ impl Controls {
             wave_w:         KeyStatus::Released,
             kriging:        KeyStatus::Released,
             radial_basis:   KeyStatus::Released,
+            thin_plate:     KeyStatus::Released,
+            multiquadric:   KeyStatus::Released,
+            inv_multiquadric: KeyStatus::Released,
+            gaussian_rbf:   KeyStatus::Released,
+            linear:         KeyStatus::Released,
+            natural_neighbour: KeyStatus::Released,
+            export_grid:    KeyStatus::Released,
+            export_meshes:  KeyStatus::Released,
+            resolution_up:  KeyStatus::Released,
+            resolution_down: KeyStatus::Released,
+            next_terrain:   KeyStatus::Released,
+            toggle_filters: KeyStatus::Released,
+            toggle_update_mode: KeyStatus::Released,
+            brush_mode:     KeyStatus::Released,
+            brush_smaller:  KeyStatus::Released,
+            brush_bigger:   KeyStatus::Released,
+            brush_weaker:   KeyStatus::Released,
+            brush_stronger: KeyStatus::Released,
+            edit_poles:     KeyStatus::Released,
+            pole_up:        KeyStatus::Released,
+            pole_down:      KeyStatus::Released,
+            delete_pole:    KeyStatus::Released,
+            save_poles:     KeyStatus::Released,
+            save_snapshot:  KeyStatus::Released,
+            load_snapshot:  KeyStatus::Released,
             rain:           KeyStatus::Released,
             is_rain,
             cam_capture:    KeyStatus::Released,
+            sculpt:         KeyStatus::Released,
             mouse_left_clk,
             mouse_cur_pos,
         }
@@ -89,8 +180,33 @@ impl Controls {
             Keycode::A =>       self.wave_w       = status,
             Keycode::D =>       self.wave_e       = status,
             Keycode::R =>       self.rain         = status,
+            Keycode::E =>       self.export_grid  = status,
+            Keycode::M =>       self.export_meshes = status,
+            Keycode::Equals =>  self.resolution_up = status,
+            Keycode::Minus =>   self.resolution_down = status,
+            Keycode::G =>       self.next_terrain = status,
+            Keycode::T =>       self.toggle_filters = status,
+            Keycode::U =>       self.toggle_update_mode = status,
+            Keycode::B =>       self.brush_mode   = status,
+            Keycode::LeftBracket => self.brush_smaller = status,
+            Keycode::RightBracket => self.brush_bigger = status,
+            Keycode::Comma =>   self.brush_weaker = status,
+            Keycode::Period =>  self.brush_stronger = status,
+            Keycode::P =>       self.edit_poles   = status,
+            Keycode::Up =>      self.pole_up      = status,
+            Keycode::Down =>    self.pole_down    = status,
+            Keycode::Delete | Keycode::Backspace => self.delete_pole = status,
+            Keycode::K =>       self.save_poles   = status,
+            Keycode::F5 =>      self.save_snapshot = status,
+            Keycode::F9 =>      self.load_snapshot = status,
             Keycode::Num1 =>    self.radial_basis = status,
             Keycode::Num2 =>    self.kriging      = status,
+            Keycode::Num3 =>    self.thin_plate   = status,
+            Keycode::Num4 =>    self.multiquadric = status,
+            Keycode::Num5 =>    self.inv_multiquadric = status,
+            Keycode::Num6 =>    self.gaussian_rbf = status,
+            Keycode::Num7 =>    self.linear       = status,
+            Keycode::Num8 =>    self.natural_neighbour = status,
             _ => (),
         }
     }
@@ -104,6 +220,7 @@ impl Controls {
                     self.mouse_left_clk.y = y;
                 }
             },
+            MouseButton::Right => self.sculpt = status,
             _ => (),
         }
     }
@@ -124,13 +241,56 @@ impl Controls {
             Actions::Rain        => self.rain         = KeyStatus::Released,
             Actions::Kriging     => self.kriging      = KeyStatus::Released,
             Actions::RadialBasis => self.radial_basis = KeyStatus::Released,
+            Actions::ThinPlateSpline => self.thin_plate = KeyStatus::Released,
+            Actions::Multiquadric => self.multiquadric = KeyStatus::Released,
+            Actions::InverseMultiquadric => self.inv_multiquadric = KeyStatus::Released,
+            Actions::GaussianRbf => self.gaussian_rbf = KeyStatus::Released,
+            Actions::Linear      => self.linear       = KeyStatus::Released,
+            Actions::NaturalNeighbour => self.natural_neighbour = KeyStatus::Released,
+            Actions::ExportGrid  => self.export_grid  = KeyStatus::Released,
+            Actions::ExportMeshes => self.export_meshes = KeyStatus::Released,
+            Actions::ResolutionUp => self.resolution_up = KeyStatus::Released,
+            Actions::ResolutionDown => self.resolution_down = KeyStatus::Released,
+            Actions::NextTerrain => self.next_terrain = KeyStatus::Released,
+            Actions::ToggleFilters => self.toggle_filters = KeyStatus::Released,
+            Actions::ToggleUpdateMode => self.toggle_update_mode = KeyStatus::Released,
+            Actions::BrushMode   => self.brush_mode   = KeyStatus::Released,
+            Actions::BrushSmaller => self.brush_smaller = KeyStatus::Released,
+            Actions::BrushBigger => self.brush_bigger = KeyStatus::Released,
+            Actions::BrushWeaker => self.brush_weaker = KeyStatus::Released,
+            Actions::BrushStronger => self.brush_stronger = KeyStatus::Released,
+            Actions::EditPoles   => self.edit_poles   = KeyStatus::Released,
+            Actions::PoleUp      => self.pole_up      = KeyStatus::Released,
+            Actions::PoleDown    => self.pole_down    = KeyStatus::Released,
+            Actions::DeletePole  => self.delete_pole  = KeyStatus::Released,
+            Actions::SavePoles   => self.save_poles   = KeyStatus::Released,
+            Actions::SaveSnapshot => self.save_snapshot = KeyStatus::Released,
+            Actions::LoadSnapshot => self.load_snapshot = KeyStatus::Released,
         }
     }
 
+    // Keys of recorded actions do nothing while a replay runs, nor do pole edits and snapshot loads
+    pub fn reset_simulation_actions(&mut self) {
+        for action in [
+            Actions::Flush, Actions::AddWater, Actions::WaveN, Actions::WaveS, Actions::WaveE, Actions::WaveW,
+            Actions::Rain, Actions::Kriging, Actions::RadialBasis, Actions::ThinPlateSpline, Actions::Multiquadric,
+            Actions::InverseMultiquadric, Actions::GaussianRbf, Actions::Linear, Actions::NaturalNeighbour,
+            Actions::ResolutionUp, Actions::ResolutionDown, Actions::NextTerrain, Actions::ToggleFilters,
+            Actions::ToggleUpdateMode, Actions::EditPoles, Actions::LoadSnapshot,
+        ] {
+            self.reset_action(action);
+        }
+        self.sculpt = KeyStatus::Released;
+    }
+
     pub fn get_naviball(&self) -> na::Vector2<i32> {
         self.mouse_cur_pos - self.mouse_left_clk
     }
 
+    pub fn get_mouse_pos(&self) -> na::Vector2<i32> {
+        self.mouse_cur_pos
+    }
+
     pub fn save_mouse_clk_pos(&mut self) {
         self.mouse_left_clk.x = self.mouse_cur_pos.x;
         self.mouse_left_clk.y = self.mouse_cur_pos.y;
@@ -139,8 +299,37 @@ impl Controls {
 
 impl GameData {
     pub fn process_input(&mut self) -> Result<(), failure::Error> {
+        if self.replay.is_some() {
+            self.controls.reset_simulation_actions();
+            self.replay_input()?;
+        }
         if self.controls.kriging.into() { self.action_set_kriging()? };
         if self.controls.radial_basis.into() { self.action_set_radial_basis()? };
+        if self.controls.thin_plate.into() { self.action_set_thin_plate()? };
+        if self.controls.multiquadric.into() { self.action_set_multiquadric()? };
+        if self.controls.inv_multiquadric.into() { self.action_set_inv_multiquadric()? };
+        if self.controls.gaussian_rbf.into() { self.action_set_gaussian_rbf()? };
+        if self.controls.linear.into() { self.action_set_linear()? };
+        if self.controls.natural_neighbour.into() { self.action_set_natural_neighbour()? };
+        if self.controls.export_grid.into() { self.action_export_grid()? };
+        if self.controls.export_meshes.into() { self.action_export_meshes()? };
+        if self.controls.resolution_up.into() { self.action_resolution_up()? };
+        if self.controls.resolution_down.into() { self.action_resolution_down()? };
+        if self.controls.next_terrain.into() { self.action_next_terrain()? };
+        if self.controls.toggle_filters.into() { self.action_toggle_filters()? };
+        if self.controls.toggle_update_mode.into() { self.action_toggle_update_mode() };
+        if self.controls.brush_mode.into() { self.action_brush_mode() };
+        if self.controls.brush_smaller.into() { self.action_brush_size(false) };
+        if self.controls.brush_bigger.into() { self.action_brush_size(true) };
+        if self.controls.brush_weaker.into() { self.action_brush_strength(false) };
+        if self.controls.brush_stronger.into() { self.action_brush_strength(true) };
+        if self.controls.edit_poles.into() { self.action_edit_poles()? };
+        if self.controls.pole_up.into() { self.action_pole_height(true)? };
+        if self.controls.pole_down.into() { self.action_pole_height(false)? };
+        if self.controls.delete_pole.into() { self.action_delete_pole()? };
+        if self.controls.save_poles.into() { self.action_save_poles()? };
+        if self.controls.save_snapshot.into() { self.action_save_snapshot()? };
+        if self.controls.load_snapshot.into() { self.action_load_snapshot() };
         if self.controls.exit.into() { self.action_exit() };
         if self.controls.flush.into() { self.action_flush() };
         if self.controls.add_water.into() { self.action_add_water() };
@@ -149,48 +338,127 @@ impl GameData {
         if self.controls.wave_w.into() { self.action_wave_w() };
         if self.controls.wave_e.into() { self.action_wave_e() };
         if self.controls.rain.into() { self.action_rain() };
-        if self.controls.cam_capture.into() { self.action_cam_capture().map_err(err_msg)? };
+        match (self.edit_poles, self.controls.cam_capture.into()) {
+            (false, true) => self.action_cam_capture().map_err(err_msg)?,
+            (true, true) => self.action_drag_pole()?,
+            (_, false) if self.pole_drag.is_some() => self.action_release_pole()?,
+            _ => (),
+        }
+        match self.controls.sculpt.into() {
+            true => self.action_sculpt()?,
+            false => self.stroke_height = None,
+        }
         Ok(())
     }
 
+    // Recorded events of the current frame, in their order
+    pub(super) fn replay_input(&mut self) -> Result<(), failure::Error> {
+        let (events, finished) = match &mut self.replay {
+            Some(replay) => (replay.take_due(self.frame), replay.is_finished()),
+            None => return Ok(()),
+        };
+        for event in events {
+            println!("Replay frame {}: {}", self.frame, event);
+            self.perform(event)?;
+        }
+        if finished {
+            println!("Replay finished at frame {}, the controls are back", self.frame);
+            self.replay = None;
+        }
+        Ok(())
+    }
+
+    fn perform(&mut self, event: Event) -> Result<(), failure::Error> {
+        match event {
+            Event::Flush => self.action_flush(),
+            Event::AddWater => self.action_add_water(),
+            Event::Wave(dir) => self.wave(dir),
+            Event::Rain => self.action_rain(),
+            Event::GridingAlgo(griding_algo) => self.set_griding_algo(griding_algo)?,
+            Event::ResolutionUp => self.action_resolution_up()?,
+            Event::ResolutionDown => self.action_resolution_down()?,
+            Event::NextTerrain => self.action_next_terrain()?,
+            Event::ToggleFilters => self.action_toggle_filters()?,
+            Event::UpdateMode(mode) => self.set_update_mode(mode),
+            Event::Sculpt { brush, x, z, target } => self.sculpt_at(&brush, x, z, target)?,
+            Event::AddPole { x, y, z } => { self.add_pole(x, y, z); },
+            Event::MovePole { index, x, z } => {
+                self.check_pole(&event, index)?;
+                self.move_pole(index, x, z);
+            },
+            Event::PoleHeight { index, y } => {
+                self.check_pole(&event, index)?;
+                self.set_pole_height(index, y)?;
+            },
+            Event::DeletePole { index } => {
+                self.check_pole(&event, index)?;
+                self.delete_pole(index)?;
+            },
+            Event::Regrid => self.regrid_keeping_water()?,
+        }
+        if let Event::AddPole { .. } | Event::MovePole { .. } | Event::PoleHeight { .. } | Event::DeletePole { .. } = event {
+            self.selected_pole = None;
+            self.update_markers();
+        }
+        Ok(())
+    }
+
+    // A replay started with another terrain source may refer to poles that do not exist
+    fn check_pole(&self, event: &Event, index: usize) -> Result<(), failure::Error> {
+        let count = self.grid.get_user_poles().len();
+        match index < count {
+            true => Ok(()),
+            false => Err(replay::Error::NoPole { event: event.to_string(), index, count }.into()),
+        }
+    }
+
     fn action_flush(&mut self) {
         println!("Flush!");
         self.controls.reset_action(Actions::Flush);
+        self.record(Event::Flush);
         self.water.flush();
+        self.fill_sea();
     }
 
     fn action_add_water(&mut self) {
         println!("Add water");
         self.controls.reset_action(Actions::AddWater);
+        self.record(Event::AddWater);
         self.water.increase_water_level();
     }
 
     fn action_wave_s(&mut self) {
         println!("Wave south");
         self.controls.reset_action(Actions::WaveS);
-        self.water.add_wave_particles(Direction::South);
+        self.wave(Direction::South);
     }
 
     fn action_wave_n(&mut self) {
         println!("Wave north");
         self.controls.reset_action(Actions::WaveN);
-        self.water.add_wave_particles(Direction::North);
+        self.wave(Direction::North);
     }
 
     fn action_wave_e(&mut self) {
         println!("Wave east");
         self.controls.reset_action(Actions::WaveE);
-        self.water.add_wave_particles(Direction::East);
+        self.wave(Direction::East);
     }
 
     fn action_wave_w(&mut self) {
         println!("Wave west");
         self.controls.reset_action(Actions::WaveW);
-        self.water.add_wave_particles(Direction::West);
+        self.wave(Direction::West);
+    }
+
+    fn wave(&mut self, dir: Direction) {
+        self.record(Event::Wave(dir));
+        self.water.add_wave_particles(dir);
     }
 
     fn action_rain(&mut self) {
         self.controls.reset_action(Actions::Rain);
+        self.record(Event::Rain);
         self.controls.is_rain = !self.controls.is_rain;
         match self.controls.is_rain {
             true => println!("Rain start"),
@@ -201,20 +469,435 @@ impl GameData {
     fn action_set_kriging(&mut self) -> Result<(), failure::Error> {
         println!("Griding algorithm: Kriging");
         self.controls.reset_action(Actions::Kriging);
-        self.action_flush();
-        self.grid.update_grid(GRID_WIDTH, GridingAlgo::Kriging);
-        self.water.set_grid(&self.grid.get_data());
-        self.surface.set_grid(&self.grid.get_data())?;
-        Ok(())
+        self.set_griding_algo(GridingAlgo::Kriging)
     }
 
     fn action_set_radial_basis(&mut self) -> Result<(), failure::Error> {
         println!("Griding algorithm: Radial basis function");
         self.controls.reset_action(Actions::RadialBasis);
-        self.action_flush();
-        self.grid.update_grid(GRID_WIDTH, GridingAlgo::RadialBasisFunction);
-        self.water.set_grid(&self.grid.get_data());
-        self.surface.set_grid(&self.grid.get_data())?;
+        self.set_griding_algo(GridingAlgo::RadialBasisFunction)
+    }
+
+    fn action_set_thin_plate(&mut self) -> Result<(), failure::Error> {
+        println!("Griding algorithm: Thin plate spline RBF");
+        self.controls.reset_action(Actions::ThinPlateSpline);
+        self.set_griding_algo(GridingAlgo::ThinPlateSpline)
+    }
+
+    fn action_set_multiquadric(&mut self) -> Result<(), failure::Error> {
+        println!("Griding algorithm: Multiquadric RBF");
+        self.controls.reset_action(Actions::Multiquadric);
+        self.set_griding_algo(GridingAlgo::Multiquadric)
+    }
+
+    fn action_set_inv_multiquadric(&mut self) -> Result<(), failure::Error> {
+        println!("Griding algorithm: Inverse multiquadric RBF");
+        self.controls.reset_action(Actions::InverseMultiquadric);
+        self.set_griding_algo(GridingAlgo::InverseMultiquadric)
+    }
+
+    fn action_set_gaussian_rbf(&mut self) -> Result<(), failure::Error> {
+        println!("Griding algorithm: Gaussian RBF");
+        self.controls.reset_action(Actions::GaussianRbf);
+        self.set_griding_algo(GridingAlgo::GaussianRbf)
+    }
+
+    fn action_set_linear(&mut self) -> Result<(), failure::Error> {
+        println!("Griding algorithm: Linear (Delaunay)");
+        self.controls.reset_action(Actions::Linear);
+        self.set_griding_algo(GridingAlgo::Linear)
+    }
+
+    fn action_set_natural_neighbour(&mut self) -> Result<(), failure::Error> {
+        println!("Griding algorithm: Natural neighbour");
+        self.controls.reset_action(Actions::NaturalNeighbour);
+        self.set_griding_algo(GridingAlgo::NaturalNeighbour)
+    }
+
+    fn set_griding_algo(&mut self, griding_algo: GridingAlgo) -> Result<(), failure::Error> {
+        self.record(Event::GridingAlgo(griding_algo));
+        self.griding_algo = griding_algo;
+        self.rebuild_world()
+    }
+
+    fn action_resolution_up(&mut self) -> Result<(), failure::Error> {
+        self.controls.reset_action(Actions::ResolutionUp);
+        self.record(Event::ResolutionUp);
+        let longer = self.resolution.x.max(self.resolution.z);
+        match RESOLUTION_PRESETS.iter().find(|&&size| size > longer) {
+            Some(&size) => self.set_resolution_size(size),
+            None => Ok(()),
+        }
+    }
+
+    fn action_resolution_down(&mut self) -> Result<(), failure::Error> {
+        self.controls.reset_action(Actions::ResolutionDown);
+        self.record(Event::ResolutionDown);
+        let longer = self.resolution.x.max(self.resolution.z);
+        match RESOLUTION_PRESETS.iter().rev().find(|&&size| size < longer) {
+            Some(&size) => self.set_resolution_size(size),
+            None => Ok(()),
+        }
+    }
+
+    fn set_resolution_size(&mut self, size: usize) -> Result<(), failure::Error> {
+        self.resolution = self.resolution.with_longer_side(size);
+        println!("Resolution: {}x{}, {} water layers", self.resolution.x, self.resolution.z, self.resolution.height);
+        self.rebuild_world()
+    }
+
+    fn action_next_terrain(&mut self) -> Result<(), failure::Error> {
+        self.controls.reset_action(Actions::NextTerrain);
+        self.record(Event::NextTerrain);
+        match self.grid.next_seed() {
+            Some(_) => self.rebuild_world(),
+            None => { println!("Terrain is not procedural"); Ok(()) },
+        }
+    }
+
+    fn action_toggle_filters(&mut self) -> Result<(), failure::Error> {
+        self.controls.reset_action(Actions::ToggleFilters);
+        self.record(Event::ToggleFilters);
+        if self.grid.get_filters().is_empty() {
+            println!("No terrain filters, see --filter");
+            return Ok(());
+        }
+        match self.grid.toggle_filters() {
+            true => println!("Terrain filters on: {:?}", self.grid.get_filters()),
+            false => println!("Terrain filters off"),
+        }
+        self.reload_terrain()
+    }
+
+    fn action_toggle_update_mode(&mut self) {
+        self.controls.reset_action(Actions::ToggleUpdateMode);
+        self.set_update_mode(self.water.get_update_mode().next());
+    }
+
+    fn set_update_mode(&mut self, mode: UpdateMode) {
+        self.record(Event::UpdateMode(mode));
+        self.water.set_update_mode(mode);
+        println!("Water update mode: {}", mode.name());
+    }
+
+    fn action_brush_mode(&mut self) {
+        self.controls.reset_action(Actions::BrushMode);
+        self.brush.mode = self.brush.mode.next();
+        println!("Brush: {:?}", self.brush.mode);
+    }
+
+    fn action_brush_size(&mut self, grow: bool) {
+        self.controls.reset_action(match grow { true => Actions::BrushBigger, false => Actions::BrushSmaller });
+        self.brush.resize(grow);
+        println!("Brush radius: {:.3}", self.brush.radius);
+    }
+
+    fn action_brush_strength(&mut self, grow: bool) {
+        self.controls.reset_action(match grow { true => Actions::BrushStronger, false => Actions::BrushWeaker });
+        self.brush.strengthen(grow);
+        println!("Brush strength: {:.4}", self.brush.strength);
+    }
+
+    // Terrain point under the mouse cursor as (x, z) in [-1;1]
+    fn pick_cursor(&self) -> Option<(f32, f32)> {
+        let cursor = self.controls.get_mouse_pos();
+        let ndc_x = 2. * cursor.x as f32 / self.viewport.w as f32 - 1.;
+        let ndc_y = 1. - 2. * cursor.y as f32 / self.viewport.h as f32;
+        let (near, far) = self.mvp.unproject(ndc_x, ndc_y)?;
+        self.grid.pick(&near, &far)
+    }
+
+    // Applied every frame while the right button is held
+    fn action_sculpt(&mut self) -> Result<(), failure::Error> {
+        let (x, z) = match self.pick_cursor() {
+            Some(point) => point,
+            None => return Ok(()),
+        };
+        let target = match self.stroke_height {
+            Some(height) => height,
+            None => *self.stroke_height.insert(self.grid.height_at(x, z)),
+        };
+        let brush = self.brush;
+        self.sculpt_at(&brush, x, z, target)
+    }
+
+    fn sculpt_at(&mut self, brush: &Brush, x: f32, z: f32, target: f32) -> Result<(), failure::Error> {
+        self.record(Event::Sculpt { brush: *brush, x, z, target });
+        if let Some(affected) = self.grid.sculpt(brush, x, z, target) {
+            self.surface.update_heights(self.grid.get_data())?;
+            self.update_water_borders(affected.rows, affected.cols);
+        }
+        Ok(())
+    }
+
+    fn action_edit_poles(&mut self) -> Result<(), failure::Error> {
+        self.controls.reset_action(Actions::EditPoles);
+        if !self.grid.has_poles() {
+            println!("Terrain has no poles to edit");
+            return Ok(());
+        }
+        if self.pole_drag.is_some() {
+            self.action_release_pole()?;
+        }
+        self.edit_poles = !self.edit_poles;
+        self.selected_pole = None;
+        self.update_markers();
+        match self.edit_poles {
+            true => println!("Pole editing on"),
+            false => println!("Pole editing off"),
+        }
+        Ok(())
+    }
+
+    // A click grabs the nearest pole or adds one on the surface, dragging moves the grabbed pole
+    fn action_drag_pole(&mut self) -> Result<(), failure::Error> {
+        let (x, z) = match self.pick_cursor() {
+            Some(point) => point,
+            None => return Ok(()),
+        };
+        match &mut self.pole_drag {
+            None => {
+                let (index, changed) = match self.grid.nearest_pole(x, z, POLE_PICK_DISTANCE) {
+                    Some(index) => (index, false),
+                    None => (self.add_pole(x, self.grid.height_at(x, z), z), true),
+                };
+                let pole = self.grid.get_user_poles()[index];
+                println!("Pole {}: {:?}", index, self.grid.get_extents().to_world(&pole));
+                self.selected_pole = Some(index);
+                self.pole_drag = Some(PoleDrag { offset_x: pole.x - x, offset_z: pole.z - z, changed });
+            },
+            Some(drag) => {
+                let index = match self.selected_pole {
+                    Some(index) => index,
+                    None => return Ok(()),
+                };
+                let (new_x, new_z) = (x + drag.offset_x, z + drag.offset_z);
+                let pole = self.grid.get_user_poles()[index];
+                if pole.x != new_x || pole.z != new_z {
+                    drag.changed = true;
+                    self.move_pole(index, new_x, new_z);
+                }
+            },
+        }
+        self.update_markers();
+        Ok(())
+    }
+
+    fn action_release_pole(&mut self) -> Result<(), failure::Error> {
+        match self.pole_drag.take() {
+            Some(drag) if drag.changed => {
+                self.record(Event::Regrid);
+                self.regrid_keeping_water()
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn action_pole_height(&mut self, up: bool) -> Result<(), failure::Error> {
+        self.controls.reset_action(match up { true => Actions::PoleUp, false => Actions::PoleDown });
+        let index = match (self.edit_poles, self.selected_pole) {
+            (true, Some(index)) => index,
+            _ => return Ok(()),
+        };
+        let step = match up { true => POLE_HEIGHT_STEP, false => -POLE_HEIGHT_STEP };
+        let height = self.grid.get_user_poles()[index].y + step;
+        self.set_pole_height(index, height)?;
+        println!("Pole {}: {:?}", index, self.grid.get_extents().to_world(&self.grid.get_user_poles()[index]));
+        self.update_markers();
+        Ok(())
+    }
+
+    fn action_delete_pole(&mut self) -> Result<(), failure::Error> {
+        self.controls.reset_action(Actions::DeletePole);
+        let index = match (self.edit_poles, self.selected_pole) {
+            (true, Some(index)) => index,
+            _ => return Ok(()),
+        };
+        if self.delete_pole(index)? {
+            self.selected_pole = None;
+            self.update_markers();
+        }
+        Ok(())
+    }
+
+    // Pole edits shared by the mouse, the keys and the replay
+    fn add_pole(&mut self, x: f32, y: f32, z: f32) -> usize {
+        self.record(Event::AddPole { x, y, z });
+        self.grid.add_pole(x, y, z)
+    }
+
+    fn move_pole(&mut self, index: usize, x: f32, z: f32) {
+        self.record(Event::MovePole { index, x, z });
+        self.grid.move_pole(index, x, z);
+    }
+
+    fn set_pole_height(&mut self, index: usize, y: f32) -> Result<(), failure::Error> {
+        self.record(Event::PoleHeight { index, y });
+        self.grid.set_pole_height(index, y);
+        self.regrid_keeping_water()
+    }
+
+    fn delete_pole(&mut self, index: usize) -> Result<bool, failure::Error> {
+        self.record(Event::DeletePole { index });
+        if !self.grid.remove_pole(index) {
+            println!("The last pole can not be deleted");
+            return Ok(false);
+        }
+        println!("Pole {} deleted", index);
+        self.regrid_keeping_water()?;
+        Ok(true)
+    }
+
+    // Written next to the source file, which is left untouched
+    fn action_save_poles(&mut self) -> Result<(), failure::Error> {
+        self.controls.reset_action(Actions::SavePoles);
+        if !self.grid.has_poles() {
+            println!("Terrain has no poles to save");
+            return Ok(());
+        }
+        let stem = Path::new(&self.grid_files[0].path).file_stem().and_then(|stem| stem.to_str()).unwrap_or("grid");
+        let grids_dir = self.res.resource_path("grids");
+        fs::create_dir_all(&grids_dir)?;
+        let path = grids_dir.join(format!("{}_edited.mod1", stem));
+        fs::write(&path, self.grid.to_mod1())?;
+        println!("Poles saved: {}", path.display());
+        Ok(())
+    }
+
+    fn action_save_snapshot(&mut self) -> Result<(), failure::Error> {
+        self.controls.reset_action(Actions::SaveSnapshot);
+        self.save_snapshot(&self.res.resource_path(QUICK_SNAPSHOT))
+    }
+
+    // A missing or broken snapshot is reported, the running simulation goes on.
+    // The replay would need the same file, so the recording stops
+    fn action_load_snapshot(&mut self) {
+        self.controls.reset_action(Actions::LoadSnapshot);
+        let path = self.res.resource_path(QUICK_SNAPSHOT);
+        match self.load_snapshot(&path) {
+            Ok(()) => self.stop_recording("a snapshot was loaded"),
+            Err(e) => print!("Snapshot {} is not loaded: {}", path.display(), debug::failure_to_string(e)),
+        }
+    }
+
+    fn update_markers(&mut self) {
+        self.markers.set_poles(self.grid.get_user_poles(), self.selected_pole);
+    }
+
+    // Grid file and shaders are reloaded when they change on disk. A running replay keeps its terrain
+    pub fn hot_reload(&mut self) -> Result<(), failure::Error> {
+        let changed = self.watcher.changed();
+        for name in &changed {
+            if self.grid_files.iter().any(|file| file.path == *name) {
+                match self.replay.is_some() {
+                    true => println!("Grid {} is not reloaded while a replay runs", name),
+                    false => self.reload_grid()?,
+                }
+                continue;
+            }
+            let result = match name.rsplit_once('.').map(|(shader, _)| shader) {
+                Some(surface::SHADER) => self.surface.reload_program(&self.gl, &self.res),
+                Some(water::renderer::SHADER) => self.water_renderer.reload_program(&self.gl, &self.res),
+                Some(pole_markers::SHADER) => self.markers.reload_program(&self.gl, &self.res),
+                _ => Ok(()),
+            };
+            match result {
+                Ok(()) => println!("Shader reloaded: {}", name),
+                Err(e) => print!("Shader {} is not reloaded: {}", name, debug::failure_to_string(e.into())),
+            }
+        }
+        match changed.is_empty() {
+            true => Ok(()),
+            false => self.apply_uniforms(),
+        }
+    }
+
+    // The old terrain stays when the file fails to load. Griding options, filters and water are kept
+    fn reload_grid(&mut self) -> Result<(), failure::Error> {
+        let names: Vec<&str> = self.grid_files.iter().map(|file| file.path.as_str()).collect();
+        let mut grid = match Grid::load(&self.res, &self.grid_files, self.grid.get_options()) {
+            Ok(grid) => grid,
+            Err(e) => {
+                print!("Grid {} is not reloaded: {}", names.join(", "), debug::failure_to_string(e));
+                return Ok(());
+            },
+        };
+        println!("Grid reloaded: {}", names.join(", "));
+        self.stop_recording("a grid file was reloaded");
+        grid.set_filters(self.grid.get_filters().clone());
+        if grid.get_aspect() != self.grid.get_aspect() {
+            self.mvp.set_aspect(&grid.get_aspect());
+            self.mvp.projection_recalc(self.viewport.w, self.viewport.h);
+        }
+        self.grid = grid;
+        self.selected_pole = None;
+        self.pole_drag = None;
+        self.update_markers();
+        self.regrid_keeping_water()
+    }
+
+    // Surface and water follow the changed poles, the water is kept
+    fn regrid_keeping_water(&mut self) -> Result<(), failure::Error> {
+        self.grid.update_grid(self.resolution.x, self.resolution.z, self.griding_algo);
+        self.surface.update_heights(self.grid.get_data())?;
+        self.update_water_borders(0..self.resolution.z, 0..self.resolution.x);
+        Ok(())
+    }
+
+    // The water is kept unless the ground went below its lowest layer, the sea is filled again then
+    fn update_water_borders(&mut self, rows: Range<usize>, cols: Range<usize>) {
+        if !self.water.update_borders(self.grid.get_data(), rows, cols) {
+            println!("Terrain is below the lowest water layer, water is flushed");
+            self.fill_sea();
+        }
+    }
+
+    fn rebuild_world(&mut self) -> Result<(), failure::Error> {
+        self.grid.update_grid(self.resolution.x, self.resolution.z, self.griding_algo);
+        self.reload_terrain()
+    }
+
+    // Uploads the current grid data, the water is flushed as its borders change
+    fn reload_terrain(&mut self) -> Result<(), failure::Error> {
+        self.water.flush();
+        self.water.set_grid(self.grid.get_data(), self.resolution.height);
+        self.fill_sea();
+        self.surface.set_grid(self.grid.get_data())?;
+        Ok(())
+    }
+
+    fn action_export_grid(&mut self) -> Result<(), failure::Error> {
+        self.controls.reset_action(Actions::ExportGrid);
+        let export_dir = self.res.resource_path("exports");
+        fs::create_dir_all(&export_dir)?;
+        let asc_path = export_dir.join("terrain.asc");
+        let xyz_path = export_dir.join("terrain.xyz");
+        fs::write(&asc_path, self.grid.to_esri_ascii())?;
+        fs::write(&xyz_path, self.grid.to_xyz())?;
+        println!("Grid exported: {}, {}", asc_path.display(), xyz_path.display());
+        Ok(())
+    }
+
+    fn action_export_meshes(&mut self) -> Result<(), failure::Error> {
+        self.controls.reset_action(Actions::ExportMeshes);
+        let export_dir = self.res.resource_path("exports");
+        fs::create_dir_all(&export_dir)?;
+
+        // Meshes are written in world proportions, as they are rendered
+        let aspect = self.grid.get_aspect();
+        let stretch = |(x, y, z): (f32, f32, f32)| (x * aspect.x, y, z * aspect.z);
+        let (positions, indices) = surface::generate_mesh(self.grid.get_data())?;
+        let positions: Vec<(f32, f32, f32)> = positions.into_iter().map(stretch).collect();
+        fs::write(export_dir.join("terrain.obj"), mesh_export::triangles_to_obj(&positions, &indices))?;
+        fs::write(export_dir.join("terrain.stl"), mesh_export::triangles_to_stl(&positions, &indices))?;
+
+        let quads: Vec<[(f32, f32, f32); 4]> = self.water.particle_quads().into_iter()
+            .map(|quad| quad.map(stretch))
+            .collect();
+        fs::write(export_dir.join("water.obj"), mesh_export::quads_to_obj(&quads))?;
+        fs::write(export_dir.join("water.ply"), mesh_export::quads_to_ply(&quads))?;
+        println!("Meshes exported to {}: {} terrain triangles, {} water cells",
+                 export_dir.display(), indices.len() / 3, quads.len());
         Ok(())
     }
 
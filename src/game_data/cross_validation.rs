@@ -0,0 +1,77 @@
+use std::fmt::Write;
+use std::fs;
+use failure::err_msg;
+use resources::Resources;
+use crate::config::Config;
+use crate::game_data::grid::{Grid, GridingAlgo};
+
+// Leave-one-out errors of one algorithm, in the height units of the source file
+pub struct Validation {
+    pub griding_algo: GridingAlgo,
+    pub predicted: Vec<f32>,
+    pub rmse: f64,
+    pub mae: f64,
+}
+
+impl Validation {
+    pub fn new(grid: &Grid, griding_algo: GridingAlgo) -> Validation {
+        let predicted = grid.cross_validate(griding_algo);
+        let extents = grid.get_extents();
+        let errors: Vec<f64> = grid.get_user_poles().iter().zip(&predicted)
+            .map(|(pole, &height)| extents.height_to_world(height) - extents.height_to_world(pole.y))
+            .collect();
+        let count = errors.len().max(1) as f64;
+        Validation {
+            griding_algo,
+            predicted,
+            rmse: (errors.iter().map(|e| e * e).sum::<f64>() / count).sqrt(),
+            mae: errors.iter().map(|e| e.abs()).sum::<f64>() / count,
+        }
+    }
+}
+
+// Validates every griding algorithm on the configured point file, prints the summary
+// and writes per point predictions when a CSV path is given
+pub fn run(res: &Resources, config: &Config) -> Result<(), failure::Error> {
+    let grid = Grid::load(res, &config.grid_files, config.grid_options)?;
+    let poles_count = grid.get_user_poles().len();
+    if poles_count < 2 {
+        return Err(err_msg("Cross-validation needs a point file with at least 2 points"));
+    }
+
+    println!("Leave-one-out cross-validation over {} points", poles_count);
+    println!("{:<22}{:>14}{:>14}", "algorithm", "RMSE", "MAE");
+    let mut validations: Vec<Validation> = Vec::with_capacity(GridingAlgo::ALL.len());
+    for griding_algo in GridingAlgo::ALL.iter() {
+        let validation = Validation::new(&grid, *griding_algo);
+        println!("{:<22}{:>14.6}{:>14.6}", griding_algo.name(), validation.rmse, validation.mae);
+        validations.push(validation);
+    }
+
+    if let Some(csv_path) = &config.cross_validation_csv {
+        fs::write(csv_path, validations_to_csv(&grid, &validations))?;
+        println!("Per point predictions written to {}", csv_path);
+    }
+    Ok(())
+}
+
+fn validations_to_csv(grid: &Grid, validations: &[Validation]) -> String {
+    let extents = grid.get_extents();
+    let mut out = String::from("x,y,z");
+    for validation in validations {
+        let name = validation.griding_algo.name();
+        let _ = write!(out, ",{}_predicted,{}_error", name, name);
+    }
+    out.push('\n');
+
+    for (i, pole) in grid.get_user_poles().iter().enumerate() {
+        let world = extents.to_world(pole);
+        let _ = write!(out, "{},{},{}", world.x as f32, world.y as f32, world.z as f32);
+        for validation in validations {
+            let predicted = extents.height_to_world(validation.predicted[i]);
+            let _ = write!(out, ",{},{}", predicted as f32, (predicted - world.y) as f32);
+        }
+        out.push('\n');
+    }
+    out
+}
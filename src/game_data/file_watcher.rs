@@ -0,0 +1,55 @@
+use resources::Resources;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+struct WatchedFile {
+    name: String,
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+// Polls modification times of resources, at most once per POLL_INTERVAL
+pub struct FileWatcher {
+    files: Vec<WatchedFile>,
+    last_poll: Instant,
+}
+
+impl FileWatcher {
+    pub fn new(res: &Resources, names: &[String]) -> FileWatcher {
+        let files = names.iter()
+            .map(|name| {
+                let path = res.resource_path(name);
+                WatchedFile { name: name.clone(), modified: modified(&path), path }
+            })
+            .collect();
+        FileWatcher { files, last_poll: Instant::now() }
+    }
+
+    // Names of the resources written since the last call. A file that is missing for a moment
+    // while an editor saves it is reported once it is back
+    pub fn changed(&mut self) -> Vec<String> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return vec![];
+        }
+        self.last_poll = Instant::now();
+
+        let mut changed = vec![];
+        for file in &mut self.files {
+            let current = modified(&file.path);
+            if current.is_some() && current != file.modified {
+                changed.push(file.name.clone());
+            }
+            if current.is_some() {
+                file.modified = current;
+            }
+        }
+        changed
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
@@ -1,60 +1,771 @@
 use resources::Resources;
 use failure::err_msg;
 use std::ffi::CString;
+use std::str::FromStr;
+use kriging::Kriging;
+use rbf::{Rbf, RbfKernel};
+use delaunay::Triangulation;
+use procedural::TerrainKind;
+use filters::Filter;
+use sculpt::{Affected, Brush};
+use kdtree::KdTree;
+use ascii_grid::Raster;
+use crate::game_data::snapshot;
+use std::thread;
+pub use kriging::{Variogram, VariogramModel};
+pub use kdtree::Neighbourhood;
+
+mod kriging;
+mod rbf;
+mod delaunay;
+mod kdtree;
+mod heightmap;
+mod ascii_grid;
+pub mod procedural;
+pub mod filters;
+pub mod sculpt;
+
+const HEIGHTMAP_EXTENSIONS: [&str; 2] = [".pgm", ".png"];
+const ESRI_ASCII_EXTENSION: &str = ".asc";
+const XYZ_EXTENSION: &str = ".xyz";
+const POINT_FILE_EXTENSION: &str = ".mod1";
+// Generated rasters are resampled like loaded ones, so a seed gives the same terrain at any resolution
+const PROCEDURAL_RASTER_SIZE: usize = 513;
+// Terrain heights, negative ones are below the sea level of a canonical point file
+pub const MIN_HEIGHT: f32 = -1.;
+pub const MAX_HEIGHT: f32 = 1.;
+
+// Height at a point, built once per grid from the poles
+type GridingFunction<'a> = Box<dyn Fn(&na::Vector3<f32>) -> f32 + Sync + 'a>;
 
 pub struct Grid {
+    user_poles: Vec<na::Vector3<f32>>,
     poles: Vec<na::Vector3<f32>>,
+    raster: Option<Vec<Vec<f32>>>,
+    raster_nodata: Option<Vec<Vec<bool>>>,
+    sampled: Vec<Vec<f32>>,
     data: Vec<Vec<f32>>,
+    edits: Vec<Vec<f32>>,
+    filters: Vec<Filter>,
+    filters_enabled: bool,
+    extents: Extents,
+    aspect: Aspect,
+    procedural: Option<(TerrainKind, u64)>,
+    options: GridOptions,
+}
+
+// Proportions of the world X and Z sides, the longer one is 1.
+// Sampling stays on [-1;1] along both axes, only distances and rendering are scaled
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub struct Aspect {
+    pub x: f32,
+    pub z: f32,
+}
+
+impl Default for Aspect {
+    fn default() -> Self {
+        Aspect { x: 1., z: 1. }
+    }
+}
+
+impl Aspect {
+    pub fn from_sides(x: f64, z: f64) -> Aspect {
+        match x.max(z) {
+            longest if longest > 0. && x > 0. && z > 0. => Aspect { x: (x / longest) as f32, z: (z / longest) as f32 },
+            _ => Aspect::default(),
+        }
+    }
+}
+
+impl FromStr for Aspect {
+    type Err = String;
+
+    // "<x>:<z>", e.g. 4:1
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let sides = s.split_once(':')
+            .and_then(|(x, z)| Some((x.parse::<f64>().ok()?, z.parse::<f64>().ok()?)));
+        match sides {
+            Some((x, z)) if x > 0. && z > 0. => Ok(Aspect::from_sides(x, z)),
+            _ => Err(format!("invalid aspect {}", s)),
+        }
+    }
 }
 
+// World box that corresponds to x, z in [-1;1] and heights in [0;1]. World z is the northing.
+// Heights outside of [0;1] map linearly beyond the box
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub struct Extents {
+    pub min: na::Vector3<f64>,
+    pub max: na::Vector3<f64>,
+}
+
+impl Extents {
+    pub fn canonical() -> Extents {
+        Extents {
+            min: na::Vector3::new(-1., 0., -1.),
+            max: na::Vector3::new(1., 1., 1.),
+        }
+    }
+
+    pub fn height_to_world(&self, height: f32) -> f64 {
+        self.min.y + height as f64 * (self.max.y - self.min.y)
+    }
+
+    pub fn height_from_world(&self, height: f64) -> f32 {
+        match self.max.y - self.min.y {
+            span if span > 0. => ((height - self.min.y) / span) as f32,
+            _ => (height - self.min.y) as f32,
+        }
+    }
+
+    pub fn to_world(self, point: &na::Vector3<f32>) -> na::Vector3<f64> {
+        let along = |value: f32, axis: usize| {
+            self.min[axis] + (value as f64 + 1.) / 2. * (self.max[axis] - self.min[axis])
+        };
+        na::Vector3::new(along(point.x, 0), self.height_to_world(point.y), along(point.z, 2))
+    }
+}
+
+#[derive(Debug)]
+#[derive(Copy, Clone)]
 pub enum GridingAlgo {
     RadialBasisFunction,
     Kriging,
+    ThinPlateSpline,
+    Multiquadric,
+    InverseMultiquadric,
+    GaussianRbf,
+    Linear,
+    NaturalNeighbour,
+}
+
+impl GridingAlgo {
+    pub const ALL: [GridingAlgo; 8] = [
+        GridingAlgo::RadialBasisFunction,
+        GridingAlgo::Kriging,
+        GridingAlgo::ThinPlateSpline,
+        GridingAlgo::Multiquadric,
+        GridingAlgo::InverseMultiquadric,
+        GridingAlgo::GaussianRbf,
+        GridingAlgo::Linear,
+        GridingAlgo::NaturalNeighbour,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GridingAlgo::RadialBasisFunction => "radial_basis",
+            GridingAlgo::Kriging => "kriging",
+            GridingAlgo::ThinPlateSpline => "thin_plate",
+            GridingAlgo::Multiquadric => "multiquadric",
+            GridingAlgo::InverseMultiquadric => "inverse_multiquadric",
+            GridingAlgo::GaussianRbf => "gaussian",
+            GridingAlgo::Linear => "linear",
+            GridingAlgo::NaturalNeighbour => "natural_neighbour",
+        }
+    }
+}
+
+// How the terrain behaves along the [-1;1] square border
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub enum BoundaryMode {
+    Zero(i32),
+    Clamp(i32),
+    Mirror,
+    None,
+}
+
+const DEFAULT_EDGE_SAMPLES: i32 = 30;
+const MIRROR_MARGIN: f32 = 0.5;
+
+impl Default for BoundaryMode {
+    fn default() -> Self {
+        BoundaryMode::Zero(DEFAULT_EDGE_SAMPLES)
+    }
+}
+
+impl FromStr for BoundaryMode {
+    type Err = String;
+
+    // zero[:count], clamp[:count], mirror, none
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let count = match parts.next() {
+            Some(count) => match count.parse::<i32>() {
+                Ok(count) if count > 0 => Some(count),
+                _ => return Err(format!("invalid edge samples count {}", count)),
+            },
+            None => None,
+        };
+        match (name, count) {
+            ("zero", count) => Ok(BoundaryMode::Zero(count.unwrap_or(DEFAULT_EDGE_SAMPLES))),
+            ("clamp", count) => Ok(BoundaryMode::Clamp(count.unwrap_or(DEFAULT_EDGE_SAMPLES))),
+            ("mirror", None) => Ok(BoundaryMode::Mirror),
+            ("none", None) => Ok(BoundaryMode::None),
+            _ => Err(format!("unknown boundary mode {}", s)),
+        }
+    }
+}
+
+// How point file coordinates are mapped into x, z in [-1;1] and y in [0;1]
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+#[derive(Default)]
+pub enum Normalization {
+    #[default]
+    Strict,
+    Uniform,
+    PerAxis,
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Normalization::Strict),
+            "uniform" => Ok(Normalization::Uniform),
+            "per-axis" => Ok(Normalization::PerAxis),
+            _ => Err(format!("unknown normalization {}", s)),
+        }
+    }
 }
 
 #[derive(Fail, Debug)]
 pub enum Error {
     #[fail(display = "Unable to convert file {} to string", name)]
     UnableConvertFileToString { name: String },
-    #[fail(display = "Point {} does not have 3 components (x, y, z)", name)]
-    PointDoNotHave3Components { name: String },
-    #[fail(display = "Non f32 component found: {}, {}", name, message)]
-    ComponentIsNotF32 { name: String, message: String },
-    #[fail(display = "Component X is not in range [-1;1]: {}", name)]
-    ComponentXNotValid { name: String },
-    #[fail(display = "Component Y is not in range [0;1]: {}", name)]
-    ComponentYNotValid { name: String },
-    #[fail(display = "Component Z is not in range [-1;1]: {}", name)]
-    ComponentZNotValid { name: String },
+    #[fail(display = "{}:{}: point does not have 3 components (x, y, z): {}", file, line, name)]
+    PointDoNotHave3Components { file: String, line: usize, name: String },
+    #[fail(display = "{}:{}:{}: non f32 component found: {}, {}", file, line, column, name, message)]
+    ComponentIsNotF32 { file: String, line: usize, column: usize, name: String, message: String },
+    #[fail(display = "{}:{}:{}: component X is not in range [-1;1]: {}", file, line, column, name)]
+    ComponentXNotValid { file: String, line: usize, column: usize, name: String },
+    #[fail(display = "{}:{}:{}: component Y is not in range [-1;1]: {}", file, line, column, name)]
+    ComponentYNotValid { file: String, line: usize, column: usize, name: String },
+    #[fail(display = "{}:{}:{}: component Z is not in range [-1;1]: {}", file, line, column, name)]
+    ComponentZNotValid { file: String, line: usize, column: usize, name: String },
+    #[fail(display = "{} can not be merged or transformed, only .mod1 point files can", name)]
+    NotAPointFile { name: String },
+    #[fail(display = "Invalid heightmap {}: {}", name, message)]
+    InvalidHeightmap { name: String, message: String },
+    #[fail(display = "Invalid ASCII grid {}: {}", name, message)]
+    InvalidAsciiGrid { name: String, message: String },
+}
+
+#[derive(Default)]
+#[derive(Copy, Clone)]
+pub struct GridOptions {
+    pub normalization: Normalization,
+    pub boundary: BoundaryMode,
+    pub variogram: Variogram,
+    pub rbf_epsilon: Option<f32>,
+    pub rbf_smoothing: f32,
+    pub neighbourhood: Neighbourhood,
+    pub aspect: Option<Aspect>,
+}
+
+// Placement of the points of one file in the common frame, in source units: scaled,
+// rotated around Y by degrees (counterclockwise from above, X east, Z north), then translated
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub struct PointTransform {
+    pub translation: na::Vector3<f64>,
+    pub rotation_y: f64,
+    pub scale: f64,
+}
+
+impl Default for PointTransform {
+    fn default() -> Self {
+        PointTransform {
+            translation: na::Vector3::zeros(),
+            rotation_y: 0.,
+            scale: 1.,
+        }
+    }
+}
+
+impl PointTransform {
+    pub fn is_identity(&self) -> bool {
+        *self == PointTransform::default()
+    }
+
+    fn apply(&self, point: &[f32]) -> Vec<f32> {
+        let (sin, cos) = self.rotation_y.to_radians().sin_cos();
+        let (x, y, z) = (point[0] as f64 * self.scale, point[1] as f64 * self.scale, point[2] as f64 * self.scale);
+        vec![
+            (x * cos - z * sin + self.translation.x) as f32,
+            (y + self.translation.y) as f32,
+            (x * sin + z * cos + self.translation.z) as f32,
+        ]
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct PointFile {
+    pub path: String,
+    pub transform: PointTransform,
+}
+
+impl PointFile {
+    pub fn new(path: &str) -> PointFile {
+        PointFile { path: path.to_owned(), transform: PointTransform::default() }
+    }
 }
 
 impl Grid {
-    pub fn new(res: &Resources, grid_path: &str, size: usize, griding_algo: GridingAlgo) -> Result<Grid, failure::Error> {
-        let input_array = Grid::get_user_grid(res, grid_path)?;
-        let input_array = Grid::add_zeros_to_edges(&input_array, 30);    // TODO: config
-        let grid = Grid::make_grid(size, &input_array, griding_algo);
+    // Several files are merged into one pole set, a single untransformed file may also be a raster
+    pub fn load(res: &Resources, files: &[PointFile], options: GridOptions) -> Result<Grid, failure::Error> {
+        match files {
+            [file] if file.transform.is_identity() => Grid::new(res, &file.path, options),
+            _ => Grid::from_point_files(res, files, options),
+        }
+    }
+
+    // Loads the source only, the terrain is sampled by update_grid
+    pub fn new(res: &Resources, grid_path: &str, options: GridOptions) -> Result<Grid, failure::Error> {
+        if HEIGHTMAP_EXTENSIONS.iter().any(|ext| grid_path.ends_with(ext)) {
+            return Grid::from_heightmap(res, grid_path, options);
+        }
+        if grid_path.ends_with(ESRI_ASCII_EXTENSION) || grid_path.ends_with(XYZ_EXTENSION) {
+            return Grid::from_ascii_grid(res, grid_path, options);
+        }
+        Grid::from_point_files(res, &[PointFile::new(grid_path)], options)
+    }
+
+    fn from_point_files(res: &Resources, files: &[PointFile], options: GridOptions) -> Result<Grid, failure::Error> {
+        let (user_poles, extents) = Grid::get_user_grid(res, files, options.normalization)?;
+        let input_array = Grid::add_boundary(&user_poles, options.boundary);
+        // Only uniform normalization knows the real proportions of the points
+        let aspect = match options.normalization {
+            Normalization::Uniform => Aspect::from_sides(extents.max.x - extents.min.x, extents.max.z - extents.min.z),
+            _ => Aspect::default(),
+        };
         Ok(Grid {
+            user_poles,
             poles: input_array,
-            data: grid,
+            raster: None,
+            raster_nodata: None,
+            sampled: vec![],
+            data: vec![],
+            edits: vec![],
+            filters: vec![],
+            filters_enabled: true,
+            extents,
+            aspect: options.aspect.unwrap_or(aspect),
+            procedural: None,
+            options,
         })
     }
 
-    pub fn update_grid(&mut self, size: usize, griding_algo: GridingAlgo) {
-        self.data = Grid::make_grid(size, &self.poles, griding_algo);
+    pub fn procedural(kind: TerrainKind, seed: u64, options: GridOptions) -> Grid {
+        println!("Procedural terrain: {:?}, seed {}", kind, seed);
+        let aspect = options.aspect.unwrap_or_default();
+        let mut grid = Grid::from_raster((generate_raster(kind, seed, &aspect), None, Extents::canonical()), aspect, options);
+        grid.procedural = Some((kind, seed));
+        grid
+    }
+
+    // Replaces a procedural terrain with the one of the next seed, update_grid resamples it
+    pub fn next_seed(&mut self) -> Option<u64> {
+        let (kind, seed) = self.procedural?;
+        let seed = seed.wrapping_add(1);
+        println!("Procedural terrain: {:?}, seed {}", kind, seed);
+        self.raster = Some(generate_raster(kind, seed, &self.aspect));
+        self.procedural = Some((kind, seed));
+        Some(seed)
     }
 
-    pub fn get_data(&self) -> &Vec<Vec<f32>> {
-        &self.data
+    // Raster terrain is only resampled, griding algorithms do not apply to it
+    fn from_heightmap(res: &Resources, grid_path: &str, options: GridOptions) -> Result<Grid, failure::Error> {
+        let bytes = res.load_bytes(grid_path).map_err(err_msg)?;
+        let raster = heightmap::decode_heightmap(&bytes, grid_path)?;
+        println!("Heightmap {}: {}x{}", grid_path, raster[0].len(), raster.len());
+        let aspect = Aspect::from_sides(raster[0].len() as f64, raster.len() as f64);
+        Ok(Grid::from_raster((raster, None, Extents::canonical()), aspect, options))
     }
 
-    fn get_user_grid(res: &Resources, grid_path: &str) -> Result<Vec<na::Vector3<f32>>, failure::Error> {
+    fn from_ascii_grid(res: &Resources, grid_path: &str, options: GridOptions) -> Result<Grid, failure::Error> {
         let grid_file = res.load_cstring(grid_path).map_err(err_msg)?;
         let grid_str = grid_str2file(grid_file, grid_path)?;
-        let grid_lines: Vec<&str> = grid_str.split("\n").collect();
-        let grid_points_str = grid_lines2points_str(&grid_lines)?;
-        let grid_points_f32 = grid_points_str2points_f32(&grid_points_str)?;
-        let grid: Vec<na::Vector3<f32>> = grid_points_f32to_grid(&grid_points_f32)?;
-        Ok(grid)
+        let raster = match grid_path.ends_with(ESRI_ASCII_EXTENSION) {
+            true => ascii_grid::read_esri_ascii(&grid_str, grid_path)?,
+            false => ascii_grid::read_xyz(&grid_str, grid_path)?,
+        };
+        let (heights, _, extents) = &raster;
+        println!("ASCII grid {}: {}x{}, extents {:?}", grid_path, heights[0].len(), heights.len(), extents);
+        let aspect = Aspect::from_sides(extents.max.x - extents.min.x, extents.max.z - extents.min.z);
+        Ok(Grid::from_raster(raster, aspect, options))
+    }
+
+    fn from_raster((raster, raster_nodata, extents): Raster, aspect: Aspect, options: GridOptions) -> Grid {
+        Grid {
+            user_poles: vec![],
+            poles: vec![],
+            raster: Some(raster),
+            raster_nodata,
+            sampled: vec![],
+            data: vec![],
+            edits: vec![],
+            filters: vec![],
+            filters_enabled: true,
+            extents,
+            aspect: options.aspect.unwrap_or(aspect),
+            procedural: None,
+            options,
+        }
+    }
+
+    // x_size samples along X in every one of z_size rows
+    pub fn update_grid(&mut self, x_size: usize, z_size: usize, griding_algo: GridingAlgo) {
+        self.sampled = match &self.raster {
+            Some(raster) => resample_raster(raster, x_size, z_size),
+            None => Grid::make_grid(x_size, z_size, &self.poles, griding_algo, &self.options, &self.aspect),
+        };
+        self.edits = vec![vec![0.; x_size]; z_size];
+        self.apply_filters();
+    }
+
+    // The chain runs on every update_grid, the sampled terrain is kept to switch it on and off
+    pub fn set_filters(&mut self, filters: Vec<Filter>) {
+        self.filters = filters;
+        self.apply_filters();
+    }
+
+    pub fn get_filters(&self) -> &Vec<Filter> {
+        &self.filters
+    }
+
+    pub fn toggle_filters(&mut self) -> bool {
+        self.filters_enabled = !self.filters_enabled;
+        self.apply_filters();
+        self.filters_enabled
+    }
+
+    // Sculpted changes are kept on top of the filtered terrain until the next update_grid
+    fn apply_filters(&mut self) {
+        self.data = match self.filters_enabled && !self.filters.is_empty() && !self.sampled.is_empty() {
+            true => filters::apply_filters(&self.sampled, &self.filters),
+            false => self.sampled.clone(),
+        };
+        for (row, edits) in self.data.iter_mut().zip(&self.edits) {
+            for (height, edit) in row.iter_mut().zip(edits) {
+                if *edit != 0. {
+                    *height = (*height + edit).clamp(MIN_HEIGHT, MAX_HEIGHT);
+                }
+            }
+        }
+    }
+
+    pub fn sculpt(&mut self, brush: &Brush, x: f32, z: f32, target: f32) -> Option<Affected> {
+        sculpt::apply(&mut self.data, &mut self.edits, brush, x, z, target, &self.aspect)
+    }
+
+    // Bilinear terrain height at x, z in [-1;1]
+    pub fn height_at(&self, x: f32, z: f32) -> f32 {
+        let (rows, cols) = (self.data.len(), self.data[0].len());
+        let col = ((x + 1.) / 2. * (cols - 1) as f32).clamp(0., (cols - 1) as f32);
+        let row = ((z + 1.) / 2. * (rows - 1) as f32).clamp(0., (rows - 1) as f32);
+        let (c0, r0) = ((col as usize).min(cols - 2), (row as usize).min(rows - 2));
+        let (tx, tz) = (col - c0 as f32, row - r0 as f32);
+        let top = self.data[r0][c0] * (1. - tx) + self.data[r0][c0 + 1] * tx;
+        let bot = self.data[r0 + 1][c0] * (1. - tx) + self.data[r0 + 1][c0 + 1] * tx;
+        top * (1. - tz) + bot * tz
+    }
+
+    // First point of the segment from -> to (model space) under the terrain, as (x, z)
+    pub fn pick(&self, from: &na::Point3<f32>, to: &na::Point3<f32>) -> Option<(f32, f32)> {
+        let lowest = self.data.iter().flatten().copied().fold(f32::MAX, f32::min);
+        let highest = self.data.iter().flatten().copied().fold(f32::MIN, f32::max);
+        let (from, to) = clip_to_terrain_box(from, to, lowest, highest)?;
+        let below = |p: &na::Point3<f32>| p.y <= self.height_at(p.x, p.z);
+        let cell = 2. / self.data.len().max(self.data[0].len()) as f32;
+        let steps = ((to - from).norm() / (cell / 2.)).ceil().max(1.) as usize;
+
+        let mut previous = from;
+        for step in 1..=steps {
+            let current = from + (to - from) * (step as f32 / steps as f32);
+            if below(&current) {
+                // Bisection between the last point above and the first one below
+                let (mut above, mut under) = (previous, current);
+                for _i in 0..16 {
+                    let middle = na::center(&above, &under);
+                    match below(&middle) {
+                        true => under = middle,
+                        false => above = middle,
+                    }
+                }
+                return Some((under.x, under.z));
+            }
+            previous = current;
+        }
+        None
+    }
+
+    pub fn get_aspect(&self) -> Aspect {
+        self.aspect
+    }
+
+    pub fn get_options(&self) -> GridOptions {
+        self.options
+    }
+
+    pub fn get_extents(&self) -> &Extents {
+        &self.extents
+    }
+
+    pub fn get_user_poles(&self) -> &Vec<na::Vector3<f32>> {
+        &self.user_poles
+    }
+
+    // Only terrain from a point file has poles to edit. Edits take effect on the next update_grid
+    pub fn has_poles(&self) -> bool {
+        self.raster.is_none()
+    }
+
+    pub fn add_pole(&mut self, x: f32, y: f32, z: f32) -> usize {
+        self.user_poles.push(na::Vector3::new(x.clamp(-1., 1.), y.clamp(MIN_HEIGHT, MAX_HEIGHT), z.clamp(-1., 1.)));
+        self.rebuild_poles();
+        self.user_poles.len() - 1
+    }
+
+    pub fn move_pole(&mut self, index: usize, x: f32, z: f32) {
+        self.user_poles[index].x = x.clamp(-1., 1.);
+        self.user_poles[index].z = z.clamp(-1., 1.);
+        self.rebuild_poles();
+    }
+
+    pub fn set_pole_height(&mut self, index: usize, y: f32) {
+        self.user_poles[index].y = y.clamp(MIN_HEIGHT, MAX_HEIGHT);
+        self.rebuild_poles();
+    }
+
+    // The last pole stays, griding needs at least one
+    pub fn remove_pole(&mut self, index: usize) -> bool {
+        if self.user_poles.len() <= 1 {
+            return false;
+        }
+        self.user_poles.remove(index);
+        self.rebuild_poles();
+        true
+    }
+
+    // Closest pole on XZ within max_distance, in world proportions
+    pub fn nearest_pole(&self, x: f32, z: f32, max_distance: f32) -> Option<usize> {
+        self.user_poles.iter().enumerate()
+            .map(|(i, pole)| (i, ((pole.x - x) * self.aspect.x).hypot((pole.z - z) * self.aspect.z)))
+            .filter(|(_, distance)| *distance <= max_distance)
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(i, _)| i)
+    }
+
+    // Terrain section of a snapshot: the source poles or raster and the terrain as it was,
+    // sculpt edits and filters included. Filters themselves come from the command line
+    pub fn write_snapshot(&self, out: &mut snapshot::Writer) {
+        out.vector3_f64(&self.extents.min);
+        out.vector3_f64(&self.extents.max);
+        out.f32(self.aspect.x);
+        out.f32(self.aspect.z);
+        out.usize(self.user_poles.len());
+        self.user_poles.iter().for_each(|pole| out.vector3_f32(pole));
+        out.bool(self.raster.is_some());
+        if let Some(raster) = &self.raster {
+            out.matrix(raster);
+            out.bool(self.raster_nodata.is_some());
+            self.raster_nodata.iter().flatten().flatten().for_each(|&nodata| out.bool(nodata));
+        }
+        out.bool(self.filters_enabled);
+        out.matrix(&self.sampled);
+        out.matrix(&self.edits);
+        out.matrix(&self.data);
+    }
+
+    // Filters are the current ones, the saved terrain already went through them when it was enabled
+    pub fn from_snapshot(input: &mut snapshot::Reader, options: GridOptions,
+                         filters: Vec<Filter>) -> Result<Grid, snapshot::Error> {
+        let invalid = |what: &str| snapshot::Error::InvalidValue { what: what.into() };
+        let extents = Extents { min: input.vector3_f64()?, max: input.vector3_f64()? };
+        let aspect = Aspect { x: input.f32()?, z: input.f32()? };
+        let poles_count = input.count(3 * 4)?;
+        let user_poles = (0..poles_count).map(|_| input.vector3_f32()).collect::<Result<Vec<_>, _>>()?;
+        let raster = match input.bool()? {
+            true => Some(input.matrix()?),
+            false => None,
+        };
+        let raster_nodata = match (&raster, raster.is_some() && input.bool()?) {
+            (Some(raster), true) => {
+                Some(raster.iter()
+                    .map(|row| row.iter().map(|_| input.bool()).collect::<Result<Vec<bool>, _>>())
+                    .collect::<Result<Vec<_>, _>>()?)
+            },
+            _ => None,
+        };
+        let filters_enabled = input.bool()?;
+        let (sampled, edits, data) = (input.matrix()?, input.matrix()?, input.matrix()?);
+
+        let size = |rows: &Vec<Vec<f32>>| (rows.len(), rows.first().map_or(0, |row| row.len()));
+        if raster.is_none() == user_poles.is_empty() {
+            return Err(invalid("terrain source"));
+        }
+        if data.len() < 2 || size(&data).1 < 2 || size(&sampled) != size(&data) || size(&edits) != size(&data) {
+            return Err(invalid("terrain size"));
+        }
+        if data.iter().flatten().any(|height| !(MIN_HEIGHT..=MAX_HEIGHT).contains(height)) {
+            return Err(invalid("terrain heights"));
+        }
+
+        let poles = Grid::add_boundary(&user_poles, options.boundary);
+        Ok(Grid {
+            user_poles, poles, raster, raster_nodata, sampled, data, edits, filters, filters_enabled,
+            extents, aspect, procedural: None, options,
+        })
+    }
+
+    fn rebuild_poles(&mut self) {
+        self.poles = Grid::add_boundary(&self.user_poles, self.options.boundary);
+    }
+
+    // Point file of the user poles in the units of the source file, one "x,y,z" per line
+    pub fn to_mod1(&self) -> String {
+        self.user_poles.iter()
+            .map(|pole| self.extents.to_world(pole))
+            .map(|pole| format!("{},{},{}\n", pole.x as f32, pole.y as f32, pole.z as f32))
+            .collect()
+    }
+
+    // Leave-one-out: every user pole is predicted by the surface through all the other ones,
+    // boundary padding is rebuilt without it. Returns predicted heights in pole order
+    pub fn cross_validate(&self, griding_algo: GridingAlgo) -> Vec<f32> {
+        (0..self.user_poles.len()).map(|left_out| {
+            let others: Vec<na::Vector3<f32>> = self.user_poles.iter().enumerate()
+                .filter(|(i, _)| *i != left_out)
+                .map(|(_, pole)| *pole)
+                .collect();
+            let poles = stretch_poles(&Grid::add_boundary(&others, self.options.boundary), &self.aspect);
+            let griding_function = Grid::match_griding_function(griding_algo, &poles, &self.options, false);
+            let target = &self.user_poles[left_out];
+            griding_function(&na::Vector3::new(target.x * self.aspect.x, 0., target.z * self.aspect.z))
+        }).collect()
+    }
+
+    pub fn get_data(&self) -> &Vec<Vec<f32>> {
+        &self.data
+    }
+
+    // Heights and coordinates are written back in the units of the source file
+    pub fn to_esri_ascii(&self) -> String {
+        ascii_grid::write_esri_ascii(&self.data, self.get_nodata().as_deref(), &self.get_sample_extents())
+    }
+
+    pub fn to_xyz(&self) -> String {
+        ascii_grid::write_xyz(&self.data, self.get_nodata().as_deref(), &self.get_sample_extents())
+    }
+
+    // World box from the first sample to the last one. Rasters are resampled corner to corner,
+    // point files are sampled from -1 up to one step short of 1
+    fn get_sample_extents(&self) -> Extents {
+        if self.raster.is_some() {
+            return self.extents;
+        }
+        let last = |size: usize| 1. - 2. / size as f32;
+        let max = self.extents.to_world(&na::Vector3::new(last(self.data[0].len()), 1., last(self.data.len())));
+        Extents { min: self.extents.min, max: na::Vector3::new(max.x, self.extents.max.y, max.z) }
+    }
+
+    // Samples that mix in a no data cell of the source raster
+    fn get_nodata(&self) -> Option<Vec<Vec<bool>>> {
+        let mask = self.raster_nodata.as_ref()?;
+        Some(resample_nodata(mask, self.data[0].len(), self.data.len()))
+    }
+
+    // Points of all files are transformed, then normalized together
+    fn get_user_grid(res: &Resources, files: &[PointFile],
+                     normalization: Normalization) -> Result<(Vec<na::Vector3<f32>>, Extents), failure::Error> {
+        let mut grid_points_f32: Vec<Vec<f32>> = vec![];
+        let mut positions: Vec<Vec<PointPosition>> = Vec::with_capacity(files.len());
+        for file in files {
+            let grid_path = file.path.as_str();
+            if !grid_path.ends_with(POINT_FILE_EXTENSION) {
+                return Err(Error::NotAPointFile { name: grid_path.into() }.into());
+            }
+            let grid_file = res.load_cstring(grid_path).map_err(err_msg)?;
+            let grid_str = grid_str2file(grid_file, grid_path)?;
+            let grid_lines: Vec<&str> = grid_str.split("\n").collect();
+            let grid_points_str = grid_lines2points_str(&grid_lines, grid_path)?;
+            let file_points = grid_points_str2points_f32(&grid_points_str, grid_path)?;
+            match file.transform.is_identity() {
+                true => grid_points_f32.extend(file_points),
+                false => grid_points_f32.extend(file_points.iter().map(|point| file.transform.apply(point))),
+            }
+            positions.push(grid_points_str.iter().map(|point| point.position).collect());
+        }
+        if files.len() > 1 {
+            println!("Point files merged: {} points from {} files", grid_points_f32.len(), files.len());
+        }
+
+        let (grid_points_f32, extents) = normalize_points(&grid_points_f32, normalization);
+        let mut grid: Vec<na::Vector3<f32>> = Vec::with_capacity(grid_points_f32.len());
+        for (file, file_positions) in files.iter().zip(&positions) {
+            let first = grid.len();
+            let file_points = &grid_points_f32[first..first + file_positions.len()];
+            grid.extend(grid_points_f32to_grid(file_points, file_positions, &file.path)?);
+        }
+        Ok((grid, extents))
+    }
+
+    fn add_boundary(input: &Vec<na::Vector3<f32>>, boundary: BoundaryMode) -> Vec<na::Vector3<f32>> {
+        match boundary {
+            BoundaryMode::Zero(count) => Grid::add_zeros_to_edges(input, count),
+            BoundaryMode::Clamp(count) => Grid::add_clamped_edges(input, count),
+            BoundaryMode::Mirror => Grid::add_mirrored_edges(input),
+            BoundaryMode::None => input.clone(),
+        }
+    }
+
+    // Same edge samples as zero walls, but every sample copies the height of the nearest pole
+    fn add_clamped_edges(input: &Vec<na::Vector3<f32>>, count: i32) -> Vec<na::Vector3<f32>> {
+        let mut input_clamped = Grid::add_zeros_to_edges(input, count);
+        for edge in input_clamped.iter_mut().take((count * 4) as usize) {
+            edge.y = input.iter()
+                .map(|pole| (length_on_xz(edge, pole), pole.y))
+                .fold((f32::MAX, 0.), |nearest, cur| if cur.0 < nearest.0 { cur } else { nearest })
+                .1;
+        }
+        input_clamped
+    }
+
+    // Poles close to the border are reflected behind it, so the surface has no slope across the edge
+    fn add_mirrored_edges(input: &Vec<na::Vector3<f32>>) -> Vec<na::Vector3<f32>> {
+        let mirror = |coord: f32| -> Vec<f32> {
+            let mut coords = vec![coord];
+            // Poles lying right on the border would be reflected onto themselves
+            if coord > -1. && coord < -1. + MIRROR_MARGIN {
+                coords.push(-2. - coord);
+            }
+            if coord < 1. && coord > 1. - MIRROR_MARGIN {
+                coords.push(2. - coord);
+            }
+            coords
+        };
+
+        let mut input_mirrored: Vec<na::Vector3<f32>> = Vec::with_capacity(input.len() * 4);
+        for elem in input {
+            for x in mirror(elem.x) {
+                for z in mirror(elem.z) {
+                    input_mirrored.push(na::Vector3::new(x, elem.y, z));
+                }
+            }
+        }
+        input_mirrored
     }
 
     fn add_zeros_to_edges(input: &Vec<na::Vector3<f32>>, count: i32) -> Vec<na::Vector3<f32>> {
@@ -74,71 +785,142 @@ impl Grid {
         input_zeroed
     }
 
-    // Makes isomorphic size*size 2d grid on [-1;1] through input points (poles)
-    fn make_grid(size: usize, poles: &Vec<na::Vector3<f32>>, griding_algo: GridingAlgo) -> Vec<Vec<f32>> {
-        let step: f32 = 2. / size as f32;
-        let griding_function = Grid::match_griding_function(griding_algo);
-        let mut cur_point: na::Vector3<f32> = na::Vector3::new(-1. - step, 0., -1. - step);
-        let mut grid: Vec<Vec<f32>> = vec![vec![0.; size]; size];
+    // Makes x_size*z_size 2d grid on [-1;1] through input points (poles).
+    // Poles and samples are stretched by the aspect, so distances are measured in world proportions
+    fn make_grid(x_size: usize, z_size: usize, poles: &Vec<na::Vector3<f32>>, griding_algo: GridingAlgo,
+                 options: &GridOptions, aspect: &Aspect) -> Vec<Vec<f32>> {
+        let poles = stretch_poles(poles, aspect);
+        let griding_function = Grid::match_griding_function(griding_algo, &poles, options, true);
+        // Accumulated the same way for every row, so the samples do not depend on the thread split
+        let coords = |size: usize, scale: f32| -> Vec<f32> {
+            let step: f32 = 2. / size as f32;
+            (0..size)
+                .scan(-1. - step, |coord, _| { *coord += step; Some(*coord * scale) })
+                .collect()
+        };
+        let (x_coords, z_coords) = (coords(x_size, aspect.x), coords(z_size, aspect.z));
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        Grid::sample_rows(&griding_function, &x_coords, &z_coords, threads)
+    }
 
-        for row in &mut grid {
-            cur_point.z += step;
-            for elem in row {
-                cur_point.x += step;
-                *elem = griding_function(&cur_point, poles);
+    // Rows are split between threads, every sample is computed the same way whatever the split
+    fn sample_rows(griding_function: &GridingFunction, x_coords: &[f32], z_coords: &[f32],
+                   threads: usize) -> Vec<Vec<f32>> {
+        let (x_size, z_size) = (x_coords.len(), z_coords.len());
+        let mut grid: Vec<Vec<f32>> = vec![vec![0.; x_size]; z_size];
+
+        let threads = threads.min(z_size).max(1);
+        let rows_per_thread = z_size.div_ceil(threads).max(1);
+        thread::scope(|scope| {
+            for (chunk, rows) in grid.chunks_mut(rows_per_thread).enumerate() {
+                scope.spawn(move || {
+                    for (i, row) in rows.iter_mut().enumerate() {
+                        let z = z_coords[chunk * rows_per_thread + i];
+                        for (elem, &x) in row.iter_mut().zip(x_coords) {
+                            *elem = griding_function(&na::Vector3::new(x, 0., z));
+                        }
+                    }
+                });
             }
-            cur_point.x = -1. - step;
-        }
+        });
         grid
     }
 
-    fn match_griding_function(griding_algo: GridingAlgo) -> fn(&na::Vector3<f32>, &Vec<na::Vector3<f32>>) -> f32 {
+    // Algorithms that need a solved system prepare it here, once per grid
+    fn match_griding_function<'a>(griding_algo: GridingAlgo, poles: &'a Vec<na::Vector3<f32>>,
+                                  options: &GridOptions, verbose: bool) -> GridingFunction<'a> {
         match griding_algo {
-            GridingAlgo::Kriging => Grid::kriging_calculate_point,
-            GridingAlgo::RadialBasisFunction => Grid::rbf_calculate_point,
+            GridingAlgo::Kriging => {
+                let kriging = Kriging::new(poles, &options.variogram, &options.neighbourhood);
+                if verbose {
+                    println!("{}", kriging.describe());
+                }
+                Box::new(move |point| kriging.calculate_point(point))
+            },
+            GridingAlgo::RadialBasisFunction if options.neighbourhood.is_unbounded() => {
+                Box::new(move |point| Grid::rbf_calculate_point(point, poles.iter(), poles.len()))
+            },
+            GridingAlgo::RadialBasisFunction => {
+                let (tree, neighbourhood) = (KdTree::new(poles), options.neighbourhood);
+                Box::new(move |point| {
+                    let neighbours = tree.neighbours(point, &neighbourhood);
+                    Grid::rbf_calculate_point(point, neighbours.iter().map(|&i| &poles[i]), poles.len())
+                })
+            },
+            GridingAlgo::ThinPlateSpline => Grid::rbf_griding_function(poles, RbfKernel::ThinPlateSpline, options, verbose),
+            GridingAlgo::Multiquadric => Grid::rbf_griding_function(poles, RbfKernel::Multiquadric, options, verbose),
+            GridingAlgo::InverseMultiquadric => Grid::rbf_griding_function(poles, RbfKernel::InverseMultiquadric, options, verbose),
+            GridingAlgo::GaussianRbf => Grid::rbf_griding_function(poles, RbfKernel::Gaussian, options, verbose),
+            GridingAlgo::Linear => {
+                let triangulation = Triangulation::new(poles);
+                Box::new(move |point| triangulation.linear_calculate_point(point))
+            },
+            GridingAlgo::NaturalNeighbour => {
+                let triangulation = Triangulation::new(poles);
+                Box::new(move |point| triangulation.natural_neighbour_calculate_point(point))
+            },
         }
     }
 
-    fn rbf_calculate_point(cur_point: &na::Vector3<f32>, poles: &Vec<na::Vector3<f32>>) -> f32 {
-        let mut rev_distances: Vec<f32> = Vec::with_capacity(poles.len());
-
-        for pole in poles {
-            let dist = max(length_on_xz(cur_point, pole), f32::EPSILON * 100.);
-            rev_distances.push(1. / dist);
+    fn rbf_griding_function<'a>(poles: &Vec<na::Vector3<f32>>, kernel: RbfKernel, options: &GridOptions,
+                                verbose: bool) -> GridingFunction<'a> {
+        let rbf = Rbf::new(poles, kernel, options.rbf_epsilon, options.rbf_smoothing, &options.neighbourhood);
+        if verbose {
+            println!("{}", rbf.describe());
         }
+        Box::new(move |point| rbf.calculate_point(point))
+    }
 
-        let shaping_factor = 5.6 / (25. * poles.len() as f32);
+    // The shaping factor depends on the total pole count, so a neighbourhood only drops far poles
+    fn rbf_calculate_point<'p, I>(cur_point: &na::Vector3<f32>, poles: I, poles_count: usize) -> f32
+        where I: Iterator<Item = &'p na::Vector3<f32>> {
+        let shaping_factor = 5.6 / (25. * poles_count as f32);
         // let rbf_func = |dist :&f32| ((dist * dist) + shaping_factor).sqrt();
         // let rbf_func = |dist :&f32| 1. / ((dist * dist) + shaping_factor).sqrt();
         // let rbf_func = |dist :&f32| ((dist * dist) + shaping_factor).ln();
         let rbf_func = |dist :&f32| ((dist * dist) + shaping_factor).powf(3.).sqrt();
+        let weight = |pole: &na::Vector3<f32>| {
+            let dist = max(length_on_xz(cur_point, pole), f32::EPSILON * 100.);
+            rbf_func(&(1. / dist))  // TODO: add function to config
+        };
+
+        let (weights_sum, y_sum) = poles
+            .map(|pole| (weight(pole), pole.y))
+            .fold((0., 0.), |(weights_sum, y_sum), (weight, y)| (weights_sum + weight, y_sum + weight * y));
+        y_sum / weights_sum
+    }
+}
 
-        let weights: Vec<f32> = rev_distances.iter().map(rbf_func).collect(); // TODO: add function to config
-        let weights_sum: f32 = weights.iter().sum();
+fn generate_raster(kind: TerrainKind, seed: u64, aspect: &Aspect) -> Vec<Vec<f32>> {
+    let side = |scale: f32| ((PROCEDURAL_RASTER_SIZE as f32 * scale).round() as usize).max(2);
+    procedural::generate(kind, seed, side(aspect.x), side(aspect.z))
+}
 
-        let mut y_value = 0.;
+fn stretch_poles(poles: &[na::Vector3<f32>], aspect: &Aspect) -> Vec<na::Vector3<f32>> {
+    poles.iter()
+        .map(|pole| na::Vector3::new(pole.x * aspect.x, pole.y, pole.z * aspect.z))
+        .collect()
+}
 
-        for (w, d) in weights.iter().zip(poles) {
-            let weight = w / weights_sum;
-            y_value += d.y * weight;
+// Part of the segment inside the box the terrain occupies in model space
+fn clip_to_terrain_box(from: &na::Point3<f32>, to: &na::Point3<f32>,
+                       lowest: f32, highest: f32) -> Option<(na::Point3<f32>, na::Point3<f32>)> {
+    let direction = to - from;
+    let (mut enter, mut exit) = (0_f32, 1_f32);
+    for &(axis, min, max) in &[(0, -1., 1.), (1, lowest, highest), (2, -1., 1.)] {
+        if direction[axis].abs() < f32::EPSILON {
+            if from[axis] < min || from[axis] > max {
+                return None;
+            }
+            continue;
         }
-        y_value
+        let (t0, t1) = ((min - from[axis]) / direction[axis], (max - from[axis]) / direction[axis]);
+        enter = enter.max(t0.min(t1));
+        exit = exit.min(t0.max(t1));
     }
-
-    fn kriging_calculate_point(cur_point: &na::Vector3<f32>, poles: &Vec<na::Vector3<f32>>) -> f32 {
-        let mut rev_distances: Vec<f32> = Vec::with_capacity(poles.len());
-        let mut sum_rev_dists: f32 = 0.;
-        for pole in poles {
-            let rev_dist = 1. / max(length_on_xz(cur_point, pole), f32::MIN_POSITIVE * 100.);
-            sum_rev_dists += rev_dist;
-            rev_distances.push(rev_dist);
-        }
-        let weights: Vec<f32> = rev_distances.iter().map(|rev_dist| rev_dist / sum_rev_dists).collect();
-        let mut y_value = 0.;
-        for (w, d) in weights.iter().zip(poles) {
-            y_value += d.y * w;
-        }
-        y_value
+    match enter <= exit {
+        true => Some((from + direction * enter, from + direction * exit)),
+        false => None,
     }
 }
 
@@ -151,48 +933,312 @@ fn max(a: f32, b: f32) -> f32 {
     }
 }
 
+// Solves a dense system, falling back to least squares when it is singular (e.g. duplicated poles)
+fn solve_linear_system(matrix: na::DMatrix<f64>, rhs: na::DVector<f64>) -> na::DVector<f64> {
+    if let Some(solution) = matrix.clone().lu().solve(&rhs) {
+        if solution.iter().all(|v| v.is_finite()) {
+            return solution;
+        }
+    }
+    matrix.svd(true, true).solve(&rhs, 1e-12)
+        .unwrap_or_else(|_| na::DVector::zeros(rhs.len()))
+}
+
+// Bilinear resampling of a raster (rows along Z) to z_size rows of x_size.
+// Both are node grids spanning the same extents, so corners are kept in place
+fn resample_raster(raster: &[Vec<f32>], x_size: usize, z_size: usize) -> Vec<Vec<f32>> {
+    let (height, width) = (raster.len(), raster[0].len());
+    (0..z_size).map(|row| {
+        let (r0, r1, tv) = resample_position(row, height, z_size);
+        (0..x_size).map(|col| {
+            let (c0, c1, tu) = resample_position(col, width, x_size);
+            let top = raster[r0][c0] * (1. - tu) + raster[r0][c1] * tu;
+            let bot = raster[r1][c0] * (1. - tu) + raster[r1][c1] * tu;
+            top * (1. - tv) + bot * tv
+        }).collect()
+    }).collect()
+}
+
+// A sample is no data when any raster cell weighing in its height is
+fn resample_nodata(mask: &[Vec<bool>], x_size: usize, z_size: usize) -> Vec<Vec<bool>> {
+    let (height, width) = (mask.len(), mask[0].len());
+    (0..z_size).map(|row| {
+        let (r0, r1, tv) = resample_position(row, height, z_size);
+        (0..x_size).map(|col| {
+            let (c0, c1, tu) = resample_position(col, width, x_size);
+            mask[r0][c0] || (tu > 0. && mask[r0][c1]) || (tv > 0. && (mask[r1][c0] || (tu > 0. && mask[r1][c1])))
+        }).collect()
+    }).collect()
+}
+
+// Raster cells around sample i of size along a side of len cells, and the weight of the second one
+fn resample_position(i: usize, len: usize, size: usize) -> (usize, usize, f32) {
+    let v = match size {
+        1 => 0.,
+        _ => i as f32 * (len - 1) as f32 / (size - 1) as f32,
+    };
+    let i0 = (v.floor() as usize).min(len - 1);
+    (i0, (i0 + 1).min(len - 1), v - i0 as f32)
+}
+
 fn length_on_xz(p1: &na::Vector3<f32>, p2: &na::Vector3<f32>) -> f32 {
     ((p1.x - p2.x).powf(2.) + (p1.z - p2.z).powf(2.)).sqrt()
 }
 
+// Solved systems use the same f64 distances as their evaluation, so exact interpolators
+// go through the poles up to f32 rounding
+fn length_on_xz_f64(p1: &na::Vector3<f32>, p2: &na::Vector3<f32>) -> f64 {
+    ((p1.x as f64 - p2.x as f64).powi(2) + (p1.z as f64 - p2.z as f64).powi(2)).sqrt()
+}
+
 fn grid_str2file(str: CString, filename: &str) -> Result<String, Error> {
     str.into_string().map_err(
         |_| Error::UnableConvertFileToString { name: filename.into() }
     )
 }
 
-fn grid_lines2points_str<'a>(lines: &Vec<&'a str>) -> Result<Vec<Vec<&'a str>>, Error> {
-    lines.iter().map(|line| {
-        let coords: Vec<&str> = line.split(",").collect();
-        match coords.len() {
-            3 => Ok(coords),
-            _ => Err(Error::PointDoNotHave3Components {name: line.to_string()})
-        }}
-    ).collect::<Result<Vec<Vec<&str>>, Error>>()
+// Where the coordinates of a point were found in the file, 1-based
+#[derive(Copy, Clone)]
+struct PointPosition {
+    line: usize,
+    columns: [usize; 3],
+}
+
+struct PointFields<'a> {
+    position: PointPosition,
+    coords: Vec<&'a str>,
 }
 
-fn grid_points_str2points_f32(points: &Vec<Vec<&str>>) -> Result<Vec<Vec<f32>>, Error> {
-    points.iter().map(|coords| {
-        coords.iter().map(|coord| {
-            coord.trim().parse::<f32>().map_err(|e| Error::ComponentIsNotF32 { name: coord.to_string(), message: e.to_string() })
+// Fields are separated by any whitespace, ',' or ';'. Returns (column, field) pairs
+fn split_fields(line: &str) -> Vec<(usize, &str)> {
+    let mut fields: Vec<(usize, &str)> = vec![];
+    let mut start: Option<(usize, usize)> = None;     // (column, byte offset)
+    for (column, (offset, c)) in line.char_indices().enumerate() {
+        let is_separator = c.is_whitespace() || c == ',' || c == ';';
+        match (start, is_separator) {
+            (None, false) => start = Some((column + 1, offset)),
+            (Some((field_column, field_offset)), true) => {
+                fields.push((field_column, &line[field_offset..offset]));
+                start = None;
+            },
+            _ => (),
+        }
+    }
+    if let Some((field_column, field_offset)) = start {
+        fields.push((field_column, &line[field_offset..]));
+    }
+    fields
+}
+
+// Skips blank lines, '#' comments and one header row before the first point. A header has
+// no number in its first three fields, any other line is a point. Columns after z (labels, weights) are ignored
+fn grid_lines2points_str<'a>(lines: &Vec<&'a str>, filename: &str) -> Result<Vec<PointFields<'a>>, Error> {
+    let mut points: Vec<PointFields<'a>> = Vec::with_capacity(lines.len());
+    let mut header_seen = false;
+    for (i, line) in lines.iter().enumerate() {
+        let content = line.split('#').next().unwrap_or("");
+        let fields = split_fields(content);
+        if fields.is_empty() {
+            continue;
+        }
+        let is_header = points.is_empty() && !header_seen
+            && fields.iter().take(3).all(|field| field.1.parse::<f32>().is_err());
+        if is_header {
+            header_seen = true;
+            continue;
+        }
+        if fields.len() < 3 {
+            return Err(Error::PointDoNotHave3Components {
+                file: filename.into(), line: i + 1, name: line.trim_end().to_string()
+            });
+        }
+        points.push(PointFields {
+            position: PointPosition { line: i + 1, columns: [fields[0].0, fields[1].0, fields[2].0] },
+            coords: fields.iter().take(3).map(|field| field.1).collect(),
+        });
+    }
+    Ok(points)
+}
+
+fn grid_points_str2points_f32(points: &Vec<PointFields>, filename: &str) -> Result<Vec<Vec<f32>>, Error> {
+    points.iter().map(|point| {
+        point.coords.iter().zip(&point.position.columns).map(|(coord, column)| {
+            coord.parse::<f32>().map_err(|e| Error::ComponentIsNotF32 {
+                file: filename.into(),
+                line: point.position.line,
+                column: *column,
+                name: coord.to_string(),
+                message: e.to_string(),
+            })
         }).collect::<Result<Vec<f32>, Error>>()
     }).collect::<Result<Vec<Vec<f32>>, Error>>()
 }
 
-fn grid_points_f32to_grid(points: &Vec<Vec<f32>>) -> Result<Vec<na::Vector3<f32>>, Error> {
-    points.iter().map(|point| {
+// Maps the bounding box of the points into the canonical cube, heights always fill [0;1].
+// Returned extents describe the original box for converting back, uniform keeps
+// the X/Z proportions of the box as the world aspect
+fn normalize_points(points: &Vec<Vec<f32>>, normalization: Normalization) -> (Vec<Vec<f32>>, Extents) {
+    if normalization == Normalization::Strict || points.is_empty() {
+        return (points.clone(), Extents::canonical());
+    }
+
+    let mut min = na::Vector3::repeat(f64::MAX);
+    let mut max = na::Vector3::repeat(f64::MIN);
+    for point in points {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(point[axis] as f64);
+            max[axis] = max[axis].max(point[axis] as f64);
+        }
+    }
+
+    let extents = Extents { min, max };
+    let to_unit = |value: f32, axis: usize| -> f64 {
+        match extents.max[axis] - extents.min[axis] {
+            span if span > 0. => (value as f64 - extents.min[axis]) / span,
+            _ => 0.5,
+        }
+    };
+    let normalized = points.iter()
+        .map(|point| vec![
+            (to_unit(point[0], 0) * 2. - 1.) as f32,
+            to_unit(point[1], 1) as f32,
+            (to_unit(point[2], 2) * 2. - 1.) as f32,
+        ])
+        .collect();
+    println!("Points normalized from {:?}", extents);
+    (normalized, extents)
+}
+
+fn grid_points_f32to_grid(points: &[Vec<f32>], positions: &[PointPosition],
+                          filename: &str) -> Result<Vec<na::Vector3<f32>>, Error> {
+    points.iter().zip(positions).map(|(point, position)| {
         let x = match point[0] {
             x if x >= -1. && x <= 1. => Ok(x),
-            _ => Err(Error::ComponentXNotValid { name: point[0].to_string() }),
+            _ => Err(Error::ComponentXNotValid {
+                file: filename.into(), line: position.line, column: position.columns[0], name: point[0].to_string()
+            }),
         }?;
         let y = match point[1] {
-            y if y >= 0. && y <= 1. => Ok(y),
-            _ => Err(Error::ComponentYNotValid { name: point[1].to_string() }),
+            y if y >= MIN_HEIGHT && y <= MAX_HEIGHT => Ok(y),
+            _ => Err(Error::ComponentYNotValid {
+                file: filename.into(), line: position.line, column: position.columns[1], name: point[1].to_string()
+            }),
         }?;
         let z = match point[2] {
             z if z >= -1. && z <= 1. => Ok(z),
-            _ => Err(Error::ComponentZNotValid { name: point[2].to_string() }),
+            _ => Err(Error::ComponentZNotValid {
+                file: filename.into(), line: position.line, column: position.columns[2], name: point[2].to_string()
+            }),
         }?;
         Ok(na::Vector3::new(x, y, z))
     }).collect::<Result<Vec<na::Vector3<f32>>, Error>>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deterministic poles spread over [-1;1] with a smooth height
+    fn poles(count: usize) -> Vec<na::Vector3<f32>> {
+        let mut state: u64 = 0x9e3779b97f4a7c15;
+        let mut random = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 40) as f32 / (1u64 << 24) as f32 * 2. - 1.
+        };
+        (0..count).map(|_| {
+            let (x, z) = (random(), random());
+            na::Vector3::new(x, 0.5 + 0.3 * (x * 2.).sin() * z, z)
+        }).collect()
+    }
+
+    fn coords(size: usize) -> Vec<f32> {
+        (0..size).map(|i| i as f32 * 2. / (size - 1) as f32 - 1.).collect()
+    }
+
+    fn samples(poles: &Vec<na::Vector3<f32>>, griding_algo: GridingAlgo, options: &GridOptions) -> Vec<Vec<f32>> {
+        let griding_function = Grid::match_griding_function(griding_algo, poles, options, false);
+        Grid::sample_rows(&griding_function, &coords(13), &coords(11), 1)
+    }
+
+    // Point file text through the same steps as get_user_grid, without normalization
+    fn parse_points(text: &str) -> Result<Vec<(usize, [usize; 3], Vec<f32>)>, Error> {
+        let lines: Vec<&str> = text.split("\n").collect();
+        let fields = grid_lines2points_str(&lines, "test.mod1")?;
+        let points = grid_points_str2points_f32(&fields, "test.mod1")?;
+        let positions: Vec<PointPosition> = fields.iter().map(|point| point.position).collect();
+        grid_points_f32to_grid(&points, &positions, "test.mod1")?;
+        Ok(positions.iter().zip(points).map(|(position, point)| (position.line, position.columns, point)).collect())
+    }
+
+    fn parse_error(text: &str) -> String {
+        match parse_points(text) {
+            Ok(_) => panic!("{:?} was accepted", text),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn points_accept_crlf_line_endings() {
+        let points = parse_points("0.1,0.2,0.3\r\n-0.5 0.4 0.5\r\n").unwrap();
+        assert_eq!(points, vec![(1, [1, 5, 9], vec![0.1, 0.2, 0.3]), (2, [1, 6, 10], vec![-0.5, 0.4, 0.5])]);
+    }
+
+    #[test]
+    fn points_skip_comments_and_blank_lines() {
+        let points = parse_points("# survey\n\n0.1 0.2 0.3 # first\n   \n#0.9 0.9 0.9\n0.4 0.5 0.6\n").unwrap();
+        assert_eq!(points, vec![(3, [1, 5, 9], vec![0.1, 0.2, 0.3]), (6, [1, 5, 9], vec![0.4, 0.5, 0.6])]);
+    }
+
+    #[test]
+    fn points_accept_a_single_header_row() {
+        let points = parse_points("# exported\nx;y;z;label\n0.1;0.2;0.3;a\n").unwrap();
+        assert_eq!(points, vec![(3, [1, 5, 9], vec![0.1, 0.2, 0.3])]);
+        assert!(parse_error("x y z\nx y z\n0.1 0.2 0.3\n").starts_with("test.mod1:2:1: non f32 component found: x"));
+        assert!(parse_error("0.1 0.2 0.3\nx y z\n").starts_with("test.mod1:2:1: non f32 component found: x"));
+    }
+
+    #[test]
+    fn points_accept_mixed_separators() {
+        let points = parse_points("0.1, 0.2;0.3\n0.4\t0.5 ,0.6 label 7\n").unwrap();
+        assert_eq!(points, vec![(1, [1, 6, 10], vec![0.1, 0.2, 0.3]), (2, [1, 5, 10], vec![0.4, 0.5, 0.6])]);
+    }
+
+    #[test]
+    fn point_errors_give_the_line_and_column() {
+        assert!(parse_error("0.1 0.2 0.3\n0.4 abc 0.6\n").starts_with("test.mod1:2:5: non f32 component found: abc"));
+        assert!(parse_error("0.1 0.2 0.3\n\n 0.4,0.5,1.5\n").starts_with("test.mod1:3:10: component Z is not in range"));
+        assert!(parse_error("0.1 0.2 0.3\r\n0.4 0.5\r\n").starts_with("test.mod1:2: point does not have 3 components"));
+    }
+
+    #[test]
+    fn neighbourhood_of_all_poles_matches_full_evaluation() {
+        let poles = poles(40);
+        let full = GridOptions::default();
+        assert!(full.neighbourhood.is_unbounded());
+        let all_poles = [
+            Neighbourhood { count: Some(poles.len()), radius: None },
+            Neighbourhood { count: None, radius: Some(10.) },
+        ];
+        for &griding_algo in &[GridingAlgo::RadialBasisFunction, GridingAlgo::Kriging, GridingAlgo::ThinPlateSpline,
+                               GridingAlgo::Multiquadric, GridingAlgo::InverseMultiquadric, GridingAlgo::GaussianRbf] {
+            let expected = samples(&poles, griding_algo, &full);
+            for &neighbourhood in &all_poles {
+                let options = GridOptions { neighbourhood, ..full };
+                assert_eq!(samples(&poles, griding_algo, &options), expected, "{}", griding_algo.name());
+            }
+        }
+    }
+
+    #[test]
+    fn threads_do_not_change_the_grid() {
+        let poles = poles(30);
+        for &griding_algo in &[GridingAlgo::RadialBasisFunction, GridingAlgo::Kriging, GridingAlgo::ThinPlateSpline] {
+            let griding_function = Grid::match_griding_function(griding_algo, &poles, &GridOptions::default(), false);
+            let (x_coords, z_coords) = (coords(17), coords(23));
+            let expected = Grid::sample_rows(&griding_function, &x_coords, &z_coords, 1);
+            for threads in &[2, 3, 8, 64] {
+                assert_eq!(Grid::sample_rows(&griding_function, &x_coords, &z_coords, *threads), expected);
+            }
+        }
+    }
+}
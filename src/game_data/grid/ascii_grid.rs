@@ -0,0 +1,263 @@
+use std::fmt::Write;
+use super::{Error, Extents};
+
+const ESRI_NODATA: f64 = -9999.;
+
+// Heights in [0;1], rows south to north, and the no data cells when there are any.
+// Samples are nodes: the extents go from the first cell centre to the last one
+pub type Raster = (Vec<Vec<f32>>, Option<Vec<Vec<bool>>>, Extents);
+
+// Reads an ESRI ASCII grid. Rows are returned south to north, so the first row lies at z = -1
+pub fn read_esri_ascii(text: &str, name: &str) -> Result<Raster, Error> {
+    let invalid = |message: String| Error::InvalidAsciiGrid { name: name.into(), message };
+    let mut tokens = text.split_ascii_whitespace().peekable();
+
+    let mut ncols: Option<usize> = None;
+    let mut nrows: Option<usize> = None;
+    let mut x_corner: Option<(f64, bool)> = None;   // (value, is cell center)
+    let mut y_corner: Option<(f64, bool)> = None;
+    let mut cellsize: Option<(f64, f64)> = None;
+    let mut nodata: Option<f64> = None;
+
+    while let Some(key) = tokens.peek().filter(|t| t.starts_with(|c: char| c.is_ascii_alphabetic())) {
+        let key = key.to_ascii_lowercase();
+        tokens.next();
+        let value = tokens.next().ok_or_else(|| invalid(format!("missing value for {}", key)))?;
+        let invalid_value = || invalid(format!("invalid value for {}: {}", key, value));
+        // Counts are whole numbers, cell sizes positive ones
+        let count = || value.parse::<usize>().map_err(|_| invalid_value());
+        let number = || value.parse::<f64>().map_err(|_| invalid_value());
+        let size = || match number()? {
+            size if size.is_finite() && size > 0. => Ok(size),
+            _ => Err(invalid(format!("{} must be positive, found {}", key, value))),
+        };
+        match key.as_str() {
+            "ncols" => ncols = Some(count()?),
+            "nrows" => nrows = Some(count()?),
+            "xllcorner" => x_corner = Some((number()?, false)),
+            "xllcenter" => x_corner = Some((number()?, true)),
+            "yllcorner" => y_corner = Some((number()?, false)),
+            "yllcenter" => y_corner = Some((number()?, true)),
+            "cellsize" => { let size = size()?; cellsize = Some((size, size)) },
+            "dx" => { let dx = size()?; cellsize = Some((dx, cellsize.map_or(dx, |c| c.1))) },
+            "dy" => { let dy = size()?; cellsize = Some((cellsize.map_or(dy, |c| c.0), dy)) },
+            "nodata_value" => nodata = Some(number()?),
+            _ => return Err(invalid(format!("unknown header key {}", key))),
+        }
+    }
+
+    let (ncols, nrows) = match (ncols, nrows) {
+        (Some(c), Some(r)) if c > 1 && r > 1 => (c, r),
+        _ => return Err(invalid("ncols and nrows of at least 2 are required".into())),
+    };
+    let (dx, dy) = cellsize.unwrap_or((1., 1.));
+    let (x_corner, x_center) = x_corner.unwrap_or((0., false));
+    let (y_corner, y_center) = y_corner.unwrap_or((0., false));
+    let x_min = if x_center { x_corner } else { x_corner + dx / 2. };
+    let z_min = if y_center { y_corner } else { y_corner + dy / 2. };
+
+    let cells = ncols.checked_mul(nrows).ok_or_else(|| invalid(format!("grid of {}x{} is too large", ncols, nrows)))?;
+
+    let values = tokens
+        .map(|token| token.parse::<f64>().map_err(|_| invalid(format!("invalid value {}", token))))
+        .collect::<Result<Vec<f64>, Error>>()?;
+    if values.len() < cells {
+        return Err(invalid(format!("expected {} values, found {}", cells, values.len())));
+    }
+
+    // File rows go north to south
+    let rows: Vec<Vec<Option<f64>>> = values[..cells].chunks(ncols).rev()
+        .map(|row| row.iter().map(|&v| if Some(v) == nodata { None } else { Some(v) }).collect())
+        .collect();
+
+    let (raster, y_min, y_max) = normalize_heights(&rows).ok_or_else(|| invalid("grid has no data".into()))?;
+    let extents = Extents {
+        min: na::Vector3::new(x_min, y_min, z_min),
+        max: na::Vector3::new(x_min + dx * (ncols - 1) as f64, y_max, z_min + dy * (nrows - 1) as f64),
+    };
+    Ok((raster, nodata_mask(&rows), extents))
+}
+
+// Samples are written as cell centres spanning the extents, so the export lines up with the source
+pub fn write_esri_ascii(data: &[Vec<f32>], nodata: Option<&[Vec<bool>]>, extents: &Extents) -> String {
+    let (nrows, ncols) = (data.len(), data[0].len());
+    let dx = (extents.max.x - extents.min.x) / (ncols - 1) as f64;
+    let dy = (extents.max.z - extents.min.z) / (nrows - 1) as f64;
+
+    let mut out = String::new();
+    let _ = writeln!(out, "ncols {}", ncols);
+    let _ = writeln!(out, "nrows {}", nrows);
+    let _ = writeln!(out, "xllcenter {}", extents.min.x);
+    let _ = writeln!(out, "yllcenter {}", extents.min.z);
+    match (dx - dy).abs() <= f64::EPSILON * dx.abs().max(1.) {
+        true => { let _ = writeln!(out, "cellsize {}", dx); },
+        false => { let _ = writeln!(out, "dx {}\ndy {}", dx, dy); },
+    }
+    let _ = writeln!(out, "NODATA_value {}", ESRI_NODATA);
+    for (r, row) in data.iter().enumerate().rev() {
+        let line: Vec<String> = row.iter().enumerate()
+            .map(|(c, &h)| match is_nodata(nodata, r, c) {
+                true => ESRI_NODATA.to_string(),
+                false => (extents.height_to_world(h) as f32).to_string(),
+            })
+            .collect();
+        let _ = writeln!(out, "{}", line.join(" "));
+    }
+    out
+}
+
+// Reads "x y z" lines of a regular raster, in any order. Missing cells are treated as no data
+pub fn read_xyz(text: &str, name: &str) -> Result<Raster, Error> {
+    let invalid = |message: String| Error::InvalidAsciiGrid { name: name.into(), message };
+    let mut points: Vec<(f64, f64, f64)> = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values = line.split(|c: char| c.is_ascii_whitespace() || c == ',' || c == ';')
+            .filter(|v| !v.is_empty())
+            .take(3)
+            .map(|v| v.parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>();
+        match values {
+            Ok(v) if v.len() == 3 => points.push((v[0], v[1], v[2])),
+            // A text header is allowed on the first data line only
+            _ if points.is_empty() => continue,
+            _ => return Err(invalid(format!("line {}: expected 3 numbers", i + 1))),
+        }
+    }
+    if points.is_empty() {
+        return Err(invalid("no points found".into()));
+    }
+
+    let xs = unique_sorted(points.iter().map(|p| p.0).collect());
+    let ys = unique_sorted(points.iter().map(|p| p.1).collect());
+    if xs.len() < 2 || ys.len() < 2 {
+        return Err(invalid("at least 2 distinct x and y coordinates are required".into()));
+    }
+    let mut rows: Vec<Vec<Option<f64>>> = vec![vec![None; xs.len()]; ys.len()];
+    for (x, y, h) in points {
+        rows[nearest_index(&ys, y)][nearest_index(&xs, x)] = Some(h);
+    }
+
+    let (raster, y_min, y_max) = normalize_heights(&rows).ok_or_else(|| invalid("grid has no data".into()))?;
+    let extents = Extents {
+        min: na::Vector3::new(xs[0], y_min, ys[0]),
+        max: na::Vector3::new(xs[xs.len() - 1], y_max, ys[ys.len() - 1]),
+    };
+    Ok((raster, nodata_mask(&rows), extents))
+}
+
+// No data cells are left out, read_xyz reads them back as missing
+pub fn write_xyz(data: &[Vec<f32>], nodata: Option<&[Vec<bool>]>, extents: &Extents) -> String {
+    let (nrows, ncols) = (data.len(), data[0].len());
+    let dx = (extents.max.x - extents.min.x) / (ncols - 1) as f64;
+    let dy = (extents.max.z - extents.min.z) / (nrows - 1) as f64;
+
+    let mut out = String::new();
+    for (r, row) in data.iter().enumerate() {
+        for (c, &h) in row.iter().enumerate() {
+            if is_nodata(nodata, r, c) {
+                continue;
+            }
+            let x = extents.min.x + c as f64 * dx;
+            let y = extents.min.z + r as f64 * dy;
+            let _ = writeln!(out, "{} {} {}", x, y, extents.height_to_world(h) as f32);
+        }
+    }
+    out
+}
+
+fn is_nodata(nodata: Option<&[Vec<bool>]>, row: usize, col: usize) -> bool {
+    nodata.is_some_and(|mask| mask[row][col])
+}
+
+// None when every cell has data
+fn nodata_mask(rows: &[Vec<Option<f64>>]) -> Option<Vec<Vec<bool>>> {
+    match rows.iter().flatten().any(|v| v.is_none()) {
+        true => Some(rows.iter().map(|row| row.iter().map(|v| v.is_none()).collect()).collect()),
+        false => None,
+    }
+}
+
+// Maps heights to [0;1], no data cells get the lowest height. Returns None when every cell is empty
+fn normalize_heights(rows: &[Vec<Option<f64>>]) -> Option<(Vec<Vec<f32>>, f64, f64)> {
+    let valid = rows.iter().flatten().flatten();
+    let y_min = valid.clone().copied().fold(f64::MAX, f64::min);
+    let y_max = valid.copied().fold(f64::MIN, f64::max);
+    if y_min > y_max {
+        return None;
+    }
+    let span = y_max - y_min;
+    let raster = rows.iter()
+        .map(|row| row.iter().map(|v| match (v, span > 0.) {
+            (Some(v), true) => ((v - y_min) / span) as f32,
+            _ => 0.,
+        }).collect())
+        .collect();
+    Some((raster, y_min, y_max))
+}
+
+fn unique_sorted(mut values: Vec<f64>) -> Vec<f64> {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let span = (values[values.len() - 1] - values[0]).abs().max(1.);
+    values.dedup_by(|a, b| (*a - *b).abs() <= span * 1e-9);
+    values
+}
+
+fn nearest_index(sorted: &[f64], value: f64) -> usize {
+    match sorted.binary_search_by(|v| v.partial_cmp(&value).unwrap_or(std::cmp::Ordering::Less)) {
+        Ok(i) => i,
+        Err(0) => 0,
+        Err(i) if i >= sorted.len() => sorted.len() - 1,
+        Err(i) if value - sorted[i - 1] < sorted[i] - value => i - 1,
+        Err(i) => i,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_error(header: &str) -> String {
+        let text = format!("{}\n1 2 3\n4 5 6\n", header);
+        match read_esri_ascii(&text, "test.asc") {
+            Ok(_) => panic!("{:?} was accepted", header),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn reads_cell_centres_south_to_north() {
+        let (raster, nodata, extents) = read_esri_ascii(
+            "ncols 3\nnrows 2\nxllcorner 10\nyllcorner 20\ncellsize 2\nNODATA_value -9999\n1 -9999 3\n5 6 9\n",
+            "test.asc").unwrap();
+        assert_eq!(raster, vec![vec![0.5, 0.625, 1.], vec![0., 0., 0.25]]);
+        assert_eq!(nodata, Some(vec![vec![false, false, false], vec![false, true, false]]));
+        assert_eq!(extents.min, na::Vector3::new(11., 1., 21.));
+        assert_eq!(extents.max, na::Vector3::new(15., 9., 23.));
+    }
+
+    #[test]
+    fn rejects_counts_that_are_not_whole_numbers() {
+        assert!(header_error("ncols 3.5\nnrows 2").contains("invalid value for ncols"));
+        assert!(header_error("ncols 3\nnrows -2").contains("invalid value for nrows"));
+        assert!(header_error("ncols 3\nnrows 1e3").contains("invalid value for nrows"));
+    }
+
+    #[test]
+    fn rejects_grids_too_large_to_count() {
+        let huge = format!("ncols {}\nnrows {}", usize::MAX / 2, 3);
+        assert!(header_error(&huge).contains("too large"));
+        let many = format!("ncols {}\nnrows {}", usize::MAX / 4, 3);
+        assert!(header_error(&many).contains("expected"));
+    }
+
+    #[test]
+    fn rejects_cell_sizes_that_are_not_positive() {
+        assert!(header_error("ncols 3\nnrows 2\ncellsize 0").contains("cellsize must be positive"));
+        assert!(header_error("ncols 3\nnrows 2\ndx -1\ndy 1").contains("dx must be positive"));
+        assert!(header_error("ncols 3\nnrows 2\ndx 1\ndy nan").contains("dy must be positive"));
+    }
+}
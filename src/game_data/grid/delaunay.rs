@@ -0,0 +1,575 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+const SUPER_TRIANGLE_SCALE: f64 = 100.;
+const BARYCENTRIC_TOLERANCE: f64 = 1e-9;
+const TRIANGLES_PER_BIN: usize = 2;
+
+#[derive(Copy, Clone)]
+struct Circle {
+    x: f64,
+    z: f64,
+    r2: f64,
+}
+
+impl Circle {
+    fn contains(&self, x: f64, z: f64) -> bool {
+        (x - self.x).powi(2) + (z - self.z).powi(2) < self.r2 * (1. - 1e-12)
+    }
+}
+
+// Delaunay triangulation of the poles on the XZ plane, heights are kept per vertex.
+// Triangles are stored counter-clockwise, neighbours[t][i] is the triangle across the edge opposite to vertex i
+pub struct Triangulation {
+    points: Vec<(f64, f64, f64)>,
+    triangles: Vec<[usize; 3]>,
+    neighbours: Vec<[Option<usize>; 3]>,
+    circles: Vec<Circle>,
+    bins: Vec<Vec<usize>>,
+    bins_count: usize,
+    bbox_min: (f64, f64),
+    bin_size: (f64, f64),
+}
+
+impl Triangulation {
+    pub fn new(poles: &[na::Vector3<f32>]) -> Triangulation {
+        let mut points: Vec<(f64, f64, f64)> = Vec::with_capacity(poles.len());
+        let mut positions: HashSet<(u64, u64)> = HashSet::with_capacity(poles.len());
+        for pole in poles {
+            let point = (pole.x as f64, pole.z as f64, pole.y as f64);
+            // Duplicated positions would create zero area triangles, the first height wins.
+            // Adding zero turns -0 into 0, so both have the same bits
+            if positions.insert(((point.0 + 0.).to_bits(), (point.1 + 0.).to_bits())) {
+                points.push(point);
+            }
+        }
+
+        let triangles = bowyer_watson(&points);
+        let circles = triangles.iter()
+            .map(|t| circumcircle(xz(&points[t[0]]), xz(&points[t[1]]), xz(&points[t[2]])))
+            .collect();
+        let neighbours = find_neighbours(&triangles);
+
+        let mut triangulation = Triangulation {
+            points, triangles, neighbours, circles,
+            bins: vec![], bins_count: 0, bbox_min: (0., 0.), bin_size: (0., 0.),
+        };
+        triangulation.fill_bins();
+        triangulation
+    }
+
+    // Barycentric interpolation inside the triangle containing the point, nearest pole outside of the hull
+    pub fn linear_calculate_point(&self, cur_point: &na::Vector3<f32>) -> f32 {
+        let (x, z) = (cur_point.x as f64, cur_point.z as f64);
+        let y_value = match self.locate(x, z) {
+            Some((triangle, weights)) => self.triangles[triangle].iter().zip(&weights)
+                .map(|(&vertex, weight)| self.points[vertex].2 * weight)
+                .sum(),
+            None => self.nearest_height(x, z),
+        };
+        y_value as f32
+    }
+
+    // Sibson interpolation: weights are the areas the point steals from the Voronoi cells of its natural neighbours
+    pub fn natural_neighbour_calculate_point(&self, cur_point: &na::Vector3<f32>) -> f32 {
+        let (x, z) = (cur_point.x as f64, cur_point.z as f64);
+        let start = match self.locate(x, z) {
+            Some((triangle, _)) => triangle,
+            None => return self.nearest_height(x, z) as f32,
+        };
+
+        match self.sibson_weights(start, (x, z)) {
+            Some(weights) => {
+                let total: f64 = weights.values().sum();
+                let y_value: f64 = weights.iter()
+                    .map(|(&vertex, weight)| self.points[vertex].2 * weight)
+                    .sum();
+                (y_value / total) as f32
+            },
+            // Point lies on a pole or on an edge, where Sibson and linear interpolation coincide
+            None => self.linear_calculate_point(cur_point),
+        }
+    }
+
+    fn sibson_weights(&self, start: usize, p: (f64, f64)) -> Option<BTreeMap<usize, f64>> {
+        let mut cavity: Vec<usize> = vec![start];
+        let mut visited: Vec<usize> = vec![start];
+        let mut i = 0;
+        while i < cavity.len() {
+            for neighbour in self.neighbours[cavity[i]].iter().flatten() {
+                if !visited.contains(neighbour) {
+                    visited.push(*neighbour);
+                    if self.circles[*neighbour].contains(p.0, p.1) {
+                        cavity.push(*neighbour);
+                    }
+                }
+            }
+            i += 1;
+        }
+
+        let mut weights: BTreeMap<usize, f64> = BTreeMap::new();
+        for &triangle in &cavity {
+            let circle = &self.circles[triangle];
+            let center = (circle.x, circle.z);
+            let vertices = self.triangles[triangle];
+            for k in 0..3 {
+                let v = xz(&self.points[vertices[k]]);
+                let next = xz(&self.points[vertices[(k + 1) % 3]]);
+                let prev = xz(&self.points[vertices[(k + 2) % 3]]);
+                let u_next = circumcenter(p, v, next)?;
+                let u_prev = circumcenter(p, prev, v)?;
+                *weights.entry(vertices[k]).or_insert(0.) += signed_area(center, u_next, u_prev);
+            }
+        }
+
+        let total: f64 = weights.values().sum();
+        match total.is_finite() && total.abs() > f64::EPSILON {
+            true => Some(weights),
+            false => None,
+        }
+    }
+
+    fn locate(&self, x: f64, z: f64) -> Option<(usize, [f64; 3])> {
+        let bin_x = ((x - self.bbox_min.0) / self.bin_size.0).floor();
+        let bin_z = ((z - self.bbox_min.1) / self.bin_size.1).floor();
+        if self.bins_count == 0 || bin_x < 0. || bin_z < 0. {
+            return None;
+        }
+        // Points lying exactly on the max side of the bounding box belong to the last bin
+        let bin_x = (bin_x as usize).min(self.bins_count - 1);
+        let bin_z = (bin_z as usize).min(self.bins_count - 1);
+
+        self.bins[bin_z * self.bins_count + bin_x].iter()
+            .map(|&triangle| (triangle, self.barycentric(triangle, (x, z))))
+            .find(|(_, weights)| weights.iter().all(|w| *w >= -BARYCENTRIC_TOLERANCE))
+    }
+
+    fn barycentric(&self, triangle: usize, p: (f64, f64)) -> [f64; 3] {
+        let [a, b, c] = self.triangles[triangle];
+        let (a, b, c) = (xz(&self.points[a]), xz(&self.points[b]), xz(&self.points[c]));
+        let area = signed_area(a, b, c);
+        [signed_area(p, b, c) / area, signed_area(a, p, c) / area, signed_area(a, b, p) / area]
+    }
+
+    fn nearest_height(&self, x: f64, z: f64) -> f64 {
+        self.points.iter()
+            .map(|p| ((p.0 - x).powi(2) + (p.1 - z).powi(2), p.2))
+            .fold((f64::MAX, 0.), |best, cur| if cur.0 < best.0 { cur } else { best })
+            .1
+    }
+
+    // Uniform bins over the bounding box, every triangle is registered in all bins its bounding box touches
+    fn fill_bins(&mut self) {
+        if self.triangles.is_empty() {
+            return;
+        }
+        let min_x = self.points.iter().map(|p| p.0).fold(f64::MAX, f64::min);
+        let max_x = self.points.iter().map(|p| p.0).fold(f64::MIN, f64::max);
+        let min_z = self.points.iter().map(|p| p.1).fold(f64::MAX, f64::min);
+        let max_z = self.points.iter().map(|p| p.1).fold(f64::MIN, f64::max);
+
+        self.bins_count = ((self.triangles.len() / TRIANGLES_PER_BIN) as f64).sqrt().ceil().max(1.) as usize;
+        self.bbox_min = (min_x, min_z);
+        self.bin_size = ((max_x - min_x) / self.bins_count as f64, (max_z - min_z) / self.bins_count as f64);
+        self.bins = vec![vec![]; self.bins_count * self.bins_count];
+
+        let bins_count = self.bins_count;
+        let to_bin = |value: f64, min: f64, size: f64| -> usize {
+            (((value - min) / size).floor().max(0.) as usize).min(bins_count - 1)
+        };
+        for (i, triangle) in self.triangles.iter().enumerate() {
+            let xs = triangle.iter().map(|&v| self.points[v].0);
+            let zs = triangle.iter().map(|&v| self.points[v].1);
+            let (t_min_x, t_max_x) = xs.fold((f64::MAX, f64::MIN), |acc, v| (acc.0.min(v), acc.1.max(v)));
+            let (t_min_z, t_max_z) = zs.fold((f64::MAX, f64::MIN), |acc, v| (acc.0.min(v), acc.1.max(v)));
+            for bin_z in to_bin(t_min_z, min_z, self.bin_size.1)..=to_bin(t_max_z, min_z, self.bin_size.1) {
+                for bin_x in to_bin(t_min_x, min_x, self.bin_size.0)..=to_bin(t_max_x, min_x, self.bin_size.0) {
+                    self.bins[bin_z * self.bins_count + bin_x].push(i);
+                }
+            }
+        }
+    }
+}
+
+fn xz(point: &(f64, f64, f64)) -> (f64, f64) {
+    (point.0, point.1)
+}
+
+fn signed_area(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> f64 {
+    0.5 * ((b.0 - a.0) * (c.1 - a.1) - (c.0 - a.0) * (b.1 - a.1))
+}
+
+fn circumcenter(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> Option<(f64, f64)> {
+    let d = 2. * (a.0 * (b.1 - c.1) + b.0 * (c.1 - a.1) + c.0 * (a.1 - b.1));
+    if d.abs() < 1e-14 {
+        return None;
+    }
+    let (a2, b2, c2) = (a.0 * a.0 + a.1 * a.1, b.0 * b.0 + b.1 * b.1, c.0 * c.0 + c.1 * c.1);
+    let x = (a2 * (b.1 - c.1) + b2 * (c.1 - a.1) + c2 * (a.1 - b.1)) / d;
+    let z = (a2 * (c.0 - b.0) + b2 * (a.0 - c.0) + c2 * (b.0 - a.0)) / d;
+    Some((x, z))
+}
+
+fn circumcircle(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> Circle {
+    match circumcenter(a, b, c) {
+        Some((x, z)) => Circle { x, z, r2: (a.0 - x).powi(2) + (a.1 - z).powi(2) },
+        None => Circle { x: 0., z: 0., r2: f64::MAX },
+    }
+}
+
+// Incremental Bowyer-Watson inside a super triangle, triangles touching it are dropped at the end.
+// Neighbours are kept up to date, so a new point walks to its triangle and the cavity grows from there
+fn bowyer_watson(points: &[(f64, f64, f64)]) -> Vec<[usize; 3]> {
+    if points.len() < 3 {
+        return vec![];
+    }
+    let n = points.len();
+    let min_x = points.iter().map(|p| p.0).fold(f64::MAX, f64::min);
+    let max_x = points.iter().map(|p| p.0).fold(f64::MIN, f64::max);
+    let min_z = points.iter().map(|p| p.1).fold(f64::MAX, f64::min);
+    let max_z = points.iter().map(|p| p.1).fold(f64::MIN, f64::max);
+    let center = ((min_x + max_x) / 2., (min_z + max_z) / 2.);
+    let extent = (max_x - min_x).max(max_z - min_z).max(f64::EPSILON) * SUPER_TRIANGLE_SCALE;
+
+    let mut vertices: Vec<(f64, f64)> = points.iter().map(xz).collect();
+    vertices.push((center.0 - extent, center.1 - extent));
+    vertices.push((center.0 + extent, center.1 - extent));
+    vertices.push((center.0, center.1 + extent));
+
+    let mut triangles: Vec<[usize; 3]> = vec![[n, n + 1, n + 2]];
+    let mut circles: Vec<Circle> = vec![circumcircle(vertices[n], vertices[n + 1], vertices[n + 2])];
+    let mut neighbours: Vec<[Option<usize>; 3]> = vec![[None; 3]];
+    let mut in_cavity: Vec<bool> = vec![false];
+    let mut last = 0;
+
+    for (i, &point) in vertices[..n].iter().enumerate() {
+        let start = walk(&vertices, &triangles, &neighbours, last, point)
+            .or_else(|| (0..triangles.len()).find(|&t| circles[t].contains(point.0, point.1)));
+        let start = match start {
+            Some(start) => start,
+            None => continue,
+        };
+
+        let mut cavity: Vec<usize> = vec![start];
+        in_cavity[start] = true;
+        let mut c = 0;
+        while c < cavity.len() {
+            for neighbour in neighbours[cavity[c]].iter().flatten() {
+                if !in_cavity[*neighbour] && circles[*neighbour].contains(point.0, point.1) {
+                    in_cavity[*neighbour] = true;
+                    cavity.push(*neighbour);
+                }
+            }
+            c += 1;
+        }
+
+        // Every cavity boundary edge makes a triangle with the new point, the cavity slots are reused first
+        let mut boundary: Vec<(usize, usize, Option<usize>)> = vec![];
+        for &t in &cavity {
+            for k in 0..3 {
+                let outside = neighbours[t][k].filter(|&u| !in_cavity[u]);
+                if neighbours[t][k].is_none() || outside.is_some() {
+                    boundary.push((triangles[t][(k + 1) % 3], triangles[t][(k + 2) % 3], outside));
+                }
+            }
+        }
+        cavity.iter().for_each(|&t| in_cavity[t] = false);
+
+        let mut starting_at: HashMap<usize, usize> = HashMap::with_capacity(boundary.len());
+        for (b, &(first, second, outside)) in boundary.iter().enumerate() {
+            let t = match cavity.get(b) {
+                Some(&t) => t,
+                None => {
+                    triangles.push([0; 3]);
+                    circles.push(Circle { x: 0., z: 0., r2: 0. });
+                    neighbours.push([None; 3]);
+                    in_cavity.push(false);
+                    triangles.len() - 1
+                },
+            };
+            triangles[t] = [first, second, i];
+            circles[t] = circumcircle(vertices[first], vertices[second], point);
+            neighbours[t] = [None, None, outside];
+            if let Some(u) = outside {
+                let l = (0..3).find(|&l| triangles[u][l] != first && triangles[u][l] != second).unwrap_or(0);
+                neighbours[u][l] = Some(t);
+            }
+            starting_at.insert(first, t);
+            last = t;
+        }
+        // Triangles around the new point are linked through the edge to it they share
+        for &t in starting_at.values() {
+            if let Some(&next) = starting_at.get(&triangles[t][1]) {
+                neighbours[t][0] = Some(next);
+                neighbours[next][1] = Some(t);
+            }
+        }
+    }
+
+    let mut triangles: Vec<[usize; 3]> = triangles.into_iter()
+        .filter(|triangle| triangle.iter().all(|&v| v < n))
+        .filter(|triangle| signed_area(vertices[triangle[0]], vertices[triangle[1]], vertices[triangle[2]]) > 0.)
+        .collect();
+    let ears = close_hull(&vertices, &mut triangles);
+    legalize(&vertices, &mut triangles, &ears);
+    triangles
+}
+
+// Steps towards the point across the edges it lies behind. None when the walk does not settle,
+// which only happens on degenerate input
+fn walk(vertices: &[(f64, f64)], triangles: &[[usize; 3]], neighbours: &[[Option<usize>; 3]],
+        start: usize, point: (f64, f64)) -> Option<usize> {
+    let mut t = start;
+    for _ in 0..triangles.len() {
+        let behind = (0..3).find(|&k| {
+            let (a, b) = (triangles[t][(k + 1) % 3], triangles[t][(k + 2) % 3]);
+            signed_area(vertices[a], vertices[b], point) < 0.
+        });
+        match behind {
+            Some(k) => t = neighbours[t][k]?,
+            None => return Some(t),
+        }
+    }
+    None
+}
+
+// Dropping the super triangle leaves the hull concave where boundary points are (almost) collinear,
+// so boundary pockets are filled with ears until the hull is convex. Returns the indices of the ears
+fn close_hull(vertices: &[(f64, f64)], triangles: &mut Vec<[usize; 3]>) -> Vec<usize> {
+    let edges: HashSet<(usize, usize)> = triangles.iter()
+        .flat_map(|t| (0..3).map(move |k| (t[k], t[(k + 1) % 3])))
+        .collect();
+    // The hull goes counter-clockwise along the edges without a twin
+    let mut next: HashMap<usize, usize> = edges.iter()
+        .filter(|(a, b)| !edges.contains(&(*b, *a)))
+        .copied()
+        .collect();
+    let mut previous: HashMap<usize, usize> = next.iter().map(|(&a, &b)| (b, a)).collect();
+
+    let mut ears: Vec<usize> = vec![];
+    let mut candidates: Vec<usize> = next.keys().copied().collect();
+    while let Some(b) = candidates.pop() {
+        let (a, c) = match (previous.get(&b), next.get(&b)) {
+            (Some(&a), Some(&c)) if a != c => (a, c),
+            _ => continue,
+        };
+        if signed_area(vertices[a], vertices[b], vertices[c]) < -f64::EPSILON {
+            ears.push(triangles.len());
+            triangles.push([a, c, b]);
+            next.remove(&b);
+            previous.remove(&b);
+            next.insert(a, c);
+            previous.insert(c, a);
+            candidates.push(a);
+            candidates.push(c);
+        }
+    }
+    ears
+}
+
+// Lawson flips of the edges that violate the empty circumcircle property. The rest of the
+// triangulation is Delaunay already, so only the edges of the given triangles are checked at first,
+// then the outer edges of every flipped pair
+fn legalize(vertices: &[(f64, f64)], triangles: &mut [[usize; 3]], seeds: &[usize]) {
+    // Directed edge to the triangle it belongs to and the vertex opposite to it
+    let mut edges: HashMap<(usize, usize), (usize, usize)> = HashMap::with_capacity(triangles.len() * 3);
+    for (t, triangle) in triangles.iter().enumerate() {
+        for k in 0..3 {
+            edges.insert((triangle[(k + 1) % 3], triangle[(k + 2) % 3]), (t, k));
+        }
+    }
+
+    let mut stack: Vec<(usize, usize)> = seeds.iter()
+        .flat_map(|&t| (0..3).map(move |k| (t, k)))
+        .map(|(t, k)| (triangles[t][(k + 1) % 3], triangles[t][(k + 2) % 3]))
+        .collect();
+    let mut flips_left = triangles.len() * 3;
+    while let Some((a, b)) = stack.pop() {
+        let ((t, k), (u, l)) = match (edges.get(&(a, b)), edges.get(&(b, a))) {
+            (Some(&first), Some(&second)) => (first, second),
+            _ => continue,
+        };
+        let (c, d) = (triangles[t][k], triangles[u][l]);
+        let is_convex = signed_area(vertices[c], vertices[a], vertices[d]) > 0.
+            && signed_area(vertices[d], vertices[b], vertices[c]) > 0.;
+        let circle = circumcircle(vertices[a], vertices[b], vertices[c]);
+        if !is_convex || !circle.contains(vertices[d].0, vertices[d].1) || flips_left == 0 {
+            continue;
+        }
+        flips_left -= 1;
+
+        for &flipped in &[t, u] {
+            for k in 0..3 {
+                edges.remove(&(triangles[flipped][(k + 1) % 3], triangles[flipped][(k + 2) % 3]));
+            }
+        }
+        triangles[t] = [c, a, d];
+        triangles[u] = [d, b, c];
+        for &flipped in &[t, u] {
+            for k in 0..3 {
+                edges.insert((triangles[flipped][(k + 1) % 3], triangles[flipped][(k + 2) % 3]), (flipped, k));
+            }
+        }
+        stack.extend_from_slice(&[(c, a), (a, d), (d, b), (b, c)]);
+    }
+}
+
+fn find_neighbours(triangles: &[[usize; 3]]) -> Vec<[Option<usize>; 3]> {
+    let mut edges: HashMap<(usize, usize), (usize, usize)> = HashMap::with_capacity(triangles.len() * 3);
+    let mut neighbours: Vec<[Option<usize>; 3]> = vec![[None; 3]; triangles.len()];
+    for (t, triangle) in triangles.iter().enumerate() {
+        for k in 0..3 {
+            let (a, b) = (triangle[(k + 1) % 3], triangle[(k + 2) % 3]);
+            match edges.remove(&(b, a)) {
+                Some((other, other_k)) => {
+                    neighbours[t][k] = Some(other);
+                    neighbours[other][other_k] = Some(t);
+                },
+                None => { edges.insert((a, b), (t, k)); },
+            }
+        }
+    }
+    neighbours
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poles(points: &[(f32, f32, f32)]) -> Vec<na::Vector3<f32>> {
+        points.iter().map(|&(x, y, z)| na::Vector3::new(x, y, z)).collect()
+    }
+
+    // Square corners and count scattered points inside, heights from height(x, z)
+    fn scattered(count: usize, height: impl Fn(f32, f32) -> f32) -> Vec<na::Vector3<f32>> {
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        let mut random = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 40) as f32 / (1u64 << 24) as f32 * 2. - 1.
+        };
+        let mut points: Vec<(f32, f32)> = vec![(-1., -1.), (1., -1.), (1., 1.), (-1., 1.)];
+        points.extend((0..count).map(|_| (random(), random())));
+        points.into_iter().map(|(x, z)| na::Vector3::new(x, height(x, z), z)).collect()
+    }
+
+    fn plane(x: f32, z: f32) -> f32 {
+        0.3 + 0.2 * x - 0.1 * z
+    }
+
+    fn area(triangulation: &Triangulation, triangle: &[usize; 3]) -> f64 {
+        let [a, b, c] = *triangle;
+        let points = &triangulation.points;
+        signed_area(xz(&points[a]), xz(&points[b]), xz(&points[c]))
+    }
+
+    fn assert_delaunay(triangulation: &Triangulation, hull_area: f64) {
+        let mut total = 0.;
+        for triangle in &triangulation.triangles {
+            let [a, b, c] = *triangle;
+            let points = &triangulation.points;
+            let circle = circumcircle(xz(&points[a]), xz(&points[b]), xz(&points[c]));
+            for point in points {
+                let distance = (point.0 - circle.x).powi(2) + (point.1 - circle.z).powi(2);
+                assert!(distance >= circle.r2 * (1. - 1e-9), "{:?} is inside the circumcircle of {:?}", point, triangle);
+            }
+            assert!(area(triangulation, triangle) > 0.);
+            total += area(triangulation, triangle);
+        }
+        assert!((total - hull_area).abs() < 1e-6, "triangles cover {} of {}", total, hull_area);
+    }
+
+    #[test]
+    fn scattered_poles_are_triangulated_over_the_hull() {
+        assert_delaunay(&Triangulation::new(&scattered(200, plane)), 4.);
+    }
+
+    // Collinear points along the hull and cocircular ones everywhere
+    #[test]
+    fn regular_poles_are_triangulated_over_the_hull() {
+        let grid: Vec<na::Vector3<f32>> = (0..8)
+            .flat_map(|z| (0..11).map(move |x| na::Vector3::new(x as f32 * 0.2 - 1., 0., z as f32 * 0.2 - 1.)))
+            .collect();
+        let triangulation = Triangulation::new(&grid);
+        assert_eq!(triangulation.triangles.len(), 10 * 7 * 2);
+        assert_delaunay(&triangulation, 2. * 1.4);
+    }
+
+    #[test]
+    fn linear_reproduces_a_plane() {
+        let triangulation = Triangulation::new(&scattered(50, plane));
+        for i in 0..=20 {
+            for j in 0..=20 {
+                let (x, z) = (i as f32 * 0.1 - 1., j as f32 * 0.1 - 1.);
+                let point = na::Vector3::new(x, 0., z);
+                assert!((triangulation.linear_calculate_point(&point) - plane(x, z)).abs() < 1e-5);
+                assert!((triangulation.natural_neighbour_calculate_point(&point) - plane(x, z)).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn poles_keep_their_heights() {
+        let poles = scattered(80, |x, z| (x * 3.).sin() * (z * 2.).cos());
+        let triangulation = Triangulation::new(&poles);
+        for pole in &poles {
+            assert!((triangulation.linear_calculate_point(pole) - pole.y).abs() < 1e-6);
+            assert!((triangulation.natural_neighbour_calculate_point(pole) - pole.y).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn natural_neighbour_weights_sum_to_one() {
+        let triangulation = Triangulation::new(&scattered(60, plane));
+        for &(x, z) in &[(0.13, -0.42), (-0.77, 0.05), (0.5, 0.5), (0.91, -0.93)] {
+            let (start, _) = triangulation.locate(x, z).unwrap();
+            let weights = triangulation.sibson_weights(start, (x, z)).unwrap();
+            let total: f64 = weights.values().sum();
+            let weights: Vec<(usize, f64)> = weights.into_iter().map(|(vertex, weight)| (vertex, weight / total)).collect();
+
+            assert!(weights.iter().all(|(_, weight)| *weight >= -1e-12));
+            assert!((weights.iter().map(|(_, weight)| weight).sum::<f64>() - 1.).abs() < 1e-12);
+            // Sibson coordinates reproduce the position they are taken at
+            let position = weights.iter().fold((0., 0.), |acc, &(vertex, weight)| {
+                let point = triangulation.points[vertex];
+                (acc.0 + point.0 * weight, acc.1 + point.1 * weight)
+            });
+            assert!((position.0 - x).abs() < 1e-9 && (position.1 - z).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn duplicate_poles_keep_the_first_height() {
+        let triangulation = Triangulation::new(&poles(&[
+            (-1., 0.1, -1.), (1., 0.2, -1.), (0., 0.3, 1.), (1., 0.9, -1.), (-1., 0.8, -1.), (0., 0.7, 1.),
+            (0., 0.4, 0.), (-0., 0.6, -0.),
+        ]));
+        assert_eq!(triangulation.points.len(), 4);
+        assert_eq!(triangulation.triangles.len(), 3);
+        assert!(triangulation.triangles.iter().all(|triangle| area(&triangulation, triangle) > 0.));
+        for &(x, y, z) in &[(1., 0.2, -1.), (0., 0.4, 0.)] {
+            let point = na::Vector3::new(x, 0., z);
+            assert!((triangulation.linear_calculate_point(&point) - y).abs() < 1e-6);
+            assert!((triangulation.natural_neighbour_calculate_point(&point) - y).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn collinear_poles_use_the_nearest_pole() {
+        let triangulation = Triangulation::new(&poles(&[(-1., 0.1, -1.), (0., 0.5, 0.), (1., 0.9, 1.), (0.5, 0.7, 0.5)]));
+        assert!(triangulation.triangles.is_empty());
+        let point = na::Vector3::new(0.6, 0., 0.3);
+        assert_eq!(triangulation.linear_calculate_point(&point), 0.7);
+        assert_eq!(triangulation.natural_neighbour_calculate_point(&point), 0.7);
+    }
+
+    #[test]
+    fn fewer_than_three_poles_use_the_nearest_pole() {
+        let point = na::Vector3::new(0.4, 0., 0.);
+        let none = Triangulation::new(&[]);
+        assert_eq!(none.linear_calculate_point(&point), 0.);
+        let one = Triangulation::new(&poles(&[(-1., 0.3, 0.)]));
+        assert_eq!(one.natural_neighbour_calculate_point(&point), 0.3);
+        let two = Triangulation::new(&poles(&[(-1., 0.3, 0.), (1., 0.6, 0.)]));
+        assert_eq!(two.linear_calculate_point(&point), 0.6);
+        assert_eq!(two.natural_neighbour_calculate_point(&point), 0.6);
+    }
+}
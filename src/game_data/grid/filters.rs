@@ -0,0 +1,131 @@
+use std::str::FromStr;
+use super::{MIN_HEIGHT, MAX_HEIGHT};
+
+const DEFAULT_GAUSSIAN_SIGMA: f32 = 1.;
+const DEFAULT_MEDIAN_RADIUS: usize = 1;
+const DEFAULT_TERRACE_LEVELS: usize = 8;
+
+// Post-processing of the sampled terrain, sizes are in grid cells
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub enum Filter {
+    Gaussian(f32),
+    Median(usize),
+    Terrace(usize),
+    Scale(f32, f32),
+    SeaFloor(f32),
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    // gaussian[:sigma], median[:radius], terrace[:levels], scale:<factor>[:<offset>], floor:<height>
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let name = parts.next().unwrap_or("");
+        let values = parts
+            .map(|v| v.parse::<f32>().map_err(|_| format!("invalid filter value {}", v)))
+            .collect::<Result<Vec<f32>, String>>()?;
+        let count = |value: f32| match value {
+            v if v >= 1. && v.fract() == 0. => Ok(v as usize),
+            _ => Err(format!("filter {} needs a positive integer", name)),
+        };
+        match (name, values.as_slice()) {
+            ("gaussian", []) => Ok(Filter::Gaussian(DEFAULT_GAUSSIAN_SIGMA)),
+            ("gaussian", [sigma]) if *sigma > 0. => Ok(Filter::Gaussian(*sigma)),
+            ("median", []) => Ok(Filter::Median(DEFAULT_MEDIAN_RADIUS)),
+            ("median", [radius]) => Ok(Filter::Median(count(*radius)?)),
+            ("terrace", []) => Ok(Filter::Terrace(DEFAULT_TERRACE_LEVELS)),
+            ("terrace", [levels]) => Ok(Filter::Terrace(count(*levels)?)),
+            ("scale", [factor]) => Ok(Filter::Scale(*factor, 0.)),
+            ("scale", [factor, offset]) => Ok(Filter::Scale(*factor, *offset)),
+            ("floor", [height]) => Ok(Filter::SeaFloor(*height)),
+            _ => Err(format!("invalid filter {}", s)),
+        }
+    }
+}
+
+// Applies the chain in order, the result is kept in [MIN_HEIGHT;MAX_HEIGHT]
+pub fn apply_filters(data: &[Vec<f32>], filters: &[Filter]) -> Vec<Vec<f32>> {
+    let mut data = data.to_vec();
+    for filter in filters {
+        data = match *filter {
+            Filter::Gaussian(sigma) => gaussian(&data, sigma),
+            Filter::Median(radius) => median(&data, radius),
+            Filter::Terrace(levels) => map_heights(data, |h| terrace(h, levels)),
+            Filter::Scale(factor, offset) => map_heights(data, |h| h * factor + offset),
+            Filter::SeaFloor(floor) => map_heights(data, |h| h.max(floor)),
+        };
+    }
+    map_heights(data, |h| h.clamp(MIN_HEIGHT, MAX_HEIGHT))
+}
+
+// [MIN_HEIGHT;MAX_HEIGHT] is cut into equal steps, each one flattened to a level.
+// Levels are spread evenly from MIN_HEIGHT for the first step to MAX_HEIGHT for the last one
+fn terrace(height: f32, levels: usize) -> f32 {
+    let span = MAX_HEIGHT - MIN_HEIGHT;
+    let step = ((height - MIN_HEIGHT) / span * levels as f32).floor().clamp(0., levels as f32 - 1.);
+    MIN_HEIGHT + step / (levels as f32 - 1.).max(1.) * span
+}
+
+fn map_heights<F: Fn(f32) -> f32>(mut data: Vec<Vec<f32>>, f: F) -> Vec<Vec<f32>> {
+    for height in data.iter_mut().flatten() {
+        *height = f(*height);
+    }
+    data
+}
+
+// Separable blur, cells beyond the border repeat the edge
+fn gaussian(data: &[Vec<f32>], sigma: f32) -> Vec<Vec<f32>> {
+    let radius = (sigma * 3.).ceil() as i64;
+    let kernel: Vec<f32> = (-radius..=radius).map(|i| (-(i * i) as f32 / (2. * sigma * sigma)).exp()).collect();
+    let kernel_sum: f32 = kernel.iter().sum();
+    let (rows, cols) = (data.len() as i64, data[0].len() as i64);
+
+    let horizontal: Vec<Vec<f32>> = data.iter().map(|row| {
+        (0..cols).map(|col| {
+            kernel.iter().enumerate()
+                .map(|(k, w)| w * row[(col + k as i64 - radius).clamp(0, cols - 1) as usize])
+                .sum::<f32>() / kernel_sum
+        }).collect()
+    }).collect();
+    (0..rows).map(|row| {
+        (0..cols as usize).map(|col| {
+            kernel.iter().enumerate()
+                .map(|(k, w)| w * horizontal[(row + k as i64 - radius).clamp(0, rows - 1) as usize][col])
+                .sum::<f32>() / kernel_sum
+        }).collect()
+    }).collect()
+}
+
+// Median of the (2 * radius + 1)^2 window, removes single cell spikes and pits
+fn median(data: &[Vec<f32>], radius: usize) -> Vec<Vec<f32>> {
+    let (rows, cols) = (data.len(), data[0].len());
+    let mut window: Vec<f32> = Vec::with_capacity((2 * radius + 1).pow(2));
+    (0..rows).map(|row| {
+        (0..cols).map(|col| {
+            window.clear();
+            for window_row in &data[row.saturating_sub(radius)..(row + radius + 1).min(rows)] {
+                window.extend_from_slice(&window_row[col.saturating_sub(radius)..(col + radius + 1).min(cols)]);
+            }
+            let middle = window.len() / 2;
+            *window.select_nth_unstable_by(middle, |a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal)).1
+        }).collect()
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn terraces_cover_the_whole_height_range() {
+        let heights = [-1., -0.7, -0.59, -0.21, -0.19, 0.19, 0.21, 0.59, 0.61, 1.];
+        let terraced: Vec<f32> = heights.iter().map(|&h| terrace(h, 5)).collect();
+        assert_eq!(terraced, vec![-1., -1., -0.5, -0.5, 0., 0., 0.5, 0.5, 1., 1.]);
+        assert_eq!(terrace(-1., 8), MIN_HEIGHT);
+        assert_eq!(terrace(1., 8), MAX_HEIGHT);
+        assert_eq!(terrace(0.3, 1), MIN_HEIGHT);
+    }
+}
@@ -0,0 +1,308 @@
+extern crate flate2;
+
+use std::io::Read;
+use self::flate2::read::ZlibDecoder;
+use super::Error;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+// Larger sides are rejected before anything is allocated for them
+const MAX_IMAGE_SIDE: usize = 1 << 14;
+
+// Decodes a grayscale PGM (P2/P5) or PNG image into rows of heights in [0;1]
+pub fn decode_heightmap(bytes: &[u8], name: &str) -> Result<Vec<Vec<f32>>, Error> {
+    let invalid = |message: &str| Error::InvalidHeightmap { name: name.into(), message: message.into() };
+    match bytes {
+        [b'P', b'2', ..] | [b'P', b'5', ..] => decode_pgm(bytes).map_err(invalid),
+        _ if bytes.starts_with(&PNG_SIGNATURE) => decode_png(bytes).map_err(invalid),
+        _ => Err(invalid("only PGM and PNG images are supported")),
+    }
+}
+
+fn decode_pgm(bytes: &[u8]) -> Result<Vec<Vec<f32>>, &'static str> {
+    // Header is 4 whitespace separated tokens (magic, width, height, maxval), '#' starts a comment
+    let mut tokens: Vec<String> = vec![];
+    let mut pos = 0;
+    while tokens.len() < 4 {
+        match bytes.get(pos) {
+            None => return Err("truncated header"),
+            Some(b'#') => {
+                while pos < bytes.len() && bytes[pos] != b'\n' {
+                    pos += 1;
+                }
+            },
+            Some(c) if c.is_ascii_whitespace() => pos += 1,
+            Some(_) => {
+                let start = pos;
+                while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() {
+                    pos += 1;
+                }
+                tokens.push(String::from_utf8_lossy(&bytes[start..pos]).into_owned());
+            },
+        }
+    }
+    let parse = |token: &String| token.parse::<usize>().map_err(|_| "invalid header value");
+    let (width, height, max_value) = (parse(&tokens[1])?, parse(&tokens[2])?, parse(&tokens[3])?);
+    if width == 0 || height == 0 || max_value == 0 || max_value > 65535 {
+        return Err("invalid image size or max value");
+    }
+    if width > MAX_IMAGE_SIDE || height > MAX_IMAGE_SIDE {
+        return Err("image too large");
+    }
+
+    let samples: Vec<u32> = match tokens[0].as_str() {
+        "P5" => {
+            // Exactly one whitespace separates the header from the raster
+            let raster = bytes.get(pos + 1..).ok_or("truncated raster")?;
+            match max_value {
+                v if v < 256 => raster.iter().map(|&b| b as u32).collect(),
+                _ => raster.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as u32).collect(),
+            }
+        },
+        _ => String::from_utf8_lossy(&bytes[pos..])
+            .split_ascii_whitespace()
+            .map(|sample| sample.parse::<u32>().map_err(|_| "invalid sample"))
+            .collect::<Result<Vec<u32>, &str>>()?,
+    };
+    if samples.len() < width * height {
+        return Err("truncated raster");
+    }
+
+    Ok(samples[..width * height].chunks(width)
+        .map(|row| row.iter().map(|&v| v as f32 / max_value as f32).collect())
+        .collect())
+}
+
+fn decode_png(bytes: &[u8]) -> Result<Vec<Vec<f32>>, &'static str> {
+    let mut pos = PNG_SIGNATURE.len();
+    let mut header: Option<&[u8]> = None;
+    let mut compressed: Vec<u8> = vec![];
+    while pos + 8 <= bytes.len() {
+        let length = u32::from_be_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]]) as usize;
+        let kind = &bytes[pos + 4..pos + 8];
+        let data = bytes.get(pos + 8..pos + 8 + length).ok_or("truncated chunk")?;
+        match kind {
+            b"IHDR" => header = Some(data),
+            b"IDAT" => compressed.extend_from_slice(data),
+            b"IEND" => break,
+            _ => (),
+        }
+        pos += length + 12;  // length, type, data, crc
+    }
+
+    let header = header.filter(|h| h.len() >= 13).ok_or("missing IHDR chunk")?;
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let (bit_depth, color_type, interlace) = (header[8] as usize, header[9], header[12]);
+    if width == 0 || height == 0 {
+        return Err("empty image");
+    }
+    if bit_depth != 8 && bit_depth != 16 {
+        return Err("only 8 and 16 bit images are supported");
+    }
+    if interlace != 0 {
+        return Err("interlaced images are not supported");
+    }
+    // Gray, RGB, gray + alpha, RGBA. Alpha is ignored, RGB is averaged
+    let (channels, color_channels) = match color_type {
+        0 => (1, 1),
+        2 => (3, 3),
+        4 => (2, 1),
+        6 => (4, 3),
+        _ => return Err("palette images are not supported"),
+    };
+
+    // Every scanline starts with its filter type byte
+    let bytes_per_sample = bit_depth / 8;
+    let bpp = channels * bytes_per_sample;
+    let stride = width.checked_mul(bpp).filter(|_| width <= MAX_IMAGE_SIDE && height <= MAX_IMAGE_SIDE);
+    let (stride, size) = match stride.and_then(|stride| Some((stride, height.checked_mul(stride + 1)?))) {
+        Some(sizes) => sizes,
+        None => return Err("image too large"),
+    };
+
+    let mut raw: Vec<u8> = Vec::with_capacity(size);
+    ZlibDecoder::new(&compressed[..]).take(size as u64).read_to_end(&mut raw).map_err(|_| "corrupted image data")?;
+    if raw.len() < size {
+        return Err("truncated image data");
+    }
+
+    let max_value = ((1u32 << bit_depth) - 1) as f32;
+    let mut prev: Vec<u8> = vec![0; stride];
+    let mut heights: Vec<Vec<f32>> = Vec::with_capacity(height);
+    for line in raw.chunks_exact(stride + 1).take(height) {
+        let mut cur: Vec<u8> = line[1..].to_vec();
+        unfilter_scanline(line[0], &mut cur, &prev, bpp)?;
+        heights.push(cur.chunks_exact(bpp)
+            .map(|pixel| {
+                let sum: u32 = pixel.chunks_exact(bytes_per_sample).take(color_channels)
+                    .map(|sample| match bytes_per_sample {
+                        1 => sample[0] as u32,
+                        _ => u16::from_be_bytes([sample[0], sample[1]]) as u32,
+                    })
+                    .sum();
+                sum as f32 / color_channels as f32 / max_value
+            })
+            .collect());
+        prev = cur;
+    }
+    Ok(heights)
+}
+
+fn unfilter_scanline(filter: u8, cur: &mut [u8], prev: &[u8], bpp: usize) -> Result<(), &'static str> {
+    for i in 0..cur.len() {
+        let left = if i >= bpp { cur[i - bpp] } else { 0 };
+        let up = prev[i];
+        let up_left = if i >= bpp { prev[i - bpp] } else { 0 };
+        let predictor = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((left as u16 + up as u16) / 2) as u8,
+            4 => paeth(left, up, up_left),
+            _ => return Err("unknown scanline filter"),
+        };
+        cur[i] = cur[i].wrapping_add(predictor);
+    }
+    Ok(())
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    }
+    else if pb <= pc {
+        b
+    }
+    else {
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use self::flate2::write::ZlibEncoder;
+    use self::flate2::Compression;
+
+    fn chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&[0; 4]);  // crc is not checked
+        chunk
+    }
+
+    // PNG of already filtered scanlines
+    fn png(width: u32, height: u32, bit_depth: u8, color_type: u8, interlace: u8, scanlines: &[u8]) -> Vec<u8> {
+        let mut header = width.to_be_bytes().to_vec();
+        header.extend_from_slice(&height.to_be_bytes());
+        header.extend_from_slice(&[bit_depth, color_type, 0, 0, interlace]);
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(scanlines).unwrap();
+
+        let mut bytes = PNG_SIGNATURE.to_vec();
+        bytes.extend(chunk(b"IHDR", &header));
+        bytes.extend(chunk(b"IDAT", &encoder.finish().unwrap()));
+        bytes.extend(chunk(b"IEND", &[]));
+        bytes
+    }
+
+    // Filtered scanline with its filter type byte, the reverse of unfilter_scanline
+    fn filter_scanline(filter: u8, cur: &[u8], prev: &[u8], bpp: usize) -> Vec<u8> {
+        let mut line = vec![filter];
+        for i in 0..cur.len() {
+            let left = if i >= bpp { cur[i - bpp] } else { 0 };
+            let up_left = if i >= bpp { prev[i - bpp] } else { 0 };
+            let predictor = match filter {
+                0 => 0,
+                1 => left,
+                2 => prev[i],
+                3 => ((left as u16 + prev[i] as u16) / 2) as u8,
+                _ => paeth(left, prev[i], up_left),
+            };
+            line.push(cur[i].wrapping_sub(predictor));
+        }
+        line
+    }
+
+    fn gray8(samples: &[&[u8]]) -> Vec<Vec<f32>> {
+        samples.iter().map(|row| row.iter().map(|&v| v as f32 / 255.).collect()).collect()
+    }
+
+    #[test]
+    fn decodes_plain_pgm() {
+        let heights = decode_pgm(b"P2\n# exported\n3 2\n255\n0 128 255\n255 0 51\n").unwrap();
+        assert_eq!(heights, gray8(&[&[0, 128, 255], &[255, 0, 51]]));
+    }
+
+    #[test]
+    fn decodes_binary_pgm() {
+        let mut bytes = b"P5 2 2 255\n".to_vec();
+        bytes.extend_from_slice(&[0, 255, 51, 102]);
+        assert_eq!(decode_pgm(&bytes).unwrap(), gray8(&[&[0, 255], &[51, 102]]));
+
+        let mut bytes = b"P5\n2 1\n65535\n".to_vec();
+        bytes.extend_from_slice(&[0xff, 0xff, 0x80, 0x00]);
+        assert_eq!(decode_pgm(&bytes).unwrap(), vec![vec![1., 32768. / 65535.]]);
+    }
+
+    #[test]
+    fn decodes_8_bit_png_with_every_filter() {
+        let rows: [[u8; 4]; 5] = [[10, 20, 30, 40], [50, 40, 30, 20], [0, 255, 0, 255], [7, 200, 13, 90], [90, 91, 250, 1]];
+        let mut scanlines: Vec<u8> = vec![];
+        let mut prev = [0; 4];
+        for (filter, row) in rows.iter().enumerate() {
+            scanlines.extend(filter_scanline(filter as u8, row, &prev, 1));
+            prev = *row;
+        }
+        let heights = decode_png(&png(4, 5, 8, 0, 0, &scanlines)).unwrap();
+        let expected: Vec<&[u8]> = rows.iter().map(|row| &row[..]).collect();
+        assert_eq!(heights, gray8(&expected));
+    }
+
+    #[test]
+    fn decodes_16_bit_png() {
+        let scanlines = [0, 0xff, 0xff, 0x00, 0x00, 0, 0x80, 0x00, 0x00, 0x01];
+        let heights = decode_png(&png(2, 2, 16, 0, 0, &scanlines)).unwrap();
+        assert_eq!(heights, vec![vec![1., 0.], vec![32768. / 65535., 1. / 65535.]]);
+    }
+
+    #[test]
+    fn averages_rgb_and_ignores_alpha() {
+        let heights = decode_png(&png(1, 1, 8, 6, 0, &[0, 30, 60, 90, 0])).unwrap();
+        assert_eq!(heights, gray8(&[&[60]]));
+        let heights = decode_png(&png(2, 1, 8, 2, 0, &[0, 30, 60, 90, 255, 255, 255])).unwrap();
+        assert_eq!(heights, gray8(&[&[60, 255]]));
+    }
+
+    #[test]
+    fn rejects_palette_png() {
+        assert_eq!(decode_png(&png(1, 1, 8, 3, 0, &[0, 0])), Err("palette images are not supported"));
+    }
+
+    #[test]
+    fn rejects_interlaced_png() {
+        assert_eq!(decode_png(&png(1, 1, 8, 0, 1, &[0, 0])), Err("interlaced images are not supported"));
+    }
+
+    #[test]
+    fn rejects_oversize_images() {
+        assert_eq!(decode_png(&png(1 << 20, 1, 8, 0, 0, &[0, 0])), Err("image too large"));
+        assert_eq!(decode_png(&png(u32::MAX, u32::MAX, 16, 6, 0, &[0, 0])), Err("image too large"));
+        assert_eq!(decode_pgm(b"P5 2 100000 255\n\0\0"), Err("image too large"));
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        assert_eq!(decode_png(&png(2, 3, 8, 0, 0, &[0, 1, 2, 0, 3, 4])), Err("truncated image data"));
+        let mut bytes = png(1, 1, 8, 0, 0, &[0, 0]);
+        bytes.truncate(bytes.len() - 20);
+        assert_eq!(decode_png(&bytes), Err("truncated chunk"));
+        assert_eq!(decode_pgm(b"P5 2 2 255\n\0\0\0"), Err("truncated raster"));
+        assert_eq!(decode_pgm(b"P2 2 2 255\n0 0 0"), Err("truncated raster"));
+        assert_eq!(decode_pgm(b"P2 2"), Err("truncated header"));
+    }
+}
@@ -0,0 +1,103 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+// Poles used for every grid cell. Unbounded takes all of them, exactly like the full evaluation
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(Copy, Clone)]
+pub struct Neighbourhood {
+    pub count: Option<usize>,
+    pub radius: Option<f32>,
+}
+
+impl Neighbourhood {
+    pub fn is_unbounded(&self) -> bool {
+        self.count.is_none() && self.radius.is_none()
+    }
+}
+
+// Balanced 2d tree over the xz positions of the poles, stored implicitly:
+// the median of every slice of `order` is the node, halves are its subtrees
+pub struct KdTree {
+    points: Vec<[f32; 2]>,
+    order: Vec<usize>,
+}
+
+impl KdTree {
+    pub fn new(poles: &[na::Vector3<f32>]) -> KdTree {
+        let points: Vec<[f32; 2]> = poles.iter().map(|p| [p.x, p.z]).collect();
+        let mut order: Vec<usize> = (0..points.len()).collect();
+        build(&points, &mut order, 0);
+        KdTree { points, order }
+    }
+
+    // Indices of the nearest poles inside the neighbourhood, sorted in pole order so sums
+    // over them run in the same order as over all poles. A cell with no pole inside the
+    // search radius takes the nearest one
+    pub fn neighbours(&self, point: &na::Vector3<f32>, neighbourhood: &Neighbourhood) -> Vec<usize> {
+        let target = [point.x, point.z];
+        let count = neighbourhood.count.unwrap_or(self.points.len()).min(self.points.len());
+        let max_dist2 = neighbourhood.radius.map_or(f32::INFINITY, |r| r * r);
+
+        let mut found = self.nearest(target, count, max_dist2);
+        if found.is_empty() && count > 0 {
+            found = self.nearest(target, 1, f32::INFINITY);
+        }
+        found.sort_unstable();
+        found
+    }
+
+    fn nearest(&self, target: [f32; 2], count: usize, max_dist2: f32) -> Vec<usize> {
+        // Max heap of (squared distance bits, index): ties keep the lower index
+        let mut heap: BinaryHeap<(u32, usize)> = BinaryHeap::with_capacity(count + 1);
+        if count > 0 {
+            self.search(&self.order, 0, target, count, max_dist2, &mut heap);
+        }
+        heap.into_iter().map(|(_, i)| i).collect()
+    }
+
+    fn search(&self, order: &[usize], axis: usize, target: [f32; 2], count: usize, max_dist2: f32,
+              heap: &mut BinaryHeap<(u32, usize)>) {
+        if order.is_empty() {
+            return;
+        }
+        let mid = order.len() / 2;
+        let index = order[mid];
+        let point = self.points[index];
+        let dist2 = (point[0] - target[0]).powi(2) + (point[1] - target[1]).powi(2);
+        if dist2 <= max_dist2 {
+            // Bits of non-negative floats compare like the floats themselves
+            heap.push((dist2.to_bits(), index));
+            if heap.len() > count {
+                heap.pop();
+            }
+        }
+
+        let diff = target[axis] - point[axis];
+        let (near, far) = match diff < 0. {
+            true => (&order[..mid], &order[mid + 1..]),
+            false => (&order[mid + 1..], &order[..mid]),
+        };
+        self.search(near, 1 - axis, target, count, max_dist2, heap);
+        let worst = match heap.peek() {
+            Some(&(bits, _)) if heap.len() == count => f32::from_bits(bits).min(max_dist2),
+            _ => max_dist2,
+        };
+        if diff * diff <= worst {
+            self.search(far, 1 - axis, target, count, max_dist2, heap);
+        }
+    }
+}
+
+fn build(points: &[[f32; 2]], order: &mut [usize], axis: usize) {
+    if order.len() <= 1 {
+        return;
+    }
+    let mid = order.len() / 2;
+    order.select_nth_unstable_by(mid, |&a, &b| {
+        points[a][axis].partial_cmp(&points[b][axis]).unwrap_or(Ordering::Equal)
+    });
+    let (left, right) = order.split_at_mut(mid);
+    build(points, left, 1 - axis);
+    build(points, &mut right[1..], 1 - axis);
+}
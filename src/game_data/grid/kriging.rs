@@ -0,0 +1,372 @@
+use std::str::FromStr;
+use super::{length_on_xz, length_on_xz_f64, solve_linear_system};
+use super::kdtree::{KdTree, Neighbourhood};
+
+const VARIOGRAM_LAGS: usize = 15;
+const VARIOGRAM_RANGE_STEPS: usize = 60;
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub enum VariogramModel {
+    Spherical,
+    Exponential,
+    Gaussian,
+}
+
+impl VariogramModel {
+    // Normalized model shape: 0 at zero lag, reaches (practically) 1 at the range
+    fn shape(&self, h: f64, range: f64) -> f64 {
+        let t = h / range;
+        match self {
+            VariogramModel::Spherical if t >= 1. => 1.,
+            VariogramModel::Spherical => 1.5 * t - 0.5 * t.powi(3),
+            VariogramModel::Exponential => 1. - (-3. * t).exp(),
+            VariogramModel::Gaussian => 1. - (-3. * t * t).exp(),
+        }
+    }
+}
+
+impl FromStr for VariogramModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "spherical" => Ok(VariogramModel::Spherical),
+            "exponential" => Ok(VariogramModel::Exponential),
+            "gaussian" => Ok(VariogramModel::Gaussian),
+            _ => Err(format!("unknown variogram model {}", s)),
+        }
+    }
+}
+
+// Variogram settings. Parameters left as None are fitted to the empirical semivariogram
+#[derive(Debug)]
+#[derive(Copy, Clone)]
+pub struct Variogram {
+    pub model: VariogramModel,
+    pub nugget: Option<f32>,
+    pub sill: Option<f32>,
+    pub range: Option<f32>,
+}
+
+impl Default for Variogram {
+    fn default() -> Self {
+        Variogram {
+            model: VariogramModel::Spherical,
+            nugget: None,
+            sill: None,
+            range: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+#[derive(Copy, Clone)]
+struct FittedVariogram {
+    model: VariogramModel,
+    nugget: f64,
+    sill: f64,
+    range: f64,
+}
+
+impl FittedVariogram {
+    fn value(&self, h: f64) -> f64 {
+        if h <= 0. {
+            return 0.;
+        }
+        self.nugget + (self.sill - self.nugget) * self.model.shape(h, self.range)
+    }
+}
+
+// Ordinary kriging in dual form: the system is solved once per grid,
+// after that every point costs one variogram evaluation per pole.
+// With a bounded neighbourhood a small system is solved per point over its nearest poles instead
+pub struct Kriging {
+    variogram: FittedVariogram,
+    system: DualSystem,
+    local: Option<(Vec<na::Vector3<f32>>, KdTree, Neighbourhood)>,
+}
+
+struct DualSystem {
+    poles: Vec<(f64, f64)>,
+    weights: Vec<f64>,
+    lagrange: f64,
+}
+
+impl Kriging {
+    pub fn new(poles: &[na::Vector3<f32>], variogram: &Variogram, neighbourhood: &Neighbourhood) -> Kriging {
+        let variogram = fit_variogram(poles, variogram);
+        match neighbourhood.is_unbounded() {
+            true => Kriging { system: solve_dual(&variogram, poles), variogram, local: None },
+            false => Kriging {
+                system: DualSystem { poles: vec![], weights: vec![], lagrange: 0. },
+                variogram,
+                local: Some((poles.to_vec(), KdTree::new(poles), *neighbourhood)),
+            },
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        format!("Kriging variogram: {:?}, nugget {:.5}, sill {:.5}, range {:.5}",
+                self.variogram.model, self.variogram.nugget, self.variogram.sill, self.variogram.range)
+    }
+
+    pub fn calculate_point(&self, cur_point: &na::Vector3<f32>) -> f32 {
+        match &self.local {
+            None => self.system.calculate_point(&self.variogram, cur_point),
+            Some((poles, tree, neighbourhood)) => {
+                let neighbours: Vec<na::Vector3<f32>> = tree.neighbours(cur_point, neighbourhood)
+                    .into_iter()
+                    .map(|i| poles[i])
+                    .collect();
+                solve_dual(&self.variogram, &neighbours).calculate_point(&self.variogram, cur_point)
+            },
+        }
+    }
+}
+
+impl DualSystem {
+    fn calculate_point(&self, variogram: &FittedVariogram, cur_point: &na::Vector3<f32>) -> f32 {
+        let (x, z) = (cur_point.x as f64, cur_point.z as f64);
+        let mut y_value = self.lagrange;
+        for (w, (px, pz)) in self.weights.iter().zip(&self.poles) {
+            let dist = ((x - px).powi(2) + (z - pz).powi(2)).sqrt();
+            y_value += w * variogram.value(dist);
+        }
+        y_value as f32
+    }
+}
+
+fn solve_dual(variogram: &FittedVariogram, poles: &[na::Vector3<f32>]) -> DualSystem {
+    let n = poles.len();
+    let mut matrix = na::DMatrix::<f64>::zeros(n + 1, n + 1);
+    let mut rhs = na::DVector::<f64>::zeros(n + 1);
+    for (i, pi) in poles.iter().enumerate() {
+        for (j, pj) in poles.iter().enumerate() {
+            matrix[(i, j)] = variogram.value(length_on_xz_f64(pi, pj));
+        }
+        matrix[(i, n)] = 1.;
+        matrix[(n, i)] = 1.;
+        rhs[i] = pi.y as f64;
+    }
+
+    let solution = solve_linear_system(matrix, rhs);
+    DualSystem {
+        poles: poles.iter().map(|p| (p.x as f64, p.z as f64)).collect(),
+        weights: solution.rows(0, n).iter().copied().collect(),
+        lagrange: solution[n],
+    }
+}
+
+// Returns (lag, semivariance, pairs count) for every non-empty lag bin
+fn empirical_semivariogram(poles: &[na::Vector3<f32>]) -> Vec<(f64, f64, f64)> {
+    let mut pairs: Vec<(f64, f64)> = Vec::with_capacity(poles.len() * poles.len() / 2);
+    let mut max_dist: f64 = 0.;
+    for (i, pi) in poles.iter().enumerate() {
+        for pj in &poles[i + 1..] {
+            let dist = length_on_xz(pi, pj) as f64;
+            let semivariance = 0.5 * (pi.y - pj.y).powi(2) as f64;
+            max_dist = max_dist.max(dist);
+            pairs.push((dist, semivariance));
+        }
+    }
+
+    // Classic rule of thumb: lags further than half of the extent are unreliable
+    let max_lag = max_dist / 2.;
+    if max_lag <= 0. {
+        return vec![];
+    }
+    let lag_width = max_lag / VARIOGRAM_LAGS as f64;
+    let mut bins: Vec<(f64, f64, f64)> = vec![(0., 0., 0.); VARIOGRAM_LAGS];
+    for (dist, semivariance) in pairs {
+        if dist <= 0. || dist > max_lag {
+            continue;
+        }
+        let bin = &mut bins[((dist / lag_width) as usize).min(VARIOGRAM_LAGS - 1)];
+        bin.0 += dist;
+        bin.1 += semivariance;
+        bin.2 += 1.;
+    }
+    bins.into_iter()
+        .filter(|bin| bin.2 > 0.)
+        .map(|(dist, semivariance, count)| (dist / count, semivariance / count, count))
+        .collect()
+}
+
+fn fit_variogram(poles: &[na::Vector3<f32>], variogram: &Variogram) -> FittedVariogram {
+    fit_semivariogram(&empirical_semivariogram(poles), variogram)
+}
+
+// Ranges up to twice the furthest lag are tried, nugget and sill are solved for each of them
+fn fit_semivariogram(lags: &[(f64, f64, f64)], variogram: &Variogram) -> FittedVariogram {
+    let max_lag = lags.iter().map(|lag| lag.0).fold(0., f64::max);
+    let max_semivariance = lags.iter().map(|lag| lag.1).fold(0., f64::max);
+
+    let fixed_nugget = variogram.nugget.map(|v| v as f64);
+    let fixed_sill = variogram.sill.map(|v| v as f64);
+
+    if lags.is_empty() {
+        return FittedVariogram {
+            model: variogram.model,
+            nugget: fixed_nugget.unwrap_or(0.),
+            sill: fixed_sill.unwrap_or(1.),
+            range: variogram.range.map(|v| v as f64).unwrap_or(1.),
+        };
+    }
+
+    let ranges: Vec<f64> = match variogram.range {
+        Some(range) => vec![range as f64],
+        None => (1..=VARIOGRAM_RANGE_STEPS)
+            .map(|i| max_lag * 2. * i as f64 / VARIOGRAM_RANGE_STEPS as f64)
+            .collect(),
+    };
+
+    let mut best: Option<(f64, FittedVariogram)> = None;
+    for range in ranges {
+        let shapes: Vec<f64> = lags.iter().map(|lag| variogram.model.shape(lag.0, range)).collect();
+        let (nugget, sill) = fit_nugget_sill(lags, &shapes, fixed_nugget, fixed_sill, max_semivariance);
+        let fitted = FittedVariogram { model: variogram.model, nugget, sill, range };
+        let error: f64 = lags.iter()
+            .map(|&(lag, semivariance, count)| count * (fitted.value(lag) - semivariance).powi(2))
+            .sum();
+        let better = match best {
+            Some((best_error, _)) => error < best_error,
+            None => true,
+        };
+        if better {
+            best = Some((error, fitted));
+        }
+    }
+    best.unwrap().1
+}
+
+// Weighted least squares for semivariance = nugget + (sill - nugget) * shape,
+// which is linear in nugget and partial sill once the range is fixed
+fn fit_nugget_sill(lags: &[(f64, f64, f64)], shapes: &[f64],
+                   fixed_nugget: Option<f64>, fixed_sill: Option<f64>,
+                   max_semivariance: f64) -> (f64, f64) {
+    let sum = |f: &dyn Fn(f64, f64) -> f64| -> f64 {
+        lags.iter().zip(shapes).map(|(&(_, semivariance, count), &shape)| count * f(shape, semivariance)).sum()
+    };
+
+    match (fixed_nugget, fixed_sill) {
+        (Some(nugget), Some(sill)) => (nugget, sill),
+        (Some(nugget), None) => {
+            let denominator = sum(&|s, _| s * s);
+            let partial_sill = match denominator {
+                d if d > 0. => (sum(&|s, g| s * (g - nugget)) / d).max(0.),
+                _ => max_semivariance,
+            };
+            (nugget, nugget + partial_sill)
+        },
+        (None, Some(sill)) => {
+            let denominator = sum(&|s, _| (1. - s) * (1. - s));
+            let nugget = match denominator {
+                d if d > 0. => (sum(&|s, g| (1. - s) * (g - sill * s)) / d).clamp(0., sill.max(0.)),
+                _ => 0.,
+            };
+            (nugget, sill)
+        },
+        (None, None) => {
+            let (a, b, c) = (sum(&|_, _| 1.), sum(&|s, _| s), sum(&|s, _| s * s));
+            let (d, e) = (sum(&|_, g| g), sum(&|s, g| s * g));
+            let det = a * c - b * b;
+            let (nugget, partial_sill) = match det {
+                det if det.abs() > f64::EPSILON => ((c * d - b * e) / det, (a * e - b * d) / det),
+                _ => (0., max_semivariance),
+            };
+            let nugget = nugget.max(0.);
+            (nugget, nugget + partial_sill.max(0.))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODELS: [VariogramModel; 3] = [VariogramModel::Spherical, VariogramModel::Exponential, VariogramModel::Gaussian];
+
+    fn poles() -> Vec<na::Vector3<f32>> {
+        let mut state: u64 = 0x853c49e6748fea9b;
+        let mut random = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 40) as f32 / (1u64 << 24) as f32 * 2. - 1.
+        };
+        (0..30).map(|_| {
+            let (x, z) = (random(), random());
+            na::Vector3::new(x, 0.5 + 0.3 * (x * 3.).sin() * (z * 2.).cos() + 0.05 * random(), z)
+        }).collect()
+    }
+
+    // Weights of the poles for one point, from the primal ordinary kriging system
+    fn primal_weights(variogram: &FittedVariogram, poles: &[na::Vector3<f32>], point: &na::Vector3<f32>) -> Vec<f64> {
+        let n = poles.len();
+        let mut matrix = na::DMatrix::<f64>::zeros(n + 1, n + 1);
+        let mut rhs = na::DVector::<f64>::zeros(n + 1);
+        for (i, pi) in poles.iter().enumerate() {
+            for (j, pj) in poles.iter().enumerate() {
+                matrix[(i, j)] = variogram.value(length_on_xz_f64(pi, pj));
+            }
+            matrix[(i, n)] = 1.;
+            matrix[(n, i)] = 1.;
+            rhs[i] = variogram.value(length_on_xz_f64(pi, point));
+        }
+        rhs[n] = 1.;
+        solve_linear_system(matrix, rhs).rows(0, n).iter().copied().collect()
+    }
+
+    #[test]
+    fn zero_nugget_passes_through_the_poles() {
+        let poles = poles();
+        for &model in &MODELS {
+            let variogram = Variogram { model, nugget: Some(0.), sill: None, range: None };
+            let kriging = Kriging::new(&poles, &variogram, &Neighbourhood::default());
+            for pole in &poles {
+                let value = kriging.calculate_point(pole);
+                assert!((value - pole.y).abs() < 1e-6, "{:?}: {} at a pole of {}", model, value, pole.y);
+            }
+        }
+    }
+
+    #[test]
+    fn weights_sum_to_one() {
+        let poles = poles();
+        for &model in &MODELS {
+            let variogram = Variogram { model, ..Variogram::default() };
+            let kriging = Kriging::new(&poles, &variogram, &Neighbourhood::default());
+            for &(x, z) in &[(0.1, -0.3), (-0.8, 0.75), (0.95, 0.95), (0., 0.)] {
+                let point = na::Vector3::new(x, 0., z);
+                let weights = primal_weights(&kriging.variogram, &poles, &point);
+                assert!((weights.iter().sum::<f64>() - 1.).abs() < 1e-9, "{:?}", model);
+                // The dual form used for the grid gives the same estimate
+                let estimate: f64 = weights.iter().zip(&poles).map(|(w, pole)| w * pole.y as f64).sum();
+                assert!((estimate as f32 - kriging.calculate_point(&point)).abs() < 1e-5, "{:?}", model);
+            }
+        }
+    }
+
+    #[test]
+    fn fitting_recovers_the_model() {
+        for &model in &MODELS {
+            // The range is one of the tried ones: twice the furthest lag times 20 / 60
+            let truth = FittedVariogram { model, nugget: 0.02, sill: 0.3, range: 1. };
+            let lags: Vec<(f64, f64, f64)> = (1..=15)
+                .map(|i| i as f64 * 0.1)
+                .map(|lag| (lag, truth.value(lag), 10. + lag * 20.))
+                .collect();
+            let settings = [
+                Variogram { model, nugget: None, sill: None, range: None },
+                Variogram { model, nugget: Some(0.02), sill: None, range: None },
+                Variogram { model, nugget: None, sill: Some(0.3), range: None },
+            ];
+            for variogram in &settings {
+                let fitted = fit_semivariogram(&lags, variogram);
+                assert!((fitted.nugget - truth.nugget).abs() < 1e-6, "{:?} {:?}", variogram, fitted);
+                assert!((fitted.sill - truth.sill).abs() < 1e-6, "{:?} {:?}", variogram, fitted);
+                assert!((fitted.range - truth.range).abs() < 1e-9, "{:?} {:?}", variogram, fitted);
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+extern crate rand;
+extern crate rand_chacha;
+
+use std::str::FromStr;
+use self::rand::{Rng, SeedableRng};
+use self::rand_chacha::ChaCha12Rng;
+use self::rand::seq::SliceRandom;
+
+const NOISE_OCTAVES: usize = 6;
+const NOISE_FREQUENCY: f32 = 3.;
+const NOISE_GAIN: f32 = 0.5;
+const DIAMOND_SQUARE_ROUGHNESS: f32 = 0.55;
+const VALLEY_FLOOR: f32 = 0.12;
+const RIVER_BED: f32 = 0.04;
+const RIVER_HALF_WIDTH: f32 = 0.04;
+const VALLEY_HALF_WIDTH: f32 = 0.45;
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub enum TerrainKind {
+    Noise,
+    DiamondSquare,
+    Valley,
+}
+
+impl FromStr for TerrainKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "noise" => Ok(TerrainKind::Noise),
+            "diamond-square" => Ok(TerrainKind::DiamondSquare),
+            "valley" => Ok(TerrainKind::Valley),
+            _ => Err(format!("unknown terrain kind {}", s)),
+        }
+    }
+}
+
+// Rows along Z of heights in [0;1], the same seed always gives the same terrain.
+// ChaCha12 is named like in the water, StdRng may change its algorithm between rand releases
+pub fn generate(kind: TerrainKind, seed: u64, x_size: usize, z_size: usize) -> Vec<Vec<f32>> {
+    let mut rng = ChaCha12Rng::seed_from_u64(seed);
+    let heights = match kind {
+        TerrainKind::Noise => {
+            let noise = Perlin::new(&mut rng);
+            sample(x_size, z_size, |x, z| noise.fractal(x * NOISE_FREQUENCY, z * NOISE_FREQUENCY))
+        },
+        TerrainKind::DiamondSquare => diamond_square(&mut rng, x_size, z_size),
+        TerrainKind::Valley => valley(&mut rng, x_size, z_size),
+    };
+    normalize(heights)
+}
+
+// Calls f(x, z) with x, z in [0;1] for every cell
+fn sample<F: Fn(f32, f32) -> f32>(x_size: usize, z_size: usize, f: F) -> Vec<Vec<f32>> {
+    let coord = |i: usize, size: usize| i as f32 / (size.max(2) - 1) as f32;
+    (0..z_size)
+        .map(|row| (0..x_size).map(|col| f(coord(col, x_size), coord(row, z_size))).collect())
+        .collect()
+}
+
+fn normalize(mut heights: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+    let min = heights.iter().flatten().copied().fold(f32::MAX, f32::min);
+    let max = heights.iter().flatten().copied().fold(f32::MIN, f32::max);
+    let span = max - min;
+    for height in heights.iter_mut().flatten() {
+        *height = match span > 0. {
+            true => (*height - min) / span,
+            false => 0.,
+        };
+    }
+    heights
+}
+
+// Classic gradient noise over a seeded permutation table
+struct Perlin {
+    permutation: Vec<usize>,
+}
+
+impl Perlin {
+    fn new(rng: &mut ChaCha12Rng) -> Perlin {
+        let mut table: Vec<usize> = (0..256).collect();
+        table.shuffle(rng);
+        Perlin { permutation: table.iter().chain(table.iter()).copied().collect() }
+    }
+
+    fn fractal(&self, x: f32, z: f32) -> f32 {
+        let (mut sum, mut amplitude, mut frequency) = (0., 1., 1.);
+        for _octave in 0..NOISE_OCTAVES {
+            sum += amplitude * self.noise(x * frequency, z * frequency);
+            amplitude *= NOISE_GAIN;
+            frequency *= 2.;
+        }
+        sum
+    }
+
+    fn noise(&self, x: f32, z: f32) -> f32 {
+        let (xi, zi) = ((x.floor() as i64 & 255) as usize, (z.floor() as i64 & 255) as usize);
+        let (xf, zf) = (x - x.floor(), z - z.floor());
+        let (u, v) = (fade(xf), fade(zf));
+        let p = &self.permutation;
+        let corner = |dx: usize, dz: usize| p[p[xi + dx] + zi + dz];
+
+        let top = lerp(gradient(corner(0, 0), xf, zf), gradient(corner(1, 0), xf - 1., zf), u);
+        let bot = lerp(gradient(corner(0, 1), xf, zf - 1.), gradient(corner(1, 1), xf - 1., zf - 1.), u);
+        lerp(top, bot, v)
+    }
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6. - 15.) + 10.)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn gradient(hash: usize, x: f32, z: f32) -> f32 {
+    match hash & 7 {
+        0 => x + z,
+        1 => x - z,
+        2 => -x + z,
+        3 => -x - z,
+        4 => x,
+        5 => -x,
+        6 => z,
+        _ => -z,
+    }
+}
+
+// Works on the smallest 2^n + 1 square that covers the grid, which is then cropped
+fn diamond_square(rng: &mut ChaCha12Rng, x_size: usize, z_size: usize) -> Vec<Vec<f32>> {
+    let mut size = 2;
+    while size + 1 < x_size.max(z_size) {
+        size *= 2;
+    }
+    let last = size;
+    let mut map = vec![vec![0_f32; size + 1]; size + 1];
+    for &(row, col) in &[(0, 0), (0, last), (last, 0), (last, last)] {
+        map[row][col] = rng.gen_range(0. ..1.);
+    }
+
+    let mut step = size;
+    let mut amplitude = 1.;
+    while step > 1 {
+        let half = step / 2;
+        // Diamond: centers of squares
+        for row in (half..last).step_by(step) {
+            for col in (half..last).step_by(step) {
+                let average = (map[row - half][col - half] + map[row - half][col + half]
+                    + map[row + half][col - half] + map[row + half][col + half]) / 4.;
+                map[row][col] = average + rng.gen_range(-amplitude..amplitude);
+            }
+        }
+        // Square: edge midpoints, neighbours outside the map are skipped
+        for row in (0..=last).step_by(half) {
+            let first_col = if (row / half) % 2 == 0 { half } else { 0 };
+            for col in (first_col..=last).step_by(step) {
+                let neighbours = [
+                    (row as i64 - half as i64, col as i64),
+                    (row as i64 + half as i64, col as i64),
+                    (row as i64, col as i64 - half as i64),
+                    (row as i64, col as i64 + half as i64),
+                ];
+                let (sum, count) = neighbours.iter()
+                    .filter(|(r, c)| *r >= 0 && *c >= 0 && *r <= last as i64 && *c <= last as i64)
+                    .fold((0., 0.), |(sum, count), &(r, c)| (sum + map[r as usize][c as usize], count + 1.));
+                map[row][col] = sum / count + rng.gen_range(-amplitude..amplitude);
+            }
+        }
+        step = half;
+        amplitude *= DIAMOND_SQUARE_ROUGHNESS;
+    }
+
+    map.truncate(z_size);
+    for row in &mut map {
+        row.truncate(x_size);
+    }
+    map
+}
+
+// A river meanders along X at the bottom of a valley that descends downstream,
+// noisy slopes rise on both sides
+fn valley(rng: &mut ChaCha12Rng, x_size: usize, z_size: usize) -> Vec<Vec<f32>> {
+    let noise = Perlin::new(rng);
+    let meander_phase = rng.gen_range(0. ..std::f32::consts::TAU);
+    let meander_amplitude = rng.gen_range(0.08..0.18);
+    let meanders = rng.gen_range(1.0..2.5);
+
+    sample(x_size, z_size, |x, z| {
+        let river_z = 0.5 + meander_amplitude * (std::f32::consts::TAU * meanders * x + meander_phase).sin()
+            + 0.05 * noise.fractal(x * 2., 0.5);
+        let distance = (z - river_z).abs();
+        let downstream = 0.1 * (1. - x);
+        let slopes = noise.fractal(x * NOISE_FREQUENCY * 2., z * NOISE_FREQUENCY * 2.) * 0.15;
+
+        let wall = smoothstep(RIVER_HALF_WIDTH, VALLEY_HALF_WIDTH, distance);
+        let ground = VALLEY_FLOOR + downstream + wall * (0.7 + slopes) + slopes * 0.2;
+        let channel = smoothstep(0., RIVER_HALF_WIDTH, distance);
+        RIVER_BED + downstream + (ground - RIVER_BED - downstream) * channel
+    })
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
@@ -0,0 +1,187 @@
+use super::{length_on_xz, length_on_xz_f64, solve_linear_system};
+use super::kdtree::{KdTree, Neighbourhood};
+
+// Affine polynomial tail (1, x, z) that makes thin plate spline and multiquadric systems well-posed
+const POLYNOMIAL_TERMS: usize = 3;
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub enum RbfKernel {
+    ThinPlateSpline,
+    Multiquadric,
+    InverseMultiquadric,
+    Gaussian,
+}
+
+impl RbfKernel {
+    fn value(&self, r: f64, epsilon: f64) -> f64 {
+        let er = epsilon * r;
+        match self {
+            RbfKernel::ThinPlateSpline if r <= 0. => 0.,
+            RbfKernel::ThinPlateSpline => r * r * r.ln(),
+            RbfKernel::Multiquadric => (1. + er * er).sqrt(),
+            RbfKernel::InverseMultiquadric => 1. / (1. + er * er).sqrt(),
+            RbfKernel::Gaussian => (-er * er).exp(),
+        }
+    }
+}
+
+// Exact interpolator when smoothing is 0: the surface passes through every pole.
+// With a bounded neighbourhood every point gets its own system over the nearest poles
+pub struct Rbf {
+    kernel: RbfKernel,
+    epsilon: f64,
+    smoothing: f64,
+    system: RbfSystem,
+    local: Option<(Vec<na::Vector3<f32>>, KdTree, Neighbourhood)>,
+}
+
+struct RbfSystem {
+    poles: Vec<(f64, f64)>,
+    weights: Vec<f64>,
+    polynomial: [f64; POLYNOMIAL_TERMS],
+}
+
+impl Rbf {
+    pub fn new(poles: &[na::Vector3<f32>], kernel: RbfKernel, epsilon: Option<f32>, smoothing: f32,
+               neighbourhood: &Neighbourhood) -> Rbf {
+        let epsilon = epsilon.map(|e| e as f64).unwrap_or_else(|| default_epsilon(poles));
+
+        let mut rbf = Rbf {
+            kernel,
+            epsilon,
+            smoothing: smoothing as f64,
+            system: RbfSystem { poles: vec![], weights: vec![], polynomial: [0.; POLYNOMIAL_TERMS] },
+            local: None,
+        };
+        match neighbourhood.is_unbounded() {
+            true => rbf.system = rbf.solve(poles),
+            false => rbf.local = Some((poles.to_vec(), KdTree::new(poles), *neighbourhood)),
+        }
+        rbf
+    }
+
+    pub fn describe(&self) -> String {
+        format!("RBF kernel: {:?}, epsilon {:.5}, smoothing {:.5}", self.kernel, self.epsilon, self.smoothing)
+    }
+
+    pub fn calculate_point(&self, cur_point: &na::Vector3<f32>) -> f32 {
+        match &self.local {
+            None => self.evaluate(&self.system, cur_point),
+            Some((poles, tree, neighbourhood)) => {
+                let neighbours: Vec<na::Vector3<f32>> = tree.neighbours(cur_point, neighbourhood)
+                    .into_iter()
+                    .map(|i| poles[i])
+                    .collect();
+                self.evaluate(&self.solve(&neighbours), cur_point)
+            },
+        }
+    }
+
+    fn solve(&self, poles: &[na::Vector3<f32>]) -> RbfSystem {
+        let n = poles.len();
+        let size = n + POLYNOMIAL_TERMS;
+        let mut matrix = na::DMatrix::<f64>::zeros(size, size);
+        let mut rhs = na::DVector::<f64>::zeros(size);
+        for (i, pi) in poles.iter().enumerate() {
+            for (j, pj) in poles.iter().enumerate() {
+                matrix[(i, j)] = self.kernel.value(length_on_xz_f64(pi, pj), self.epsilon);
+            }
+            matrix[(i, i)] += self.smoothing;
+            let terms = [1., pi.x as f64, pi.z as f64];
+            for (k, term) in terms.iter().enumerate() {
+                matrix[(i, n + k)] = *term;
+                matrix[(n + k, i)] = *term;
+            }
+            rhs[i] = pi.y as f64;
+        }
+
+        let solution = solve_linear_system(matrix, rhs);
+        RbfSystem {
+            poles: poles.iter().map(|p| (p.x as f64, p.z as f64)).collect(),
+            weights: solution.rows(0, n).iter().copied().collect(),
+            polynomial: [solution[n], solution[n + 1], solution[n + 2]],
+        }
+    }
+
+    fn evaluate(&self, system: &RbfSystem, cur_point: &na::Vector3<f32>) -> f32 {
+        let (x, z) = (cur_point.x as f64, cur_point.z as f64);
+        let mut y_value = system.polynomial[0] + system.polynomial[1] * x + system.polynomial[2] * z;
+        for (w, (px, pz)) in system.weights.iter().zip(&system.poles) {
+            let dist = ((x - px).powi(2) + (z - pz).powi(2)).sqrt();
+            y_value += w * self.kernel.value(dist, self.epsilon);
+        }
+        y_value as f32
+    }
+}
+
+// Inverse of the mean nearest neighbour distance, so the kernel width follows pole density
+fn default_epsilon(poles: &[na::Vector3<f32>]) -> f64 {
+    let nearest_sum: f64 = poles.iter().enumerate()
+        .map(|(i, pi)| {
+            poles.iter().enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, pj)| length_on_xz(pi, pj) as f64)
+                .filter(|dist| *dist > 0.)
+                .fold(f64::MAX, f64::min)
+        })
+        .filter(|dist| *dist < f64::MAX)
+        .sum();
+    match nearest_sum {
+        sum if sum > 0. => poles.len() as f64 / sum,
+        _ => 1.,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poles() -> Vec<na::Vector3<f32>> {
+        let mut state: u64 = 0xda3e39cb94b95bdb;
+        let mut random = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 40) as f32 / (1u64 << 24) as f32 * 2. - 1.
+        };
+        (0..30).map(|_| {
+            let (x, z) = (random(), random());
+            na::Vector3::new(x, 0.5 + 0.3 * (x * 3.).sin() * (z * 2.).cos() + 0.05 * random(), z)
+        }).collect()
+    }
+
+    // Largest distance between the surface and a pole
+    fn pole_misfit(kernel: RbfKernel, smoothing: f32) -> f32 {
+        let poles = poles();
+        let rbf = Rbf::new(&poles, kernel, None, smoothing, &Neighbourhood::default());
+        poles.iter().map(|pole| (rbf.calculate_point(pole) - pole.y).abs()).fold(0., f32::max)
+    }
+
+    #[test]
+    fn thin_plate_spline_passes_through_the_poles() {
+        assert!(pole_misfit(RbfKernel::ThinPlateSpline, 0.) < 1e-6);
+    }
+
+    #[test]
+    fn multiquadric_passes_through_the_poles() {
+        assert!(pole_misfit(RbfKernel::Multiquadric, 0.) < 1e-6);
+    }
+
+    #[test]
+    fn inverse_multiquadric_passes_through_the_poles() {
+        assert!(pole_misfit(RbfKernel::InverseMultiquadric, 0.) < 1e-6);
+    }
+
+    #[test]
+    fn gaussian_passes_through_the_poles() {
+        assert!(pole_misfit(RbfKernel::Gaussian, 0.) < 1e-6);
+    }
+
+    #[test]
+    fn smoothing_lets_the_surface_miss_the_poles() {
+        for &kernel in &[RbfKernel::ThinPlateSpline, RbfKernel::Multiquadric,
+                         RbfKernel::InverseMultiquadric, RbfKernel::Gaussian] {
+            assert!(pole_misfit(kernel, 0.1) > 1e-3, "{:?}", kernel);
+        }
+    }
+}
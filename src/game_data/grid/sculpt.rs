@@ -0,0 +1,137 @@
+use std::ops::Range;
+use super::{Aspect, MIN_HEIGHT, MAX_HEIGHT};
+
+const DEFAULT_BRUSH_RADIUS: f32 = 0.1;
+const DEFAULT_BRUSH_STRENGTH: f32 = 0.005;
+const MIN_BRUSH_RADIUS: f32 = 0.02;
+const MAX_BRUSH_RADIUS: f32 = 1.;
+const MIN_BRUSH_STRENGTH: f32 = 0.0005;
+const MAX_BRUSH_STRENGTH: f32 = 0.05;
+const BRUSH_STEP: f32 = 1.25;
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub enum BrushMode {
+    Raise,
+    Lower,
+    Flatten,
+    Smooth,
+}
+
+impl BrushMode {
+    pub fn next(&self) -> BrushMode {
+        match self {
+            BrushMode::Raise => BrushMode::Lower,
+            BrushMode::Lower => BrushMode::Flatten,
+            BrushMode::Flatten => BrushMode::Smooth,
+            BrushMode::Smooth => BrushMode::Raise,
+        }
+    }
+}
+
+// Radius is in world units (the longer side of the terrain is 2),
+// strength is the height change per frame at the brush center, smooth scales its blend by it
+#[derive(Debug)]
+#[derive(Copy, Clone)]
+pub struct Brush {
+    pub mode: BrushMode,
+    pub radius: f32,
+    pub strength: f32,
+}
+
+impl Default for Brush {
+    fn default() -> Self {
+        Brush {
+            mode: BrushMode::Raise,
+            radius: DEFAULT_BRUSH_RADIUS,
+            strength: DEFAULT_BRUSH_STRENGTH,
+        }
+    }
+}
+
+impl Brush {
+    pub fn resize(&mut self, grow: bool) {
+        let factor = if grow { BRUSH_STEP } else { 1. / BRUSH_STEP };
+        self.radius = (self.radius * factor).clamp(MIN_BRUSH_RADIUS, MAX_BRUSH_RADIUS);
+    }
+
+    pub fn strengthen(&mut self, grow: bool) {
+        let factor = if grow { BRUSH_STEP } else { 1. / BRUSH_STEP };
+        self.strength = (self.strength * factor).clamp(MIN_BRUSH_STRENGTH, MAX_BRUSH_STRENGTH);
+    }
+}
+
+// Rows and columns of the terrain samples a stroke has changed
+pub struct Affected {
+    pub rows: Range<usize>,
+    pub cols: Range<usize>,
+}
+
+// One frame of a stroke at (x, z) in [-1;1]. Flatten pulls heights to target,
+// which is taken under the cursor when the stroke starts. Height changes are added to edits
+pub fn apply(data: &mut [Vec<f32>], edits: &mut [Vec<f32>], brush: &Brush,
+             x: f32, z: f32, target: f32, aspect: &Aspect) -> Option<Affected> {
+    let (rows, cols) = (data.len(), data[0].len());
+    let (step_x, step_z) = (2. / (cols - 1) as f32, 2. / (rows - 1) as f32);
+    let index_range = |center: f32, step: f32, scale: f32, size: usize| -> Range<usize> {
+        let reach = brush.radius / scale;
+        let first = ((center - reach + 1.) / step).ceil().max(0.) as usize;
+        let last = (((center + reach + 1.) / step).floor() + 1.).clamp(0., size as f32) as usize;
+        first..last.max(first)
+    };
+    let affected = Affected {
+        rows: index_range(z, step_z, aspect.z, rows),
+        cols: index_range(x, step_x, aspect.x, cols),
+    };
+    if affected.rows.is_empty() || affected.cols.is_empty() {
+        return None;
+    }
+
+    // Smoothing reads the heights around the brush as they were before this frame
+    let source_rows = affected.rows.start.saturating_sub(1)..(affected.rows.end + 1).min(rows);
+    let source: Vec<Vec<f32>> = match brush.mode {
+        BrushMode::Smooth => data[source_rows.clone()].to_vec(),
+        _ => vec![],
+    };
+    for row in affected.rows.clone() {
+        for col in affected.cols.clone() {
+            let dx = (-1. + col as f32 * step_x - x) * aspect.x;
+            let dz = (-1. + row as f32 * step_z - z) * aspect.z;
+            let distance = (dx * dx + dz * dz).sqrt() / brush.radius;
+            if distance >= 1. {
+                continue;
+            }
+            let weight = (1. - distance * distance).powi(2);
+            let height = data[row][col];
+            let new_height = match brush.mode {
+                BrushMode::Raise => height + brush.strength * weight,
+                BrushMode::Lower => height - brush.strength * weight,
+                BrushMode::Flatten => approach(height, target, brush.strength * weight),
+                BrushMode::Smooth => {
+                    let blend = weight * brush.strength / MAX_BRUSH_STRENGTH;
+                    height + (neighbours_average(&source, row - source_rows.start, col) - height) * blend
+                },
+            }.clamp(MIN_HEIGHT, MAX_HEIGHT);
+            edits[row][col] += new_height - height;
+            data[row][col] = new_height;
+        }
+    }
+    Some(affected)
+}
+
+// Moves towards the goal by at most the given amount
+fn approach(height: f32, goal: f32, amount: f32) -> f32 {
+    height + (goal - height).clamp(-amount, amount)
+}
+
+fn neighbours_average(data: &[Vec<f32>], row: usize, col: usize) -> f32 {
+    let (mut sum, mut count) = (0., 0.);
+    for neighbours_row in &data[row.saturating_sub(1)..(row + 2).min(data.len())] {
+        for height in &neighbours_row[col.saturating_sub(1)..(col + 2).min(neighbours_row.len())] {
+            sum += height;
+            count += 1.;
+        }
+    }
+    sum / count
+}
@@ -0,0 +1,70 @@
+use std::fmt::Write;
+
+type Position = (f32, f32, f32);
+
+pub fn triangles_to_obj(positions: &[Position], indices: &[u32]) -> String {
+    let mut out = String::from("# Terrain exported by mod1\n");
+    for (x, y, z) in positions {
+        let _ = writeln!(out, "v {} {} {}", x, y, z);
+    }
+    // OBJ indices are 1-based
+    for triangle in indices.chunks_exact(3) {
+        let _ = writeln!(out, "f {} {} {}", triangle[0] + 1, triangle[1] + 1, triangle[2] + 1);
+    }
+    out
+}
+
+pub fn triangles_to_stl(positions: &[Position], indices: &[u32]) -> Vec<u8> {
+    let triangles_count = indices.len() / 3;
+    let mut out: Vec<u8> = Vec::with_capacity(84 + triangles_count * 50);
+    let mut header = [0u8; 80];
+    let title = b"Terrain exported by mod1";
+    header[..title.len()].copy_from_slice(title);
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&(triangles_count as u32).to_le_bytes());
+
+    let push_vector = |out: &mut Vec<u8>, v: &na::Vector3<f32>| {
+        for coord in v.iter() {
+            out.extend_from_slice(&coord.to_le_bytes());
+        }
+    };
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]]
+            .map(|i| { let p = positions[i as usize]; na::Vector3::new(p.0, p.1, p.2) });
+        let normal = (b - a).cross(&(c - a));
+        let normal = normal.try_normalize(f32::EPSILON).unwrap_or_else(na::Vector3::zeros);
+        push_vector(&mut out, &normal);
+        push_vector(&mut out, &a);
+        push_vector(&mut out, &b);
+        push_vector(&mut out, &c);
+        out.extend_from_slice(&0u16.to_le_bytes());     // attribute byte count
+    }
+    out
+}
+
+pub fn quads_to_obj(quads: &[[Position; 4]]) -> String {
+    let mut out = String::from("# Water exported by mod1\n");
+    for quad in quads {
+        for (x, y, z) in quad {
+            let _ = writeln!(out, "v {} {} {}", x, y, z);
+        }
+    }
+    for i in 0..quads.len() {
+        let first = i * 4 + 1;
+        let _ = writeln!(out, "f {} {} {} {}", first, first + 1, first + 2, first + 3);
+    }
+    out
+}
+
+// One vertex per quad center
+pub fn quads_to_ply(quads: &[[Position; 4]]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "ply\nformat ascii 1.0\ncomment Water exported by mod1");
+    let _ = writeln!(out, "element vertex {}", quads.len());
+    let _ = writeln!(out, "property float x\nproperty float y\nproperty float z\nend_header");
+    for quad in quads {
+        let center = quad.iter().fold((0., 0., 0.), |acc, p| (acc.0 + p.0 / 4., acc.1 + p.1 / 4., acc.2 + p.2 / 4.));
+        let _ = writeln!(out, "{} {} {}", center.0, center.1, center.2);
+    }
+    out
+}
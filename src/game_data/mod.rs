@@ -3,50 +3,262 @@ use gl_render::{ColorBuffer, Viewport};
 use gl_render::uniform::HasUniform;
 use resources::Resources;
 use surface::Surface;
+use pole_markers::{PoleDrag, PoleMarkers};
+use file_watcher::FileWatcher;
+use replay::{Event, Recorder, Replay, Seeds};
 use crate::camera::MVP;
+use crate::config::Config;
 use controls::{Controls};
-use grid::{Grid, GridingAlgo};
-use water::{Water};
+use grid::{Aspect, Grid, GridingAlgo, PointFile};
+use grid::sculpt::Brush;
+use water::Water;
+use water::renderer::WaterRenderer;
+use std::str::FromStr;
+use std::fs;
+use std::path::Path;
 
 pub mod controls;
 mod surface;
 mod water;
-mod grid;
+pub use water::UpdateMode;
+pub mod grid;
+mod mesh_export;
+mod pole_markers;
+mod file_watcher;
+mod snapshot;
+mod replay;
+pub mod cross_validation;
 
 pub struct GameData {
     gl: gl::Gl,
+    res: Resources,
     viewport: Viewport,
     grid: Grid,
+    grid_files: Vec<PointFile>,
+    griding_algo: GridingAlgo,
+    resolution: Resolution,
+    sea_level: Option<f64>,
     surface: Surface,
     water: Water,
+    water_renderer: WaterRenderer,
+    markers: PoleMarkers,
+    edit_poles: bool,
+    selected_pole: Option<usize>,
+    pole_drag: Option<PoleDrag>,
     mvp: MVP,
+    brush: Brush,
+    stroke_height: Option<f32>,
     color_buffer: ColorBuffer,
     pub controls: Controls,
+    watcher: FileWatcher,
+    frame: u64,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
     need_exit: bool,
 }
 
-pub const GRID_WIDTH: usize = 200;
+// Terrain samples along X and Z and water layers in height
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub struct Resolution {
+    pub x: usize,
+    pub z: usize,
+    pub height: usize,
+}
+
+impl Default for Resolution {
+    fn default() -> Self {
+        Resolution { x: 200, z: 200, height: 100 }
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    // "<size>" or "<x>x<z>", then optional ":<height>". Height is half of the longer side if omitted
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |value: &str| match value.parse::<usize>() {
+            Ok(v) if v >= MIN_RESOLUTION => Ok(v),
+            _ => Err(format!("resolution must be an integer not less than {}", MIN_RESOLUTION)),
+        };
+        let (sides, height) = match s.split_once(':') {
+            Some((sides, height)) => (sides, Some(parse(height)?)),
+            None => (s, None),
+        };
+        let (x, z) = match sides.split_once('x') {
+            Some((x, z)) => (parse(x)?, parse(z)?),
+            None => (parse(sides)?, parse(sides)?),
+        };
+        let height = height.unwrap_or_else(|| (x.max(z) / 2).max(MIN_RESOLUTION));
+        Ok(Resolution { x, z, height })
+    }
+}
+
+impl Resolution {
+    // Same proportions with the longer side of the given size
+    pub fn with_longer_side(&self, size: usize) -> Resolution {
+        let longer = self.x.max(self.z);
+        let scale = |value: usize| ((value * size + longer / 2) / longer).max(MIN_RESOLUTION);
+        Resolution { x: scale(self.x), z: scale(self.z), height: scale(self.height) }
+    }
+
+    // A square resolution follows the world aspect, keeping its size for the longer side
+    pub fn fit_aspect(&self, aspect: &Aspect) -> Resolution {
+        if self.x != self.z {
+            return *self;
+        }
+        let side = |scale: f32| ((self.x as f32 * scale).round() as usize).max(MIN_RESOLUTION);
+        Resolution { x: side(aspect.x), z: side(aspect.z), height: self.height }
+    }
+}
+
+// Loaded grid files and the sources of every shader program
+fn watched_files(config: &Config) -> Vec<String> {
+    let mut files: Vec<String> = [surface::SHADER, water::renderer::SHADER, pole_markers::SHADER].iter()
+        .flat_map(|shader| vec![format!("{}.vert", shader), format!("{}.frag", shader)])
+        .collect();
+    if config.procedural.is_none() {
+        files.extend(config.grid_files.iter().map(|file| file.path.clone()));
+    }
+    files
+}
+
+const MIN_RESOLUTION: usize = 4;
+pub const RESOLUTION_PRESETS: [usize; 7] = [50, 75, 100, 150, 200, 300, 400];
 
 impl GameData {
-    pub fn new(gl: &gl::Gl, res: &Resources, grid_path: &str) -> Result<GameData, failure::Error> {
+    pub fn new(gl: &gl::Gl, res: &Resources, config: &Config) -> Result<GameData, failure::Error> {
         let color_buffer: gl_render::ColorBuffer = (0.3, 0.3, 0.5).into(); // TODO add to config
-        color_buffer.use_it(&gl);
+        color_buffer.use_it(gl);
 
         let viewport = gl_render::Viewport::for_window(900, 700); // TODO add size to config
-        viewport.use_it(&gl);
+        viewport.use_it(gl);
 
-        let grid = Grid::new(&res, grid_path, GRID_WIDTH, GridingAlgo::RadialBasisFunction)?;
-        let surface = Surface::new(&res, &gl, grid.get_data())?;
-        let water = Water::new(&res, &gl, grid.get_data())?;
+        let griding_algo = GridingAlgo::RadialBasisFunction;
+        let seeds = Seeds { simulation: config.seed.unwrap_or_else(rand::random), terrain: config.terrain_seed };
+        let replay = match &config.replay {
+            Some(path) => Some(Replay::load(&res.resource_path(path), seeds)?),
+            None => None,
+        };
+        let mut seeds = replay.as_ref().map_or(seeds, |replay| replay.get_seeds());
+        let mut grid = match config.procedural {
+            Some(kind) => {
+                let terrain_seed = *seeds.terrain.get_or_insert_with(rand::random);
+                Grid::procedural(kind, terrain_seed, config.grid_options)
+            },
+            None => Grid::load(res, &config.grid_files, config.grid_options)?,
+        };
+        let resolution = config.resolution.fit_aspect(&grid.get_aspect());
+        println!("Resolution: {}x{}, {} water layers, aspect {:?}",
+                 resolution.x, resolution.z, resolution.height, grid.get_aspect());
+        grid.update_grid(resolution.x, resolution.z, griding_algo);
+        grid.set_filters(config.filters.clone());
+        let surface = Surface::new(res, gl, grid.get_data())?;
+        println!("Simulation seed: {}", seeds.simulation);
+        let mut water = Water::new(grid.get_data(), resolution.height, seeds.simulation);
+        water.set_update_mode(config.update_mode);
+        println!("Water update mode: {}", config.update_mode.name());
+        let water_renderer = WaterRenderer::new(res, gl, &water)?;
+        let mut markers = PoleMarkers::new(res, gl)?;
+        markers.set_poles(grid.get_user_poles(), None);
 
-        let mvp = MVP::new();
-        surface.apply_uniform(&gl, &mvp, "mvp_transform").map_err(err_msg)?;
-        water.apply_uniform(&gl, &mvp, "mvp_transform").map_err(err_msg)?;
+        let mvp = MVP::new(&grid.get_aspect());
+        surface.apply_uniform(gl, &mvp, "mvp_transform").map_err(err_msg)?;
+        water_renderer.apply_uniform(gl, &mvp, "mvp_transform").map_err(err_msg)?;
+        markers.apply_uniform(gl, &mvp, "mvp_transform").map_err(err_msg)?;
 
         let controls = Controls::new();
+        let watcher = FileWatcher::new(res, &watched_files(config));
+        let brush = Brush::default();
+        let stroke_height = None;
+        let recorder = match &config.record {
+            Some(path) => Some(Recorder::create(&res.resource_path(path), &seeds)?),
+            None => None,
+        };
         let need_exit = false;
 
-        Ok(GameData { gl: gl.clone(), viewport, surface, mvp, color_buffer, controls, grid, water, need_exit })
+        let mut game_data = GameData {
+            gl: gl.clone(), res: res.clone(), viewport, surface, mvp, color_buffer, controls,
+            grid, grid_files: config.grid_files.clone(), griding_algo, resolution, sea_level: config.sea_level,
+            water, water_renderer, brush, stroke_height,
+            markers, edit_poles: false, selected_pole: None, pole_drag: None, watcher,
+            frame: 0, recorder, replay, need_exit,
+        };
+        game_data.fill_sea();
+        // The mode picked on the command line is part of the replay, its events of frame 0
+        // come before the first step
+        game_data.record(Event::UpdateMode(config.update_mode));
+        game_data.replay_input()?;
+        if let Some(snapshot) = &config.snapshot {
+            game_data.load_snapshot(&res.resource_path(snapshot))?;
+        }
+        Ok(game_data)
+    }
+
+    // Griding algorithm and resolution, then the terrain and the water sections
+    fn save_snapshot(&self, path: &Path) -> Result<(), failure::Error> {
+        let mut out = snapshot::Writer::new();
+        out.string(self.griding_algo.name());
+        out.usize(self.resolution.x);
+        out.usize(self.resolution.z);
+        out.usize(self.resolution.height);
+        self.grid.write_snapshot(&mut out);
+        self.water.write_snapshot(&mut out);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, out.into_bytes())?;
+        println!("Snapshot saved: {}", path.display());
+        Ok(())
+    }
+
+    // Nothing changes when the file is invalid. Terrain source files and filters are not
+    // read again, editing poles and switching the algorithm work on the saved poles
+    fn load_snapshot(&mut self, path: &Path) -> Result<(), failure::Error> {
+        let bytes = fs::read(path)?;
+        let mut input = snapshot::Reader::new(&bytes)?;
+        let algo_name = input.string()?;
+        let griding_algo = *GridingAlgo::ALL.iter().find(|algo| algo.name() == algo_name)
+            .ok_or_else(|| snapshot::Error::InvalidValue { what: "griding algorithm".into() })?;
+        let resolution = Resolution { x: input.usize()?, z: input.usize()?, height: input.usize()? };
+        if resolution.x.min(resolution.z).min(resolution.height) < MIN_RESOLUTION {
+            return Err(snapshot::Error::InvalidValue { what: "resolution".into() }.into());
+        }
+        let grid = Grid::from_snapshot(&mut input, self.grid.get_options(), self.grid.get_filters().clone())?;
+        if grid.get_data().len() != resolution.z || grid.get_data()[0].len() != resolution.x {
+            return Err(snapshot::Error::InvalidValue { what: "terrain size".into() }.into());
+        }
+        let mut water = Water::new(grid.get_data(), resolution.height, 0);
+        water.read_snapshot(&mut input)?;
+        water.set_update_mode(self.water.get_update_mode());
+        input.finish()?;
+
+        if grid.get_aspect() != self.grid.get_aspect() {
+            self.mvp.set_aspect(&grid.get_aspect());
+            self.mvp.projection_recalc(self.viewport.w, self.viewport.h);
+        }
+        self.griding_algo = griding_algo;
+        self.resolution = resolution;
+        self.grid = grid;
+        self.water = water;
+        self.water_renderer.observe(&self.water);
+        self.surface.set_grid(self.grid.get_data())?;
+        self.selected_pole = None;
+        self.pole_drag = None;
+        self.stroke_height = None;
+        self.markers.set_poles(self.grid.get_user_poles(), None);
+        self.apply_uniforms()?;
+        println!("Snapshot loaded: {}, griding algorithm {}, resolution {}x{}, {} water layers",
+                 path.display(), griding_algo.name(), resolution.x, resolution.z, resolution.height);
+        Ok(())
+    }
+
+    // Sea level is kept in source units, so it follows a reloaded grid
+    fn fill_sea(&mut self) {
+        if let Some(sea_level) = self.sea_level {
+            self.water.fill_sea(self.grid.get_extents().height_from_world(sea_level));
+        }
     }
 
     pub fn resized(&mut self, w: i32, h: i32) -> Result<(), failure::Error> {
@@ -62,13 +274,36 @@ impl GameData {
             self.water.add_rain_particles();
         }
         self.water.modulate();
+        self.frame += 1;
         self.apply_uniforms().map_err(err_msg)
     }
 
-    pub fn render(&self) {
+    // A recording that fails to write stops, the simulation goes on
+    fn record(&mut self, event: Event) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record(self.frame, &event) {
+                print!("Recording to {} stopped: {}", recorder.get_path(), crate::debug::failure_to_string(e));
+                self.recorder = None;
+            }
+        }
+    }
+
+    // For changes that depend on files the replay would not read again
+    fn stop_recording(&mut self, reason: &str) {
+        if let Some(recorder) = self.recorder.take() {
+            println!("Recording to {} stopped: {}, the replay could not follow", recorder.get_path(), reason);
+        }
+    }
+
+    // Water changed by the simulation and the controls since the last frame is uploaded first
+    pub fn render(&mut self) {
+        self.water_renderer.sync(&self.water);
         self.color_buffer.clear(&self.gl);
         self.surface.render(&self.gl, gl::TRIANGLES); // TODO: add key for changing render mode
-        self.water.render(&self.gl, gl::TRIANGLES);
+        self.water_renderer.render(&self.gl, gl::TRIANGLES);
+        if self.edit_poles {
+            self.markers.render(&self.gl);
+        }
 
         // TODO: depth buffer
         unsafe {
@@ -82,7 +317,8 @@ impl GameData {
 
     fn apply_uniforms(&self) -> Result<(), failure::Error> {
         self.surface.apply_uniform(&self.gl, &self.mvp, "mvp_transform").map_err(err_msg)?;
-        self.water.apply_uniform(&self.gl, &self.mvp, "mvp_transform").map_err(err_msg)?;
+        self.water_renderer.apply_uniform(&self.gl, &self.mvp, "mvp_transform").map_err(err_msg)?;
+        self.markers.apply_uniform(&self.gl, &self.mvp, "mvp_transform").map_err(err_msg)?;
         Ok(())
     }
 
@@ -0,0 +1,107 @@
+use crate::gl_render::{self, buffer, data};
+use crate::resources::Resources;
+use std::ffi::CString;
+use crate::camera::MVP;
+use failure::err_msg;
+use gl_render::uniform;
+
+pub const SHADER: &str = "shaders/markers";
+const MARKER_HALF_SIZE: f32 = 0.02;
+const MARKER_COLOR: (f32, f32, f32) = (1., 0.4, 0.);
+const SELECTED_COLOR: (f32, f32, f32) = (1., 1., 0.);
+
+#[derive(VertexAttribPointers)]
+#[derive(Copy, Clone, Debug)]
+#[repr(C, packed)]
+struct Vertex {
+    #[location = 0]
+    pos: data::f32_f32_f32,
+    #[location = 1]
+    color: data::f32_f32_f32,
+}
+
+// User poles drawn as lines: a stem from the ground and a cross at the pole height
+pub struct PoleMarkers {
+    program: gl_render::Program,
+    vbo: buffer::ArrayBuffer,
+    vao: buffer::VertexArray,
+    vertex_count: usize,
+}
+
+impl PoleMarkers {
+    pub fn new(res: &Resources, gl: &gl::Gl) -> Result<PoleMarkers, failure::Error> {
+        let program = gl_render::Program::from_res(gl, res, SHADER)?;
+
+        let vbo = buffer::ArrayBuffer::new(gl);
+        let vao = buffer::VertexArray::new(gl);
+        vao.bind();
+        vbo.bind();
+        Vertex::vertex_attrib_pointers(gl);
+        vbo.unbind();
+        vao.unbind();
+
+        Ok(PoleMarkers {
+            program,
+            vbo,
+            vao,
+            vertex_count: 0,
+        })
+    }
+
+    pub fn set_poles(&mut self, poles: &[na::Vector3<f32>], selected: Option<usize>) {
+        let mut vertices: Vec<Vertex> = Vec::with_capacity(poles.len() * 6);
+        for (i, pole) in poles.iter().enumerate() {
+            let color = match selected {
+                Some(selected) if selected == i => SELECTED_COLOR,
+                _ => MARKER_COLOR,
+            };
+            let mut line = |from: (f32, f32, f32), to: (f32, f32, f32)| {
+                vertices.push(Vertex { pos: from.into(), color: color.into() });
+                vertices.push(Vertex { pos: to.into(), color: color.into() });
+            };
+            line((pole.x, 0., pole.z), (pole.x, pole.y, pole.z));
+            line((pole.x - MARKER_HALF_SIZE, pole.y, pole.z), (pole.x + MARKER_HALF_SIZE, pole.y, pole.z));
+            line((pole.x, pole.y, pole.z - MARKER_HALF_SIZE), (pole.x, pole.y, pole.z + MARKER_HALF_SIZE));
+        }
+
+        self.vbo.bind();
+        self.vbo.dynamic_draw_data(&vertices);
+        self.vbo.unbind();
+        self.vertex_count = vertices.len();
+    }
+
+    // The running program stays when the new sources fail to build
+    pub fn reload_program(&mut self, gl: &gl::Gl, res: &Resources) -> Result<(), gl_render::Error> {
+        self.program = gl_render::Program::from_res(gl, res, SHADER)?;
+        Ok(())
+    }
+
+    pub fn render(&self, gl: &gl::Gl) {
+        self.program.use_it();
+        self.vao.bind();
+        unsafe {
+            gl.DrawArrays(gl::LINES, 0, self.vertex_count as i32);
+        }
+        self.vao.unbind();
+    }
+}
+
+impl uniform::HasUniform<MVP> for PoleMarkers {
+    fn apply_uniform(&self, gl: &gl::Gl, data: &MVP, name: &str) -> Result<(), failure::Error> {
+        self.program.use_it();
+        let name_cstr: CString = CString::new(name).map_err(err_msg)?;
+        let matrix: *const f32 = data.get_transform().as_slice().as_ptr();
+        unsafe {
+            let location = gl.GetUniformLocation(self.program.id(), name_cstr.as_ptr());
+            gl.UniformMatrix4fv(location, 1, gl::FALSE, matrix);
+        }
+        Ok(())
+    }
+}
+
+// A pole held by the mouse. The offset keeps it from jumping to the cursor when grabbed
+pub struct PoleDrag {
+    pub offset_x: f32,
+    pub offset_z: f32,
+    pub changed: bool,
+}
@@ -0,0 +1,224 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use crate::game_data::grid::GridingAlgo;
+use crate::game_data::grid::sculpt::{Brush, BrushMode};
+use crate::game_data::water::{Direction, UpdateMode};
+
+// Input log: a header with the seeds, then "<frame> <event> [values]" per line.
+// The frame is the number of simulation steps done before the event
+const HEADER: &str = "# mod1 replay";
+
+#[derive(Fail, Debug)]
+pub enum Error {
+    #[fail(display = "Replay {} line {}: {}", name, line, message)]
+    InvalidLine { name: String, line: usize, message: String },
+    #[fail(display = "Replay {}: events are not in frame order at line {}", name, line)]
+    FramesOutOfOrder { name: String, line: usize },
+    #[fail(display = "Replay event {} refers to pole {}, the terrain has {} poles", event, index, count)]
+    NoPole { event: String, index: usize, count: usize },
+}
+
+// Everything process_input does to the simulation
+#[derive(Debug)]
+#[derive(Copy, Clone)]
+pub enum Event {
+    Flush,
+    AddWater,
+    Wave(Direction),
+    Rain,
+    GridingAlgo(GridingAlgo),
+    ResolutionUp,
+    ResolutionDown,
+    NextTerrain,
+    ToggleFilters,
+    UpdateMode(UpdateMode),
+    Sculpt { brush: Brush, x: f32, z: f32, target: f32 },
+    // Pole edits change the poles only, the terrain follows on Regrid, height changes and deletions
+    AddPole { x: f32, y: f32, z: f32 },
+    MovePole { index: usize, x: f32, z: f32 },
+    PoleHeight { index: usize, y: f32 },
+    DeletePole { index: usize },
+    Regrid,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Flush => write!(f, "flush"),
+            Event::AddWater => write!(f, "add-water"),
+            Event::Wave(dir) => write!(f, "wave {}", direction_name(*dir)),
+            Event::Rain => write!(f, "rain"),
+            Event::GridingAlgo(algo) => write!(f, "algorithm {}", algo.name()),
+            Event::ResolutionUp => write!(f, "resolution-up"),
+            Event::ResolutionDown => write!(f, "resolution-down"),
+            Event::NextTerrain => write!(f, "next-terrain"),
+            Event::ToggleFilters => write!(f, "filters"),
+            Event::UpdateMode(mode) => write!(f, "update {}", mode.name()),
+            Event::Sculpt { brush, x, z, target } => write!(f, "sculpt {} {} {} {} {} {}",
+                brush_mode_name(brush.mode), brush.radius, brush.strength, x, z, target),
+            Event::AddPole { x, y, z } => write!(f, "add-pole {} {} {}", x, y, z),
+            Event::MovePole { index, x, z } => write!(f, "move-pole {} {} {}", index, x, z),
+            Event::PoleHeight { index, y } => write!(f, "pole-height {} {}", index, y),
+            Event::DeletePole { index } => write!(f, "delete-pole {}", index),
+            Event::Regrid => write!(f, "regrid"),
+        }
+    }
+}
+
+impl FromStr for Event {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let number = |value: &str| value.parse::<f32>().map_err(|_| format!("invalid number {}", value));
+        let index = |value: &str| value.parse::<usize>().map_err(|_| format!("invalid pole index {}", value));
+        match words.as_slice() {
+            ["flush"] => Ok(Event::Flush),
+            ["add-water"] => Ok(Event::AddWater),
+            ["wave", dir] => DIRECTIONS.iter().find(|(name, _)| name == dir)
+                .map(|(_, dir)| Event::Wave(*dir))
+                .ok_or_else(|| format!("invalid wave direction {}", dir)),
+            ["rain"] => Ok(Event::Rain),
+            ["algorithm", name] => GridingAlgo::ALL.iter().find(|algo| algo.name() == *name)
+                .map(|algo| Event::GridingAlgo(*algo))
+                .ok_or_else(|| format!("invalid griding algorithm {}", name)),
+            ["resolution-up"] => Ok(Event::ResolutionUp),
+            ["resolution-down"] => Ok(Event::ResolutionDown),
+            ["next-terrain"] => Ok(Event::NextTerrain),
+            ["filters"] => Ok(Event::ToggleFilters),
+            ["update", mode] => Ok(Event::UpdateMode(mode.parse()?)),
+            ["sculpt", mode, radius, strength, x, z, target] => {
+                let mode = BRUSH_MODES.iter().find(|(name, _)| name == mode)
+                    .map(|(_, mode)| *mode)
+                    .ok_or_else(|| format!("invalid brush mode {}", mode))?;
+                let brush = Brush { mode, radius: number(radius)?, strength: number(strength)? };
+                Ok(Event::Sculpt { brush, x: number(x)?, z: number(z)?, target: number(target)? })
+            },
+            ["add-pole", x, y, z] => Ok(Event::AddPole { x: number(x)?, y: number(y)?, z: number(z)? }),
+            ["move-pole", i, x, z] => Ok(Event::MovePole { index: index(i)?, x: number(x)?, z: number(z)? }),
+            ["pole-height", i, y] => Ok(Event::PoleHeight { index: index(i)?, y: number(y)? }),
+            ["delete-pole", i] => Ok(Event::DeletePole { index: index(i)? }),
+            ["regrid"] => Ok(Event::Regrid),
+            _ => Err(format!("invalid event {}", s)),
+        }
+    }
+}
+
+const DIRECTIONS: [(&str, Direction); 4] = [
+    ("north", Direction::North),
+    ("south", Direction::South),
+    ("east", Direction::East),
+    ("west", Direction::West),
+];
+
+const BRUSH_MODES: [(&str, BrushMode); 4] = [
+    ("raise", BrushMode::Raise),
+    ("lower", BrushMode::Lower),
+    ("flatten", BrushMode::Flatten),
+    ("smooth", BrushMode::Smooth),
+];
+
+fn direction_name(dir: Direction) -> &'static str {
+    DIRECTIONS.iter().find(|(_, d)| *d == dir).map_or("", |(name, _)| name)
+}
+
+fn brush_mode_name(mode: BrushMode) -> &'static str {
+    BRUSH_MODES.iter().find(|(_, m)| *m == mode).map_or("", |(name, _)| name)
+}
+
+// Seeds the run needs to give the same flood again
+#[derive(Debug)]
+#[derive(Copy, Clone)]
+pub struct Seeds {
+    pub simulation: u64,
+    pub terrain: Option<u64>,
+}
+
+// Every line is written at once, a crash keeps the log up to its last frame
+pub struct Recorder {
+    file: File,
+    path: String,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, seeds: &Seeds) -> Result<Recorder, failure::Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = File::create(path)?;
+        writeln!(file, "{}", HEADER)?;
+        writeln!(file, "seed {}", seeds.simulation)?;
+        if let Some(terrain) = seeds.terrain {
+            writeln!(file, "terrain-seed {}", terrain)?;
+        }
+        println!("Recording input to {}", path.display());
+        Ok(Recorder { file, path: path.display().to_string() })
+    }
+
+    pub fn record(&mut self, frame: u64, event: &Event) -> Result<(), failure::Error> {
+        writeln!(self.file, "{} {}", frame, event)?;
+        Ok(())
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+}
+
+pub struct Replay {
+    events: Vec<(u64, Event)>,
+    next: usize,
+    seeds: Seeds,
+}
+
+impl Replay {
+    // Seeds missing from the file are taken from the given ones
+    pub fn load(path: &Path, seeds: Seeds) -> Result<Replay, failure::Error> {
+        let text = fs::read_to_string(path)?;
+        let name = path.display().to_string();
+        let mut replay = Replay { events: vec![], next: 0, seeds };
+        for (index, line) in text.lines().enumerate() {
+            let invalid = |message: String| Error::InvalidLine { name: name.clone(), line: index + 1, message };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (first, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let value = || rest.trim().parse::<u64>().map_err(|_| invalid(format!("invalid seed {}", rest)));
+            match first {
+                "seed" => replay.seeds.simulation = value()?,
+                "terrain-seed" => replay.seeds.terrain = Some(value()?),
+                frame => {
+                    let frame = frame.parse::<u64>().map_err(|_| invalid(format!("invalid frame {}", frame)))?;
+                    if replay.events.last().is_some_and(|(last, _)| *last > frame) {
+                        return Err(Error::FramesOutOfOrder { name, line: index + 1 }.into());
+                    }
+                    replay.events.push((frame, rest.parse::<Event>().map_err(invalid)?));
+                },
+            }
+        }
+        println!("Replaying {}: {} events", name, replay.events.len());
+        Ok(replay)
+    }
+
+    pub fn get_seeds(&self) -> Seeds {
+        self.seeds
+    }
+
+    // Events of the frame, in the order they were recorded
+    pub fn take_due(&mut self, frame: u64) -> Vec<Event> {
+        let due: Vec<Event> = self.events[self.next..].iter()
+            .take_while(|(event_frame, _)| *event_frame <= frame)
+            .map(|(_, event)| *event)
+            .collect();
+        self.next += due.len();
+        due
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next == self.events.len()
+    }
+}
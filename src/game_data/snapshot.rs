@@ -0,0 +1,207 @@
+// Binary snapshot of a running simulation: little endian numbers, matrices as rows count,
+// row length and values. Every part writes and reads its own section in the same order
+const MAGIC: &[u8; 8] = b"MOD1SNAP";
+// 2: a raster is followed by its no data mask, 3: the griding algorithm is saved by name
+const VERSION: u32 = 3;
+
+#[derive(Fail, Debug)]
+pub enum Error {
+    #[fail(display = "Not a snapshot file")]
+    NotASnapshot,
+    #[fail(display = "Snapshot version {} is not supported, expected {}", version, expected)]
+    UnsupportedVersion { version: u32, expected: u32 },
+    #[fail(display = "Snapshot is truncated")]
+    Truncated,
+    #[fail(display = "Snapshot has invalid {}", what)]
+    InvalidValue { what: String },
+}
+
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        let mut writer = Writer { bytes: Vec::new() };
+        writer.bytes.extend_from_slice(MAGIC);
+        writer.u32(VERSION);
+        writer
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u128(&mut self, value: u128) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.bytes.extend_from_slice(value);
+    }
+
+    pub fn usize(&mut self, value: usize) {
+        self.u32(value as u32);
+    }
+
+    pub fn string(&mut self, value: &str) {
+        self.usize(value.len());
+        self.bytes(value.as_bytes());
+    }
+
+    pub fn vector3_f32(&mut self, value: &na::Vector3<f32>) {
+        value.iter().for_each(|&component| self.f32(component));
+    }
+
+    pub fn vector3_f64(&mut self, value: &na::Vector3<f64>) {
+        value.iter().for_each(|&component| self.f64(component));
+    }
+
+    pub fn matrix(&mut self, rows: &[Vec<f32>]) {
+        self.usize(rows.len());
+        self.usize(rows.first().map_or(0, |row| row.len()));
+        rows.iter().flatten().for_each(|&value| self.f32(value));
+    }
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Reader<'a>, Error> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(Error::NotASnapshot);
+        }
+        let mut reader = Reader { bytes: &bytes[MAGIC.len()..] };
+        match reader.u32()? {
+            VERSION => Ok(reader),
+            version => Err(Error::UnsupportedVersion { version, expected: VERSION }),
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        if self.bytes.len() < N {
+            return Err(Error::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(N);
+        self.bytes = tail;
+        let mut array = [0; N];
+        array.copy_from_slice(head);
+        Ok(array)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::InvalidValue { what: "flag".into() }),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_le_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    pub fn u128(&mut self) -> Result<u128, Error> {
+        Ok(u128::from_le_bytes(self.take()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, Error> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64, Error> {
+        Ok(f64::from_le_bytes(self.take()?))
+    }
+
+    pub fn bytes<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        self.take()
+    }
+
+    pub fn usize(&mut self) -> Result<usize, Error> {
+        Ok(self.u32()? as usize)
+    }
+
+    // A count of items of at least item_size bytes each, checked against what is left
+    pub fn count(&mut self, item_size: usize) -> Result<usize, Error> {
+        let count = self.usize()?;
+        match count.checked_mul(item_size) {
+            Some(size) if size <= self.bytes.len() => Ok(count),
+            _ => Err(Error::Truncated),
+        }
+    }
+
+    pub fn string(&mut self) -> Result<String, Error> {
+        let len = self.count(1)?;
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        String::from_utf8(head.to_vec()).map_err(|_| Error::InvalidValue { what: "text".into() })
+    }
+
+    pub fn vector3_f32(&mut self) -> Result<na::Vector3<f32>, Error> {
+        Ok(na::Vector3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    pub fn vector3_f64(&mut self) -> Result<na::Vector3<f64>, Error> {
+        Ok(na::Vector3::new(self.f64()?, self.f64()?, self.f64()?))
+    }
+
+    pub fn matrix(&mut self) -> Result<Vec<Vec<f32>>, Error> {
+        let rows = self.usize()?;
+        let row_len = self.usize()?;
+        let values = rows.checked_mul(row_len).ok_or(Error::Truncated)?;
+        match values.checked_mul(4) {
+            Some(size) if size <= self.bytes.len() => (),
+            _ => return Err(Error::Truncated),
+        }
+        (0..rows).map(|_| (0..row_len).map(|_| self.f32()).collect()).collect()
+    }
+
+    pub fn finish(&self) -> Result<(), Error> {
+        match self.bytes.is_empty() {
+            true => Ok(()),
+            false => Err(Error::InvalidValue { what: "trailing data".into() }),
+        }
+    }
+}
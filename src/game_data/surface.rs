@@ -5,6 +5,7 @@ use crate::camera::MVP;
 use failure::err_msg;
 use gl_render::uniform;
 
+pub const SHADER: &str = "shaders/surface";
 
 #[derive(VertexAttribPointers)]
 #[derive(Copy, Clone, Debug)]
@@ -29,10 +30,10 @@ pub struct Surface {
 
 impl Surface {
     pub fn new(res: &Resources, gl: &gl::Gl, grid: &[Vec<f32>]) -> Result<Surface, failure::Error> {
-        let program = gl_render::Program::from_res(gl, res, "shaders/surface")?;
+        let program = gl_render::Program::from_res(gl, res, SHADER)?;
 
         let vertices: Vec<Vertex> = generate_vertex_grid(grid)?;
-        let indices: Vec<u32> = generate_indices(grid.len())?;
+        let indices: Vec<u32> = generate_indices(grid.len(), grid[0].len())?;
 
         let vbo = buffer::ArrayBuffer::new(&gl);
         vbo.bind();
@@ -62,6 +63,12 @@ impl Surface {
         })
     }
 
+    // The running program stays when the new sources fail to build
+    pub fn reload_program(&mut self, gl: &gl::Gl, res: &Resources) -> Result<(), gl_render::Error> {
+        self.program = gl_render::Program::from_res(gl, res, SHADER)?;
+        Ok(())
+    }
+
     pub fn render(&self, gl: &gl::Gl, mode: gl::types::GLenum) {
         self.program.use_it();
         self.vao.bind();
@@ -88,43 +95,62 @@ impl Surface {
         self.ebo.unbind();
     }
 
+    // Heights changed, the grid size did not
+    pub fn update_heights(&mut self, grid: &[Vec<f32>]) -> Result<(), failure::Error> {
+        let vertices: Vec<Vertex> = generate_vertex_grid(grid)?;
+        self.vbo.bind();
+        self.vbo.static_draw_data(&vertices);
+        self.vbo.unbind();
+        Ok(())
+    }
+
     pub fn set_grid(&mut self, grid: &[Vec<f32>]) -> Result<(), failure::Error> {
         let vertices: Vec<Vertex> = generate_vertex_grid(grid)?;
-        let indices: Vec<u32> = generate_indices(grid.len())?;
+        let indices: Vec<u32> = generate_indices(grid.len(), grid[0].len())?;
         self._update_buffers(&vertices, &indices);
         Ok(())
     }
 }
 
+// Positions and triangle indices of the surface mesh, the same data that goes to the GPU
+pub fn generate_mesh(grid: &[Vec<f32>]) -> Result<(Vec<(f32, f32, f32)>, Vec<u32>), failure::Error> {
+    Ok((generate_positions(grid)?, generate_indices(grid.len(), grid[0].len())?))
+}
+
 fn generate_vertex_grid(grid: &[Vec<f32>]) -> Result<Vec<Vertex>, failure::Error> {
-    assert!(grid.len() > 1);
+    Ok(generate_positions(grid)?.into_iter().map(Vertex::from).collect())
+}
+
+fn generate_positions(grid: &[Vec<f32>]) -> Result<Vec<(f32, f32, f32)>, failure::Error> {
+    assert!(grid.len() > 1 && grid[0].len() > 1);
 
-    let step = 2. / (grid.len() - 1) as f32;
-    let mut coord: (f32, f32) = (-1. - step, -1. - step);   // (x, -z)
-    let mut vertices: Vec<Vertex> = vec![];
+    let step_x = 2. / (grid[0].len() - 1) as f32;
+    let step_z = 2. / (grid.len() - 1) as f32;
+    let mut coord: (f32, f32) = (-1. - step_x, -1. - step_z);   // (x, -z)
+    let mut positions: Vec<(f32, f32, f32)> = vec![];
 
     for row in grid {
-        assert_eq!(row.len(), grid.len());
-        coord.1 += step;
+        assert_eq!(row.len(), grid[0].len());
+        coord.1 += step_z;
         for elem in row {
-            coord.0 += step;
-            vertices.push((coord.0, *elem, coord.1).into());
+            coord.0 += step_x;
+            positions.push((coord.0, *elem, coord.1));
         }
-        coord.0 = -1. - step;
+        coord.0 = -1. - step_x;
     }
-    Ok(vertices)
+    Ok(positions)
 }
 
-fn generate_indices(grid_size: usize) -> Result<Vec<u32>, failure::Error> {
+fn generate_indices(rows: usize, row_size: usize) -> Result<Vec<u32>, failure::Error> {
     let mut indices: Vec<u32> = vec![];
-    for i in 0..(grid_size - 1) {
-        for j in 0..(grid_size - 1) {
-            indices.push((i * grid_size + j) as u32);
-            indices.push((i * grid_size + j + 1) as u32);
-            indices.push((i * grid_size + j + 1 + grid_size) as u32);
-            indices.push((i * grid_size + j) as u32);
-            indices.push((i * grid_size + j + grid_size) as u32);
-            indices.push((i * grid_size + j + grid_size + 1) as u32);
+    for i in 0..(rows - 1) {
+        for j in 0..(row_size - 1) {
+            indices.push((i * row_size + j) as u32);
+            indices.push((i * row_size + j + 1) as u32);
+            indices.push((i * row_size + j + 1 + row_size) as u32);
+            indices.push((i * row_size + j) as u32);
+            indices.push((i * row_size + j + row_size) as u32);
+            indices.push((i * row_size + j + row_size + 1) as u32);
         }
     }
     Ok(indices)
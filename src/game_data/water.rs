@@ -1,25 +1,22 @@
-extern crate chrono;
 extern crate rand;
+extern crate rand_chacha;
 
-mod vertex;
 mod particle_shape;
+mod double_buffer;
+pub mod renderer;
 
-use vertex::Vertex;
-use gl_render::{buffer, uniform};
-use resources::Resources;
-use crate::camera::MVP;
-use std::ffi::CString;
-use failure::err_msg;
-
-use chrono::prelude::*;
-use std::ops::{Index, IndexMut};
-use crate::game_data::GRID_WIDTH;
-use self::rand::Rng;
-use particle_shape::{ParticleShape, POINTS_PER_PARTICLE};
+use std::ops::{Index, IndexMut, Range};
+use std::collections::HashSet;
+use std::str::FromStr;
+use self::rand::{Rng, SeedableRng};
+use self::rand_chacha::ChaCha12Rng;
+use particle_shape::{ParticleShape, Strides};
+use crate::game_data::snapshot;
 
 
 #[derive(Debug)]
 #[derive(PartialEq)]
+#[derive(Copy, Clone)]
 enum Particle {
     Empty,
     Border(Direction),
@@ -50,98 +47,262 @@ impl std::ops::Not for Direction {
 }
 
 impl Direction {
-    pub fn rand() -> Direction {
-        match rand::thread_rng().gen_range(0..3) {
+    const ALL: [Direction; 4] = [Direction::North, Direction::South, Direction::East, Direction::West];
+
+    pub fn rand<R: Rng>(rng: &mut R) -> Direction {
+        Direction::from_roll(rng.gen_range(0..3))
+    }
+
+    fn from_roll(roll: u32) -> Direction {
+        match roll {
             0 => Direction::East,
             1 => Direction::West,
             2 => Direction::South,
             _ => Direction::North,
         }
-
     }
 }
 
+// The cellular automaton alone, it needs no GL context. WaterRenderer draws it,
+// the revisions tell it what changed since the last upload
 pub struct Water {
+    grid_x: usize,
+    grid_z: usize,
+    grid_height: usize,
+    floor: f32,
+    strides: Strides,
     water_level_max: usize,
     water_level: usize,
     grid: Vec<Vec<Vec<Particle>>>,
     locations: Vec<na::Vector3<usize>>,
     ib_data: Vec<ParticleShape>,
-    program: gl_render::Program,
-    vbo: buffer::ArrayBuffer,
-    ebo: buffer::ElementArrayBuffer,
-    vao: buffer::VertexArray,
+    rng: ChaCha12Rng,
+    update_mode: UpdateMode,
+    layout_revision: u64,
+    revision: u64,
 }
 
-const WATER_GRID_WIDTH: usize = GRID_WIDTH;
-const WATER_GIRD_HEIGHT: usize = GRID_WIDTH / 2;
-const WATER_RAIN_ITERATIONS: usize =
-    ((WATER_GRID_WIDTH * WATER_GIRD_HEIGHT) as f32 * 0.0001) as usize + 1;
-const WATER_GRAVITY_FORCE: i32 = 10;
+// In place is the original automaton, double buffered reads every particle from the previous
+// generation and does not depend on the order of locations
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Copy, Clone)]
+pub enum UpdateMode {
+    InPlace,
+    DoubleBuffered,
+}
 
-impl Water {
-    pub fn new(res: &Resources, gl: &gl::Gl, grid_heights: &[Vec<f32>]) -> Result<Water, failure::Error> {
-        let program = gl_render::Program::from_res(gl, res, "shaders/water")?;
+impl UpdateMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            UpdateMode::InPlace => "in-place",
+            UpdateMode::DoubleBuffered => "double-buffered",
+        }
+    }
 
-        let borders_h = WATER_GIRD_HEIGHT;
-        let grid = generate_borders(grid_heights, borders_h);
-        let water_level_max = borders_h;
-        let vertices = generate_vertex_grid(grid_heights, borders_h);
+    pub fn next(&self) -> UpdateMode {
+        match self {
+            UpdateMode::InPlace => UpdateMode::DoubleBuffered,
+            UpdateMode::DoubleBuffered => UpdateMode::InPlace,
+        }
+    }
+}
+
+impl FromStr for UpdateMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "in-place" => Ok(UpdateMode::InPlace),
+            "double-buffered" => Ok(UpdateMode::DoubleBuffered),
+            _ => Err(format!("invalid update mode {}", s)),
+        }
+    }
+}
 
-        let vbo = buffer::ArrayBuffer::new(&gl);
-        vbo.bind();
-        vbo.static_draw_data(&vertices);
-        vbo.unbind();
+// Size of the water vertex grid: terrain samples along X and Z, layers and the height of the lowest one
+#[derive(Debug)]
+#[derive(Copy, Clone)]
+pub struct Layout {
+    pub x: usize,
+    pub z: usize,
+    pub height: usize,
+    pub floor: f32,
+}
 
-        let ebo = buffer::ElementArrayBuffer::new(&gl);
+const WATER_RAIN_DENSITY: f32 = 0.0001;
+const WATER_GRAVITY_FORCE: i32 = 10;
 
-        let vao = buffer::VertexArray::new(&gl);
-        vao.bind();
-        vbo.bind();
-        Vertex::vertex_attrib_pointers(&gl);
-        ebo.bind();
-        vbo.unbind();
-        vao.unbind();
-        ebo.unbind();
+impl Water {
+    // Water columns follow the terrain grid, grid_height is the number of water layers
+    // Every random choice of the simulation comes from the seed, so the same seed and the same
+    // actions give the same flood. ChaCha12 is named instead of StdRng to keep the stream across rand releases
+    pub fn new(grid_heights: &[Vec<f32>], grid_height: usize, seed: u64) -> Water {
+        let (grid_x, grid_z) = (grid_heights[0].len(), grid_heights.len());
+        let borders_h = grid_height;
+        let floor = water_floor(grid_heights);
+        let grid = generate_borders(grid_heights, borders_h, floor);
+        let water_level_max = borders_h;
+        let strides = Strides::new(grid_x as u32, grid_height as u32);
 
         let water_level = 0;
         let locations = vec![];
         let ib_data = vec![];
 
-        Ok(Water {
+        Water {
+            grid_x, grid_z, grid_height, floor, strides,
             water_level_max, water_level,
             grid, locations, ib_data,
-            program, vbo, ebo, vao,
-        })
+            rng: ChaCha12Rng::seed_from_u64(seed),
+            update_mode: UpdateMode::InPlace,
+            layout_revision: 0,
+            revision: 0,
+        }
     }
 
-    pub fn render(&self, gl: &gl::Gl, mode: gl::types::GLenum) {
-        self.program.use_it();
-        self.vao.bind();
+    pub fn get_layout(&self) -> Layout {
+        Layout { x: self.grid_x, z: self.grid_z, height: self.grid_height, floor: self.floor }
+    }
 
-        unsafe {
-            gl.DrawElements(
-                mode,
-                self.ebo.get_elem_count() as i32,
-                gl::UNSIGNED_INT,
-                0 as *const gl::types::GLvoid,
-            )
-        }
-        self.vao.unbind();
+    // Changes whenever the layout does, the vertex grid has to be rebuilt then
+    pub fn get_layout_revision(&self) -> u64 {
+        self.layout_revision
+    }
+
+    // Changes whenever particles are added, moved or removed
+    pub fn get_revision(&self) -> u64 {
+        self.revision
     }
 
-    pub fn set_grid(&mut self, grid_heights: &[Vec<f32>]) {
-        let borders_h = WATER_GIRD_HEIGHT;
-        self.grid = generate_borders(grid_heights, borders_h);
+    // Two triangles per water particle, indices into the vertex grid of the layout
+    pub fn get_particle_shapes(&self) -> &[ParticleShape] {
+        &self.ib_data
+    }
+
+    // Expects flushed water when the resolution changes, particle indices depend on it
+    pub fn set_grid(&mut self, grid_heights: &[Vec<f32>], grid_height: usize) {
+        self.grid_x = grid_heights[0].len();
+        self.grid_z = grid_heights.len();
+        self.grid_height = grid_height;
+        self.strides = Strides::new(self.grid_x as u32, self.grid_height as u32);
+        let borders_h = grid_height;
+        self.floor = water_floor(grid_heights);
+        self.grid = generate_borders(grid_heights, borders_h, self.floor);
         self.water_level_max = borders_h;
-        let vertices = generate_vertex_grid(grid_heights, borders_h);
-        self._update_vbo(&vertices);
+        self.layout_revision += 1;
+        self.particles_changed();
+    }
+
+    // Rebuilds the border columns over the cells touched by a terrain edit, rows and cols are
+    // ranges of terrain samples. Water inside raised ground is pushed up its column, water next to
+    // dug ground is set moving again, nothing is flushed. Ground below the lowest water layer needs
+    // new layers: the water is flushed and the grid rebuilt then, false is returned
+    pub fn update_borders(&mut self, grid_heights: &[Vec<f32>], rows: Range<usize>, cols: Range<usize>) -> bool {
+        if water_floor(grid_heights) < self.floor {
+            self.flush();
+            self.set_grid(grid_heights, self.grid_height);
+            return false;
+        }
+        let z_cells = rows.start.saturating_sub(1)..rows.end.min(self.grid_z - 1);
+        let x_cells = cols.start.saturating_sub(1)..cols.end.min(self.grid_x - 1);
+        let mut removed: HashSet<(usize, usize, usize)> = HashSet::new();
+        let mut added: Vec<(usize, usize, usize)> = vec![];
+        let mut lowest_dug: Option<usize> = None;
+
+        for z in z_cells.clone() {
+            for x in x_cells.clone() {
+                let (cur_height, dir) = border_column(grid_heights, x, z, self.grid_height, self.floor);
+                let col = &mut self.grid[z][x];
+                let mut displaced: Vec<(Direction, i32)> = vec![];
+                for (y, particle) in col.iter_mut().enumerate() {
+                    match (y < cur_height, &particle) {
+                        (true, Particle::Water(water_dir, energy)) => {
+                            displaced.push((*water_dir, *energy));
+                            removed.insert((x, y, z));
+                            *particle = Particle::Border(dir);
+                        },
+                        (true, _) => *particle = Particle::Border(dir),
+                        (false, Particle::Border(_)) => {
+                            *particle = Particle::Empty;
+                            lowest_dug = Some(lowest_dug.map_or(y, |lowest| lowest.min(y)));
+                        },
+                        (false, _) => (),
+                    }
+                }
+                // Water that finds no room above is lost
+                for (y, particle) in col.iter_mut().enumerate().skip(cur_height) {
+                    if *particle == Particle::Empty {
+                        match displaced.pop() {
+                            Some((water_dir, energy)) => *particle = Particle::Water(water_dir, energy),
+                            None => break,
+                        }
+                        added.push((x, y, z));
+                    }
+                }
+            }
+        }
 
-        self.update_ebo();
-        self.update_vao();
+        // Settled water below the water level is not simulated, wake it up around a new hole
+        if let Some(lowest_dug) = lowest_dug.filter(|&lowest| lowest < self.water_level) {
+            self.water_level = lowest_dug;
+            let located: HashSet<(usize, usize, usize)> = self.locations.iter()
+                .map(|l| (l.x, l.y, l.z))
+                .chain(added.iter().copied())
+                .collect();
+            for z in z_cells.start.saturating_sub(1)..(z_cells.end + 1).min(self.grid_z - 1) {
+                for x in x_cells.start.saturating_sub(1)..(x_cells.end + 1).min(self.grid_x - 1) {
+                    for y in lowest_dug..self.grid_height {
+                        if let Particle::Water(_, _) = self.grid[z][x][y] {
+                            if !located.contains(&(x, y, z)) {
+                                self.grid[z][x][y] = Particle::Water(Direction::rand(&mut self.rng), WATER_GRAVITY_FORCE);
+                                added.push((x, y, z));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if !removed.is_empty() {
+            let (locations, ib_data) = self.locations.iter().zip(&self.ib_data)
+                .filter(|(location, _)| !removed.contains(&(location.x, location.y, location.z)))
+                .fold((vec![], vec![]), |mut acc, (location, index)| {
+                    acc.0.push(*location);
+                    acc.1.push(*index);
+                    acc
+                });
+            self.locations = locations;
+            self.ib_data = ib_data;
+        }
+        for (x, y, z) in added {
+            self.add_particle(x, y, z);
+        }
+        self.particles_changed();
+        true
     }
 
     pub fn modulate(&mut self) {
+        match self.update_mode {
+            UpdateMode::InPlace => self.modulate_in_place(),
+            UpdateMode::DoubleBuffered => self.modulate_double_buffered(),
+        }
+        self.update_water_level();
+        self.particles_changed();
+    }
+
+    pub fn get_update_mode(&self) -> UpdateMode {
+        self.update_mode
+    }
+
+    pub fn set_update_mode(&mut self, update_mode: UpdateMode) {
+        self.update_mode = update_mode;
+    }
+
+    // Particles in the order of locations, each one sees the moves of the ones before it
+    fn modulate_in_place(&mut self) {
+        let strides = &self.strides;
+        let (x_last, z_last) = (self.grid_x - 2, self.grid_z - 2);
+        let rng = &mut self.rng;
         for (loc, square) in self.locations.iter_mut().zip(&mut self.ib_data) {
             let x = loc.x;
             let y = loc.y;
@@ -151,7 +312,7 @@ impl Water {
                 _ => (Direction::East, WATER_GRAVITY_FORCE),
             };
 
-            let rnd_bool: bool = rand::random();
+            let rnd_bool: bool = rng.gen();
 
             if (loc.y < self.water_level) || (cur_energy <= 0) {
                 continue ;
@@ -171,71 +332,71 @@ impl Water {
                     self.grid[z][x][y] = Particle::Water(dir, cur_energy);
                 }
                 Particle::Water(_, energy) => {
-                    self.grid[z][x][y - 1] = Particle::Water(Direction::rand(), energy + 1);
+                    self.grid[z][x][y - 1] = Particle::Water(Direction::rand(rng), energy + 1);
                 }
             }
 
             if cur_dir == Direction::North {
                 if z == 0 {
-                    self.grid[z][x][y] = Particle::Water(Direction::rand(), cur_energy);
+                    self.grid[z][x][y] = Particle::Water(Direction::rand(rng), cur_energy);
                 }
                 else if (z > 0) && (self.grid[z - 1][x][y] == Particle::Empty) {
                     self.grid[z][x][y] = Particle::Empty;
                     self.grid[z - 1][x][y] = Particle::Water(cur_dir, cur_energy - 1);
                     loc.z = loc.z - 1;
-                    square.move_north();
+                    square.move_north(strides);
                 }
                 else if rnd_bool {
                     if (x > 0) && (self.grid[z][x - 1][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z][x - 1][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.x = loc.x - 1;
-                        square.move_west();
+                        square.move_west(strides);
                     }
-                    else if (x < WATER_GRID_WIDTH - 2) && (self.grid[z][x + 1][y] == Particle::Empty) {
+                    else if (x < x_last) && (self.grid[z][x + 1][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z][x + 1][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.x = loc.x + 1;
-                        square.move_east();
+                        square.move_east(strides);
                     }
                 }
                 else {
-                    if (x < WATER_GRID_WIDTH - 2) && (self.grid[z][x + 1][y] == Particle::Empty) {
+                    if (x < x_last) && (self.grid[z][x + 1][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z][x + 1][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.x = loc.x + 1;
-                        square.move_east();
+                        square.move_east(strides);
                     }
                     else if (x > 0) && (self.grid[z][x - 1][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z][x - 1][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.x = loc.x - 1;
-                        square.move_west();
+                        square.move_west(strides);
                     }
                 }
             }
             else if cur_dir == Direction::South {
-                if z >= WATER_GRID_WIDTH - 2 {
-                    self.grid[z][x][y] = Particle::Water(Direction::rand(), cur_energy);
+                if z >= z_last {
+                    self.grid[z][x][y] = Particle::Water(Direction::rand(rng), cur_energy);
                 }
-                if (z < WATER_GRID_WIDTH - 2) && (self.grid[z + 1][x][y] == Particle::Empty) {
+                if (z < z_last) && (self.grid[z + 1][x][y] == Particle::Empty) {
                     self.grid[z][x][y] = Particle::Empty;
                     self.grid[z + 1][x][y] = Particle::Water(cur_dir, cur_energy - 1);
                     loc.z = loc.z + 1;
-                    square.move_south();
+                    square.move_south(strides);
                 }
                 else if rnd_bool {
-                    if (x < WATER_GRID_WIDTH - 2) && (self.grid[z][x + 1][y] == Particle::Empty) {
+                    if (x < x_last) && (self.grid[z][x + 1][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z][x + 1][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.x = loc.x + 1;
-                        square.move_east();
+                        square.move_east(strides);
                     }
                     else if (x > 0) && (self.grid[z][x - 1][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z][x - 1][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.x = loc.x - 1;
-                        square.move_west();
+                        square.move_west(strides);
                     }
                 }
                 else {
@@ -243,38 +404,38 @@ impl Water {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z][x - 1][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.x = loc.x - 1;
-                        square.move_west();
+                        square.move_west(strides);
                     }
-                    else if (x < WATER_GRID_WIDTH - 2) && (self.grid[z][x + 1][y] == Particle::Empty) {
+                    else if (x < x_last) && (self.grid[z][x + 1][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z][x + 1][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.x = loc.x + 1;
-                        square.move_east();
+                        square.move_east(strides);
                     }
                 }
             }
             else if cur_dir == Direction::East {
-                if x >= WATER_GRID_WIDTH - 2 {
-                    self.grid[z][x][y] = Particle::Water(Direction::rand(), cur_energy);
+                if x >= x_last {
+                    self.grid[z][x][y] = Particle::Water(Direction::rand(rng), cur_energy);
                 }
-                if (x < WATER_GRID_WIDTH - 2) && (self.grid[z][x + 1][y] == Particle::Empty) {
+                if (x < x_last) && (self.grid[z][x + 1][y] == Particle::Empty) {
                     self.grid[z][x][y] = Particle::Empty;
                     self.grid[z][x + 1][y] = Particle::Water(cur_dir, cur_energy - 1);
                     loc.x = loc.x + 1;
-                    square.move_east();
+                    square.move_east(strides);
                 }
                 else if rnd_bool {
-                    if (z < WATER_GRID_WIDTH - 2) && (self.grid[z + 1][x][y] == Particle::Empty) {
+                    if (z < z_last) && (self.grid[z + 1][x][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z + 1][x][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.z = loc.z + 1;
-                        square.move_south();
+                        square.move_south(strides);
                     }
                     else if (z > 0) && (self.grid[z - 1][x][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z - 1][x][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.z = loc.z - 1;
-                        square.move_north();
+                        square.move_north(strides);
                     }
                 }
                 else {
@@ -282,60 +443,56 @@ impl Water {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z - 1][x][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.z = loc.z - 1;
-                        square.move_north();
+                        square.move_north(strides);
                     }
-                    else if (z < WATER_GRID_WIDTH - 2) && (self.grid[z + 1][x][y] == Particle::Empty) {
+                    else if (z < z_last) && (self.grid[z + 1][x][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z + 1][x][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.z = loc.z + 1;
-                        square.move_south();
+                        square.move_south(strides);
                     }
                 }
             }
             else if cur_dir == Direction::West {
                 if x <= 0 {
-                    self.grid[z][x][y] = Particle::Water(Direction::rand(), cur_energy);
+                    self.grid[z][x][y] = Particle::Water(Direction::rand(rng), cur_energy);
                 }
                 if (x > 0) && (self.grid[z][x - 1][y] == Particle::Empty) {
                     self.grid[z][x][y] = Particle::Empty;
                     self.grid[z][x - 1][y] = Particle::Water(cur_dir, cur_energy - 1);
                     loc.x = loc.x - 1;
-                    square.move_west();
+                    square.move_west(strides);
                 }
                 else if rnd_bool {
                     if (z > 0) && (self.grid[z - 1][x][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z - 1][x][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.z = loc.z - 1;
-                        square.move_north();
+                        square.move_north(strides);
                     }
-                    else if (z < WATER_GRID_WIDTH - 2) && (self.grid[z + 1][x][y] == Particle::Empty) {
+                    else if (z < z_last) && (self.grid[z + 1][x][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z + 1][x][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.z = loc.z + 1;
-                        square.move_south();
+                        square.move_south(strides);
                     }
                 }
                 else {
-                    if (z < WATER_GRID_WIDTH - 2) && (self.grid[z + 1][x][y] == Particle::Empty) {
+                    if (z < z_last) && (self.grid[z + 1][x][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z + 1][x][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.z = loc.z + 1;
-                        square.move_south();
+                        square.move_south(strides);
                     }
                     else if (z > 0) && (self.grid[z - 1][x][y] == Particle::Empty) {
                         self.grid[z][x][y] = Particle::Empty;
                         self.grid[z - 1][x][y] = Particle::Water(cur_dir, cur_energy - 3);
                         loc.z = loc.z - 1;
-                        square.move_north();
+                        square.move_north(strides);
                     }
                 }
             }
         }
-
-        self.update_water_level();
-        self.update_ebo();
-        self.update_vao();
     }
 
     pub fn flush(&mut self) {
@@ -355,8 +512,7 @@ impl Water {
         }
         self.water_level = 0;
 
-        self.update_ebo();
-        self.update_vao();
+        self.particles_changed();
     }
 
     pub fn _loop_add_water(&mut self) {
@@ -372,9 +528,22 @@ impl Water {
         self.fill_water_level(new_water_level);
     }
 
+    // Still water up to the given terrain height, layer by layer like increase_water_level
+    pub fn fill_sea(&mut self, sea_level: f32) {
+        let step_h = (1. - self.floor) / (self.grid_height - 1) as f32;
+        let top = ((sea_level - self.floor) / step_h).floor();
+        if top < 0. {
+            return;
+        }
+        let top = (top as usize).min(self.water_level_max - 2);
+        for level in self.water_level..=top {
+            self.fill_water_level(level);
+        }
+        self.water_level = top;
+    }
+
     fn fill_water_level(&mut self, level: usize) {
-        let xz_size = WATER_GRID_WIDTH as u32;
-        let y_size = WATER_GIRD_HEIGHT as u32;
+        let strides = self.strides;
         let mut cur_water_idx_x;
         let mut cur_water_idx_z = 0;
 
@@ -385,7 +554,7 @@ impl Water {
                     Particle::Empty => {
                         add_particle(&mut self.locations, &mut self.ib_data,
                                      cur_water_idx_x, level, cur_water_idx_z,
-                                     xz_size, y_size);
+                                     &strides);
                         Particle::Water(Direction::East, 0)
                     },
                     Particle::Water(any_dir, any_en) => Particle::Water(*any_dir, *any_en),
@@ -395,8 +564,7 @@ impl Water {
             }
             cur_water_idx_z += 1;
         }
-        self.update_ebo();
-        self.update_vao();
+        self.particles_changed();
     }
 
     fn update_water_level(&mut self) {
@@ -413,12 +581,13 @@ impl Water {
         }
 
         if need_up {
+            let (x_last, z_last) = (self.grid_x - 2, self.grid_z - 2);
             self.water_level = std::cmp::min(cur_water_level + 1, self.water_level_max);
             if self.water_level > 3 {
                 let v = self.locations.iter().zip(&self.ib_data)
                     .fold((vec![], vec![]), |mut acc, (location, index)| {
-                        if !((location.z > 0 && location.z < GRID_WIDTH - 2)
-                            && (location.x > 0 && location.x < GRID_WIDTH - 2)
+                        if !((location.z > 0 && location.z < z_last)
+                            && (location.x > 0 && location.x < x_last)
                             && (location.y < self.water_level - 1))
                         {
                             acc.0.push(*location);
@@ -433,11 +602,14 @@ impl Water {
     }
 
     pub fn add_rain_particles(&mut self) {
-        for _i in 0..WATER_RAIN_ITERATIONS {
-            let x = rand::thread_rng().gen_range(0..WATER_GRID_WIDTH - 2);
-            let z = rand::thread_rng().gen_range(0..WATER_GRID_WIDTH - 2);
-            let y   = WATER_GIRD_HEIGHT - 2;
-            let dir = match rand::thread_rng().gen_range(0..3) {
+        let (x_last, z_last) = (self.grid_x - 2, self.grid_z - 2);
+        let side = ((self.grid_x * self.grid_z) as f32).sqrt();
+        let rain_iterations = (side * self.grid_height as f32 * WATER_RAIN_DENSITY) as usize + 1;
+        for _i in 0..rain_iterations {
+            let x = self.rng.gen_range(0..x_last);
+            let z = self.rng.gen_range(0..z_last);
+            let y   = self.grid_height - 2;
+            let dir = match self.rng.gen_range(0..3) {
                 0 => Direction::West,
                 1 => Direction::East,
                 2 => Direction::North,
@@ -449,107 +621,190 @@ impl Water {
                 self.add_particle(x, y, z);
             }
         }
-        self.update_ebo();
-        self.update_vao();
+        self.particles_changed();
     }
 
     pub fn add_wave_particles(&mut self, dir: Direction) {
-        let y_range = 0..(WATER_GIRD_HEIGHT / 3 * 2) as usize;
+        let y_range = 0..self.grid_height / 3 * 2;
+        let (x_last, x_end) = (self.grid_x - 2, self.grid_x - 1);
+        let (z_last, z_end) = (self.grid_z - 2, self.grid_z - 1);
 
         let (z_range, x_range) = match dir {
-            Direction::South => ((WATER_GRID_WIDTH - 2 .. WATER_GRID_WIDTH - 1), (0..WATER_GRID_WIDTH - 1)),
-            Direction::North => ((0..1), (0..WATER_GRID_WIDTH - 1)),
-            Direction::East => ((0..WATER_GRID_WIDTH - 1), (WATER_GRID_WIDTH - 2 .. WATER_GRID_WIDTH - 1)),
-            Direction::West => ((0..WATER_GRID_WIDTH - 1), (0..1)),
+            Direction::South => ((z_last .. z_end), (0..x_end)),
+            Direction::North => ((0..1), (0..x_end)),
+            Direction::East => ((0..z_end), (x_last .. x_end)),
+            Direction::West => ((0..z_end), (0..1)),
         };
 
         for z in z_range.clone() {
             for x in x_range.clone() {
                 for y in y_range.clone() {
                     if self.grid[z][x][y] == Particle::Empty {
-                        self.grid[z][x][y] = Particle::Water(!dir, (GRID_WIDTH * GRID_WIDTH) as i32);
+                        self.grid[z][x][y] = Particle::Water(!dir, (self.grid_x * self.grid_z) as i32);
                         self.add_particle(x, y, z);
                     }
                 }
             }
         }
 
-        self.update_ebo();
-        self.update_vao();
+        self.particles_changed();
+    }
+
+    // Corners of every drawn water quad in model space, in the order ParticleShape indexes them
+    pub fn particle_quads(&self) -> Vec<[(f32, f32, f32); 4]> {
+        let x_step = 2. / (self.grid_x - 1) as f32;
+        let z_step = 2. / (self.grid_z - 1) as f32;
+        let y_step = (1. - self.floor) / (self.grid_height - 1) as f32;
+        let corner = |x: usize, y: usize, z: usize| -> (f32, f32, f32) {
+            (-1. + x as f32 * x_step, self.floor + y as f32 * y_step, -1. + z as f32 * z_step)
+        };
+
+        self.locations.iter()
+            .map(|loc| [
+                corner(loc.x, loc.y, loc.z),
+                corner(loc.x + 1, loc.y, loc.z),
+                corner(loc.x + 1, loc.y, loc.z + 1),
+                corner(loc.x, loc.y, loc.z + 1),
+            ])
+            .collect()
     }
 
     fn add_particle(&mut self, x: usize, y: usize, z: usize) {
         add_particle(&mut self.locations, &mut self.ib_data,
                      x, y, z,
-                     WATER_GRID_WIDTH as u32, WATER_GIRD_HEIGHT as u32);
+                     &self.strides);
     }
 
-    fn _update_vbo(&self, vertices: &[Vertex]) {
-        self.vbo.bind();
-        self.vbo.static_draw_data(vertices);
-        self.vbo.unbind();
+    // Water section of a snapshot: every cell, the simulated particles in their order,
+    // the water level and the RNG position, so the run goes on exactly as it would have
+    pub fn write_snapshot(&self, out: &mut snapshot::Writer) {
+        out.usize(self.grid_x);
+        out.usize(self.grid_z);
+        out.usize(self.grid_height);
+        for particle in self.grid.iter().flatten().flatten() {
+            match particle {
+                Particle::Empty => out.u8(0),
+                Particle::Border(dir) => {
+                    out.u8(1);
+                    out.u8(*dir as u8);
+                },
+                Particle::Water(dir, energy) => {
+                    out.u8(2);
+                    out.u8(*dir as u8);
+                    out.i32(*energy);
+                },
+            }
+        }
+        out.usize(self.locations.len());
+        for location in &self.locations {
+            location.iter().for_each(|&coord| out.usize(coord));
+        }
+        out.usize(self.water_level);
+        out.bytes(&self.rng.get_seed());
+        out.u64(self.rng.get_stream());
+        out.u128(self.rng.get_word_pos());
     }
 
-    fn update_ebo(&mut self) {
-        self.ebo.bind();
-        self.ebo.dynamic_draw_data(&self.ib_data);
-        self.ebo.set_elem_count(self.ib_data.len() * POINTS_PER_PARTICLE);
-        self.ebo.unbind();
+    // Expects the water grid already set for the terrain of the snapshot,
+    // the water stays unchanged when the section does not match it
+    pub fn read_snapshot(&mut self, input: &mut snapshot::Reader) -> Result<(), snapshot::Error> {
+        let invalid = |what: &str| snapshot::Error::InvalidValue { what: what.into() };
+        let size = (input.usize()?, input.usize()?, input.usize()?);
+        if size != (self.grid_x, self.grid_z, self.grid_height) {
+            return Err(invalid("water grid size"));
+        }
+        let grid: Vec<Vec<Vec<Particle>>> = self.grid.iter()
+            .map(|side| side.iter()
+                .map(|col| (0..col.len()).map(|_| read_particle(input)).collect())
+                .collect())
+            .collect::<Result<_, _>>()?;
+
+        let locations_count = input.count(3 * 4)?;
+        let mut locations: Vec<na::Vector3<usize>> = Vec::with_capacity(locations_count);
+        for _ in 0..locations_count {
+            let (x, y, z) = (input.usize()?, input.usize()?, input.usize()?);
+            match grid.get(z).and_then(|side| side.get(x)).and_then(|col| col.get(y)) {
+                Some(Particle::Water(_, _)) => locations.push(na::Vector3::new(x, y, z)),
+                _ => return Err(invalid("particle location")),
+            }
+        }
+        let water_level = input.usize()?;
+        if water_level > self.water_level_max {
+            return Err(invalid("water level"));
+        }
+        let mut rng = ChaCha12Rng::from_seed(input.bytes()?);
+        rng.set_stream(input.u64()?);
+        rng.set_word_pos(input.u128()?);
+
+        let strides = self.strides;
+        self.ib_data = locations.iter()
+            .map(|l| ParticleShape::new(l.x as u32, l.y as u32, l.z as u32, &strides))
+            .collect();
+        self.grid = grid;
+        self.locations = locations;
+        self.water_level = water_level;
+        self.rng = rng;
+        self.particles_changed();
+        Ok(())
     }
 
-    fn update_vao(&self) {
-        self.vao.bind();
-        self.vbo.bind();
-        self.ebo.bind();
-        self.vbo.unbind();
-        self.vao.unbind();
-        self.ebo.unbind();
+    fn particles_changed(&mut self) {
+        self.revision += 1;
     }
 }
 
 fn add_particle(locations: &mut Vec<na::Vector3<usize>>, ib_data: &mut Vec<ParticleShape>,
                 x: usize, y: usize, z: usize,
-                xz_size: u32, y_size: u32) {
+                strides: &Strides) {
     locations.push(na::Vector3::new(x, y, z));
     ib_data.push(ParticleShape::new(
         x as u32,
         y as u32,
         z as u32,
-        xz_size,
-        y_size)
+        strides)
     );
 }
 
-fn generate_borders(grid_heights: &[Vec<f32>], borders_h: usize) -> Vec<Vec<Vec<Particle>>> {
-    let mut borders: Vec<Vec<Vec<Particle>>> = vec![];
-    let step_h = 1. / (borders_h - 1) as f32;
-
-    println!("Grid_heights rows: {}", grid_heights.len());
-    println!("Grid_heights elems: {}", grid_heights[0].len());
-
-    for (cur_row, nxt_row) in grid_heights.split_last().unwrap().1.iter().zip(grid_heights.split_first().unwrap().1) {
-        let mut side: Vec<Vec<Particle>> = Vec::with_capacity(WATER_GIRD_HEIGHT - 1);
-
-        for ((top_left, top_right), (bot_left, bot_right)) in
-                cur_row.split_last().unwrap().1.iter()
-                    .zip(cur_row.split_first().unwrap().1)
-                    .zip(nxt_row.split_last().unwrap().1.iter()
-                            .zip(nxt_row.split_first().unwrap().1))
-        {
-            let mut col: Vec<Particle> = Vec::with_capacity(borders_h);
-            let cur_height = ((top_left + top_right + bot_right + bot_left) / 4. / step_h).ceil() as usize;
-            let dir = get_direction(*top_left, *top_right, *bot_left, *bot_right);
-            for _i in 0..cur_height {
-                col.push(Particle::Border(dir));
-            }
-            for _i in cur_height..borders_h {
-                col.push(Particle::Empty);
-            }
-            side.push(col);
-        }
-        borders.push(side);
+fn read_particle(input: &mut snapshot::Reader) -> Result<Particle, snapshot::Error> {
+    let direction = |input: &mut snapshot::Reader| match Direction::ALL.get(input.u8()? as usize) {
+        Some(dir) => Ok(*dir),
+        None => Err(snapshot::Error::InvalidValue { what: "direction".into() }),
+    };
+    match input.u8()? {
+        0 => Ok(Particle::Empty),
+        1 => Ok(Particle::Border(direction(input)?)),
+        2 => Ok(Particle::Water(direction(input)?, input.i32()?)),
+        _ => Err(snapshot::Error::InvalidValue { what: "cell".into() }),
     }
-    borders
+}
+
+// Water layers start at the lowest terrain point below zero, so sea floors get water too
+fn water_floor(grid_heights: &[Vec<f32>]) -> f32 {
+    grid_heights.iter().flatten().copied().fold(0., f32::min)
+}
+
+fn generate_borders(grid_heights: &[Vec<f32>], borders_h: usize, floor: f32) -> Vec<Vec<Vec<Particle>>> {
+    (0..grid_heights.len() - 1).map(|z| {
+        (0..grid_heights[0].len() - 1).map(|x| {
+            let (cur_height, dir) = border_column(grid_heights, x, z, borders_h, floor);
+            (0..borders_h)
+                .map(|y| match y < cur_height {
+                    true => Particle::Border(dir),
+                    false => Particle::Empty,
+                })
+                .collect()
+        }).collect()
+    }).collect()
+}
+
+// Border particles count and slope direction of the water column over the cell x, z
+fn border_column(grid_heights: &[Vec<f32>], x: usize, z: usize,
+                 borders_h: usize, floor: f32) -> (usize, Direction) {
+    let step_h = (1. - floor) / (borders_h - 1) as f32;
+    let (top_left, top_right) = (grid_heights[z][x], grid_heights[z][x + 1]);
+    let (bot_left, bot_right) = (grid_heights[z + 1][x], grid_heights[z + 1][x + 1]);
+    let cur_height = (((top_left + top_right + bot_right + bot_left) / 4. - floor) / step_h).ceil() as usize;
+    (cur_height.min(borders_h), get_direction(top_left, top_right, bot_left, bot_right))
 }
 
 fn get_direction(top_left: f32, top_right: f32, bot_left: f32, bot_right: f32) -> Direction {
@@ -572,41 +827,173 @@ fn get_direction(top_left: f32, top_right: f32, bot_left: f32, bot_right: f32) -
     }
 }
 
-fn generate_vertex_grid(grid_heights: &[Vec<f32>], borders_h: usize) -> Vec<Vertex> {
-    let start = Utc::now();
-
-    let mut vertices: Vec<Vertex> = Vec::with_capacity(WATER_GRID_WIDTH * WATER_GRID_WIDTH * WATER_GIRD_HEIGHT);
-    let mut cur_coord = na::Vector3::new(-1., 0., -1.);
-    let xz_step = 2. / (WATER_GRID_WIDTH - 1) as f32;
-    let y_step = 1. / (WATER_GIRD_HEIGHT - 1) as f32;
-
-    for row in grid_heights {
-        cur_coord.x = -1.;
-        for _elem in row {
-            cur_coord.y = 0.;
-            for _i in 0..borders_h {
-                vertices.push(cur_coord.into());
-                cur_coord.y += y_step;
-            }
-            cur_coord.x += xz_step;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A bowl over a flat rim, every column keeps some ground so the water has a bottom
+    fn bowl(size: usize) -> Vec<Vec<f32>> {
+        let center = (size - 1) as f32 / 2.;
+        (0..size).map(|z| (0..size).map(|x| {
+            let distance = ((x as f32 - center).powi(2) + (z as f32 - center).powi(2)).sqrt() / center;
+            distance.min(1.) * 0.4 + 0.1
+        }).collect()).collect()
+    }
+
+    fn water_cells(water: &Water) -> usize {
+        water.grid.iter().flatten().flatten().filter(|particle| matches!(particle, Particle::Water(_, _))).count()
+    }
+
+    // Settled water below the water level may be left out of the locations
+    fn assert_located(water: &Water) {
+        assert_eq!(water.locations.len(), water.ib_data.len());
+        let cells: HashSet<(usize, usize, usize)> = water.locations.iter().map(|loc| (loc.x, loc.y, loc.z)).collect();
+        assert_eq!(cells.len(), water.locations.len());
+        for loc in &water.locations {
+            assert!(matches!(water.grid[loc.z][loc.x][loc.y], Particle::Water(_, _)));
         }
-        cur_coord.z += xz_step;
     }
 
-    let end = Utc::now();
-    println!("Gen Water Vertex Grid taken: {} ms", (end - start).num_milliseconds());
-    vertices
-}
+    #[test]
+    fn rain_falls_to_the_ground() {
+        let mut water = Water::new(&bowl(16), 20, 1);
+        water.add_rain_particles();
+        let drops = water.locations.len();
+        assert!(drops > 0);
+        assert!(water.locations.iter().all(|loc| loc.y == water.grid_height - 2));
+
+        let revision = water.get_revision();
+        for _ in 0..100 {
+            water.modulate();
+            assert_located(&water);
+        }
+        assert_eq!(water.locations.len(), drops);
+        assert!(water.get_revision() > revision);
+        for loc in &water.locations {
+            assert!(loc.y < water.grid_height - 2);
+            assert!(loc.y == 0 || water.grid[loc.z][loc.x][loc.y - 1] != Particle::Empty);
+        }
+    }
 
-impl uniform::HasUniform<MVP> for Water {
-    fn apply_uniform(&self, gl: &gl::Gl, data: &MVP, name: &str) -> Result<(), failure::Error> {
-        self.program.use_it();
-        let name_cstr: CString = CString::new(name).map_err(err_msg)?;
-        let matrix: *const f32 = data.get_transform().as_slice().as_ptr();
-        unsafe {
-            let location = gl.GetUniformLocation(self.program.id(), name_cstr.as_ptr() as *const i8);
-            gl.UniformMatrix4fv(location, 1, gl::FALSE, matrix);
+    #[test]
+    fn flush_removes_all_water() {
+        let mut water = Water::new(&bowl(16), 20, 1);
+        for _ in 0..4 {
+            water.increase_water_level();
         }
-        Ok(())
+        water.add_rain_particles();
+        water.modulate();
+        assert!(water_cells(&water) > 0);
+
+        water.flush();
+        assert_eq!(water_cells(&water), 0);
+        assert!(water.locations.is_empty() && water.ib_data.is_empty());
+        water.modulate();
+        assert_eq!(water_cells(&water), 0);
+    }
+
+    // Flat ground under a still water layer, moving particles go on top of it
+    fn still_pond() -> (Water, usize) {
+        let mut water = Water::new(&vec![vec![0.3; 12]; 12], 20, 1);
+        water.set_update_mode(UpdateMode::DoubleBuffered);
+        let ground = water.grid[0][0].iter().position(|particle| *particle == Particle::Empty).unwrap();
+        for column in water.grid.iter_mut().flatten() {
+            column[ground] = Particle::Water(Direction::East, WATER_GRAVITY_FORCE);
+        }
+        water.water_level = ground + 1;
+        (water, ground + 1)
+    }
+
+    fn place(water: &mut Water, (x, y, z): (usize, usize, usize), dir: Direction) {
+        water.grid[z][x][y] = Particle::Water(dir, WATER_GRAVITY_FORCE);
+        water.add_particle(x, y, z);
+    }
+
+    fn position(water: &Water, index: usize) -> (usize, usize, usize) {
+        let loc = water.locations[index];
+        (loc.x, loc.y, loc.z)
+    }
+
+    #[test]
+    fn double_buffered_does_not_depend_on_order() {
+        let run = |reverse: bool| {
+            let mut water = Water::new(&bowl(24), 24, 7);
+            water.add_wave_particles(Direction::East);
+            if reverse {
+                water.locations.reverse();
+                water.ib_data.reverse();
+            }
+            let mut grids = vec![];
+            for _ in 0..40 {
+                let before = water.locations.clone();
+                water.modulate_double_buffered();
+                assert_located(&water);
+                for (from, to) in before.iter().zip(&water.locations) {
+                    let moved = from.iter().zip(to.iter()).map(|(a, b)| a.max(b) - a.min(b)).sum::<usize>();
+                    assert!(moved <= 1);
+                }
+                grids.push(water.grid.clone());
+            }
+            grids
+        };
+        assert_eq!(run(false), run(true));
+    }
+
+    #[test]
+    fn contested_cell_goes_to_the_falling_particle() {
+        for reverse in [false, true] {
+            let (mut water, y) = still_pond();
+            let target = (5, y, 5);
+            let mut particles = [((5, y + 1, 5), Direction::North), ((4, y, 5), Direction::East)];
+            if reverse {
+                particles.reverse();
+            }
+            particles.iter().for_each(|&(cell, dir)| place(&mut water, cell, dir));
+            water.modulate();
+            let falling = particles.iter().position(|(cell, _)| cell.1 == y + 1).unwrap();
+            assert_eq!(position(&water, falling), target);
+            assert_eq!(position(&water, 1 - falling), (4, y, 5));
+        }
+    }
+
+    #[test]
+    fn contested_cell_goes_to_the_one_moving_ahead() {
+        for reverse in [false, true] {
+            let (mut water, y) = still_pond();
+            let target = (5, y, 5);
+            // Walls ahead and on the far side leave the target as the only way aside
+            for &(x, z) in &[(6, 4), (5, 3)] {
+                water.grid[z][x][y] = Particle::Water(Direction::East, WATER_GRAVITY_FORCE);
+            }
+            let mut particles = [((5, y, 4), Direction::East), ((4, y, 5), Direction::East)];
+            if reverse {
+                particles.reverse();
+            }
+            particles.iter().for_each(|&(cell, dir)| place(&mut water, cell, dir));
+            water.modulate();
+            let ahead = particles.iter().position(|(cell, _)| *cell == (4, y, 5)).unwrap();
+            assert_eq!(position(&water, ahead), target);
+            assert_eq!(position(&water, 1 - ahead), (5, y, 4));
+        }
+    }
+
+    #[test]
+    fn contested_cell_goes_to_one_of_equal_particles() {
+        let run = |reverse: bool| {
+            let (mut water, y) = still_pond();
+            let mut particles = [((4, y, 5), Direction::East), ((6, y, 5), Direction::West)];
+            if reverse {
+                particles.reverse();
+            }
+            particles.iter().for_each(|&(cell, dir)| place(&mut water, cell, dir));
+            water.modulate();
+            let entered: Vec<(usize, usize, usize)> = particles.iter().enumerate()
+                .filter(|&(index, _)| position(&water, index) == (5, y, 5))
+                .map(|(_, (cell, _))| *cell)
+                .collect();
+            assert_eq!(entered.len(), 1);
+            entered[0]
+        };
+        assert_eq!(run(false), run(true));
     }
 }
@@ -0,0 +1,196 @@
+use std::collections::{HashMap, HashSet};
+use rand::Rng;
+use super::{Direction, Particle, Water, WATER_GRAVITY_FORCE};
+
+type Cell = (usize, usize, usize);
+
+// Contested cells go to the falling particle first, then to the one moving along its
+// direction, then to one moving aside
+#[derive(PartialEq, PartialOrd)]
+#[derive(Copy, Clone)]
+enum MoveKind {
+    Aside,
+    Ahead,
+    Down,
+}
+
+#[derive(Copy, Clone)]
+enum Step {
+    Down,
+    Toward(Direction),
+}
+
+struct Plan {
+    stay: Particle,
+    moves: Option<(Step, MoveKind, Particle)>,
+}
+
+impl Water {
+    // Every particle is planned from the previous generation, then the moves are applied at once.
+    // The next generation is kept as the planned changes, a copy of the whole grid per step would
+    // cost more than the step. Random choices come from the cell and a key drawn per generation,
+    // so nothing depends on the order of locations. A particle moves one cell at most
+    pub(super) fn modulate_double_buffered(&mut self) {
+        let key: u64 = self.rng.gen();
+
+        // Active particles push the water under them, like in place
+        let mut pushed: HashSet<Cell> = HashSet::new();
+        for loc in &self.locations {
+            if let (Particle::Water(_, energy), Some(y)) = (self.grid[loc.z][loc.x][loc.y], loc.y.checked_sub(1)) {
+                if loc.y >= self.water_level && energy > 0 {
+                    if let Particle::Water(_, _) = self.grid[loc.z][loc.x][y] {
+                        pushed.insert((loc.x, y, loc.z));
+                    }
+                }
+            }
+        }
+
+        let plans: Vec<Plan> = self.locations.iter()
+            .map(|loc| self.plan((loc.x, loc.y, loc.z), key, pushed.contains(&(loc.x, loc.y, loc.z))))
+            .collect();
+
+        let mut winners: HashMap<Cell, usize> = HashMap::new();
+        for (index, plan) in plans.iter().enumerate() {
+            if let Some((step, kind, _)) = plan.moves {
+                let target = step_target(&self.locations[index], step);
+                let rank = (kind, approach_rank(step, key));
+                match winners.get(&target) {
+                    Some(&other) if rank_of(&plans[other], key) >= rank => (),
+                    _ => { winners.insert(target, index); },
+                }
+            }
+        }
+
+        for &(x, y, z) in &pushed {
+            if let Particle::Water(_, energy) = self.grid[z][x][y] {
+                self.grid[z][x][y] = Particle::Water(push_direction(key, (x, y, z)), energy + 1);
+            }
+        }
+        let strides = self.strides;
+        for (index, plan) in plans.into_iter().enumerate() {
+            let loc = &mut self.locations[index];
+            let square = &mut self.ib_data[index];
+            self.grid[loc.z][loc.x][loc.y] = plan.stay;
+            let (step, particle) = match plan.moves {
+                Some((step, _, particle)) if winners.get(&step_target(loc, step)) == Some(&index) => (step, particle),
+                _ => continue,
+            };
+            self.grid[loc.z][loc.x][loc.y] = Particle::Empty;
+            match step {
+                Step::Down => { loc.y -= 1; square.move_down(); },
+                Step::Toward(Direction::North) => { loc.z -= 1; square.move_north(&strides); },
+                Step::Toward(Direction::South) => { loc.z += 1; square.move_south(&strides); },
+                Step::Toward(Direction::East) => { loc.x += 1; square.move_east(&strides); },
+                Step::Toward(Direction::West) => { loc.x -= 1; square.move_west(&strides); },
+            }
+            self.grid[loc.z][loc.x][loc.y] = particle;
+        }
+    }
+
+    // The in place rules read from the previous generation only. A particle at the border
+    // turns randomly and still tries to move aside
+    fn plan(&self, (x, y, z): Cell, key: u64, pushed: bool) -> Plan {
+        let (mut dir, mut energy) = match self.grid[z][x][y] {
+            Particle::Water(dir, energy) => (dir, energy),
+            _ => (Direction::East, WATER_GRAVITY_FORCE),
+        };
+        if pushed {
+            dir = push_direction(key, (x, y, z));
+            energy += 1;
+        }
+        let stay = Particle::Water(dir, energy);
+        if y < self.water_level || energy <= 0 {
+            return Plan { stay: match pushed { true => stay, false => self.grid[z][x][y] }, moves: None };
+        }
+
+        match y.checked_sub(1).map(|below| self.grid[z][x][below]) {
+            Some(Particle::Empty) => return Plan {
+                stay,
+                moves: Some((Step::Down, MoveKind::Down, Particle::Water(dir, energy + WATER_GRAVITY_FORCE))),
+            },
+            Some(Particle::Border(border_dir)) => dir = border_dir,
+            _ => (),
+        }
+
+        let is_empty = |dir: Direction| match self.neighbour((x, y, z), dir) {
+            Some((x, y, z)) => self.grid[z][x][y] == Particle::Empty,
+            None => false,
+        };
+        let stay = match self.neighbour((x, y, z), dir) {
+            Some(_) => Particle::Water(dir, energy),
+            None => Particle::Water(Direction::from_roll(((cell_bits(key, (x, y, z)) >> 1) % 3) as u32), energy),
+        };
+        if is_empty(dir) {
+            return Plan { stay, moves: Some((Step::Toward(dir), MoveKind::Ahead, Particle::Water(dir, energy - 1))) };
+        }
+        let (first, second) = sides(dir, cell_bits(key, (x, y, z)) & 1 == 1);
+        let aside = [first, second].iter().copied().find(|&side| is_empty(side));
+        Plan {
+            stay,
+            moves: aside.map(|side| (Step::Toward(side), MoveKind::Aside, Particle::Water(dir, energy - 3))),
+        }
+    }
+
+    fn neighbour(&self, (x, y, z): Cell, dir: Direction) -> Option<Cell> {
+        let (x_last, z_last) = (self.grid_x - 2, self.grid_z - 2);
+        match dir {
+            Direction::North if z > 0 => Some((x, y, z - 1)),
+            Direction::South if z < z_last => Some((x, y, z + 1)),
+            Direction::East if x < x_last => Some((x + 1, y, z)),
+            Direction::West if x > 0 => Some((x - 1, y, z)),
+            _ => None,
+        }
+    }
+}
+
+// Side moves in the order the in place rules try them
+fn sides(dir: Direction, flip: bool) -> (Direction, Direction) {
+    let (first, second) = match dir {
+        Direction::North => (Direction::West, Direction::East),
+        Direction::South => (Direction::East, Direction::West),
+        Direction::East => (Direction::South, Direction::North),
+        Direction::West => (Direction::North, Direction::South),
+    };
+    match flip {
+        true => (first, second),
+        false => (second, first),
+    }
+}
+
+fn step_target(loc: &na::Vector3<usize>, step: Step) -> Cell {
+    match step {
+        Step::Down => (loc.x, loc.y - 1, loc.z),
+        Step::Toward(Direction::North) => (loc.x, loc.y, loc.z - 1),
+        Step::Toward(Direction::South) => (loc.x, loc.y, loc.z + 1),
+        Step::Toward(Direction::East) => (loc.x + 1, loc.y, loc.z),
+        Step::Toward(Direction::West) => (loc.x - 1, loc.y, loc.z),
+    }
+}
+
+// Particles entering a cell from different sides, the preferred side changes every generation
+fn approach_rank(step: Step, key: u64) -> u64 {
+    match step {
+        Step::Down => 0,
+        Step::Toward(dir) => (dir as u64).wrapping_add(key) % 4,
+    }
+}
+
+fn rank_of(plan: &Plan, key: u64) -> (MoveKind, u64) {
+    match plan.moves {
+        Some((step, kind, _)) => (kind, approach_rank(step, key)),
+        None => (MoveKind::Aside, 0),
+    }
+}
+
+// Random bits of a cell in the generation of the key (splitmix64 finalizer). The lowest one
+// orders the side moves, the next ones turn a particle at the border, the higher ones a pushed one
+fn cell_bits(key: u64, (x, y, z): Cell) -> u64 {
+    let mut value = key ^ ((x as u64) << 42 | (y as u64) << 21 | z as u64);
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d049bb133111eb);
+    value ^ (value >> 31)
+}
+
+fn push_direction(key: u64, cell: Cell) -> Direction {
+    Direction::from_roll(((cell_bits(key, cell) >> 8) % 3) as u32)
+}
@@ -1,7 +1,22 @@
-use crate::game_data::water::{WATER_GIRD_HEIGHT, WATER_GRID_WIDTH};
-
 pub const POINTS_PER_PARTICLE: usize = 6;
 
+// Index offsets between neighbouring vertices of the water vertex grid,
+// which is laid out z, then x, then y
+#[derive(Copy, Clone)]
+pub struct Strides {
+    column: u32,
+    row: u32,
+}
+
+impl Strides {
+    pub fn new(xz_size: u32, y_size: u32) -> Strides {
+        Strides {
+            column: y_size,
+            row: xz_size * y_size,
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 #[repr(C, packed)]
 pub struct ParticleShape {
@@ -10,11 +25,11 @@ pub struct ParticleShape {
 }
 
 impl ParticleShape {
-    pub fn new(x: u32, y: u32, z: u32, xz_size: u32, y_size: u32) -> ParticleShape {
-        let p0 = z * xz_size * y_size + x * y_size + y; // Top left
-        let p1 = p0 + y_size;                              // Top right
-        let p2 = p0 + y_size * (xz_size + 1);              // Bot right
-        let p3 = p0 + y_size * xz_size;                  // Bot left
+    pub fn new(x: u32, y: u32, z: u32, strides: &Strides) -> ParticleShape {
+        let p0 = z * strides.row + x * strides.column + y;  // Top left
+        let p1 = p0 + strides.column;                       // Top right
+        let p2 = p0 + strides.row + strides.column;         // Bot right
+        let p3 = p0 + strides.row;                          // Bot left
 
         ParticleShape {
             t0: (p0, p1, p2).into(),
@@ -27,24 +42,24 @@ impl ParticleShape {
         self.t1.move_down();
     }
 
-    pub fn move_north(&mut self) {
-        self.t0.move_north();
-        self.t1.move_north();
+    pub fn move_north(&mut self, strides: &Strides) {
+        self.t0.move_north(strides);
+        self.t1.move_north(strides);
     }
 
-    pub fn move_south(&mut self) {
-        self.t0.move_south();
-        self.t1.move_south();
+    pub fn move_south(&mut self, strides: &Strides) {
+        self.t0.move_south(strides);
+        self.t1.move_south(strides);
     }
 
-    pub fn move_west(&mut self) {
-        self.t0.move_west();
-        self.t1.move_west();
+    pub fn move_west(&mut self, strides: &Strides) {
+        self.t0.move_west(strides);
+        self.t1.move_west(strides);
     }
 
-    pub fn move_east(&mut self) {
-        self.t0.move_east();
-        self.t1.move_east();
+    pub fn move_east(&mut self, strides: &Strides) {
+        self.t0.move_east(strides);
+        self.t1.move_east(strides);
     }
 }
 
@@ -71,28 +86,27 @@ impl TriangleIdx {
         self.i2 -= 1;
     }
 
-    pub fn move_north(&mut self) {
-        self.i0 -= (WATER_GRID_WIDTH * WATER_GIRD_HEIGHT) as u32;
-        self.i1 -= (WATER_GRID_WIDTH * WATER_GIRD_HEIGHT) as u32;
-        self.i2 -= (WATER_GRID_WIDTH * WATER_GIRD_HEIGHT) as u32;
+    pub fn move_north(&mut self, strides: &Strides) {
+        self.i0 -= strides.row;
+        self.i1 -= strides.row;
+        self.i2 -= strides.row;
     }
 
-    pub fn move_south(&mut self) {
-        self.i0 += (WATER_GRID_WIDTH * WATER_GIRD_HEIGHT) as u32;
-        self.i1 += (WATER_GRID_WIDTH * WATER_GIRD_HEIGHT) as u32;
-        self.i2 += (WATER_GRID_WIDTH * WATER_GIRD_HEIGHT) as u32;
+    pub fn move_south(&mut self, strides: &Strides) {
+        self.i0 += strides.row;
+        self.i1 += strides.row;
+        self.i2 += strides.row;
     }
 
-    pub fn move_west(&mut self) {
-        self.i0 -= (WATER_GIRD_HEIGHT) as u32;
-        self.i1 -= (WATER_GIRD_HEIGHT) as u32;
-        self.i2 -= (WATER_GIRD_HEIGHT) as u32;
+    pub fn move_west(&mut self, strides: &Strides) {
+        self.i0 -= strides.column;
+        self.i1 -= strides.column;
+        self.i2 -= strides.column;
     }
 
-    pub fn move_east(&mut self) {
-        self.i0 += (WATER_GIRD_HEIGHT) as u32;
-        self.i1 += (WATER_GIRD_HEIGHT) as u32;
-        self.i2 += (WATER_GIRD_HEIGHT) as u32;
+    pub fn move_east(&mut self, strides: &Strides) {
+        self.i0 += strides.column;
+        self.i1 += strides.column;
+        self.i2 += strides.column;
     }
 }
-
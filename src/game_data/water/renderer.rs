@@ -0,0 +1,164 @@
+extern crate chrono;
+
+mod vertex;
+
+use vertex::Vertex;
+use gl_render::{buffer, uniform};
+use resources::Resources;
+use crate::camera::MVP;
+use std::ffi::CString;
+use failure::err_msg;
+use chrono::prelude::*;
+use super::{Layout, Water};
+use super::particle_shape::POINTS_PER_PARTICLE;
+
+pub const SHADER: &str = "shaders/water";
+
+// GL side of the water. It keeps the revisions of the simulation it has uploaded
+// and sends only what changed since
+pub struct WaterRenderer {
+    program: gl_render::Program,
+    vbo: buffer::ArrayBuffer,
+    ebo: buffer::ElementArrayBuffer,
+    vao: buffer::VertexArray,
+    layout_revision: u64,
+    revision: u64,
+}
+
+impl WaterRenderer {
+    pub fn new(res: &Resources, gl: &gl::Gl, water: &Water) -> Result<WaterRenderer, failure::Error> {
+        let program = gl_render::Program::from_res(gl, res, SHADER)?;
+
+        let vbo = buffer::ArrayBuffer::new(gl);
+        let ebo = buffer::ElementArrayBuffer::new(gl);
+
+        let vao = buffer::VertexArray::new(gl);
+        vao.bind();
+        vbo.bind();
+        Vertex::vertex_attrib_pointers(gl);
+        ebo.bind();
+        vbo.unbind();
+        vao.unbind();
+        ebo.unbind();
+
+        let mut renderer = WaterRenderer {
+            program, vbo, ebo, vao,
+            layout_revision: water.get_layout_revision(),
+            revision: water.get_revision(),
+        };
+        renderer.observe(water);
+        Ok(renderer)
+    }
+
+    // Uploads everything, the revisions of another Water instance start over
+    pub fn observe(&mut self, water: &Water) {
+        self.update_vbo(&water.get_layout());
+        self.update_ebo(water);
+        self.update_vao();
+        self.layout_revision = water.get_layout_revision();
+        self.revision = water.get_revision();
+    }
+
+    // The running program stays when the new sources fail to build
+    pub fn reload_program(&mut self, gl: &gl::Gl, res: &Resources) -> Result<(), gl_render::Error> {
+        self.program = gl_render::Program::from_res(gl, res, SHADER)?;
+        Ok(())
+    }
+
+    // Uploads the vertex grid when the water grid was rebuilt and the particles when they moved
+    pub fn sync(&mut self, water: &Water) {
+        let layout_changed = self.layout_revision != water.get_layout_revision();
+        let particles_changed = self.revision != water.get_revision();
+        if layout_changed {
+            self.update_vbo(&water.get_layout());
+            self.layout_revision = water.get_layout_revision();
+        }
+        if particles_changed {
+            self.update_ebo(water);
+            self.revision = water.get_revision();
+        }
+        if layout_changed || particles_changed {
+            self.update_vao();
+        }
+    }
+
+    pub fn render(&self, gl: &gl::Gl, mode: gl::types::GLenum) {
+        self.program.use_it();
+        self.vao.bind();
+
+        unsafe {
+            gl.DrawElements(
+                mode,
+                self.ebo.get_elem_count() as i32,
+                gl::UNSIGNED_INT,
+                0 as *const gl::types::GLvoid,
+            )
+        }
+        self.vao.unbind();
+    }
+
+    fn update_vbo(&self, layout: &Layout) {
+        let vertices = generate_vertex_grid(layout);
+        self.vbo.bind();
+        self.vbo.static_draw_data(&vertices);
+        self.vbo.unbind();
+    }
+
+    fn update_ebo(&mut self, water: &Water) {
+        let shapes = water.get_particle_shapes();
+        self.ebo.bind();
+        self.ebo.dynamic_draw_data(shapes);
+        self.ebo.set_elem_count(shapes.len() * POINTS_PER_PARTICLE);
+        self.ebo.unbind();
+    }
+
+    fn update_vao(&self) {
+        self.vao.bind();
+        self.vbo.bind();
+        self.ebo.bind();
+        self.vbo.unbind();
+        self.vao.unbind();
+        self.ebo.unbind();
+    }
+}
+
+// One vertex per water cell corner, laid out z, then x, then y as Strides expects
+fn generate_vertex_grid(layout: &Layout) -> Vec<Vertex> {
+    let start = Utc::now();
+
+    let mut vertices: Vec<Vertex> = Vec::with_capacity(layout.x * layout.z * layout.height);
+    let mut cur_coord = na::Vector3::new(-1., 0., -1.);
+    let x_step = 2. / (layout.x - 1) as f32;
+    let z_step = 2. / (layout.z - 1) as f32;
+    let y_step = (1. - layout.floor) / (layout.height - 1) as f32;
+
+    for _row in 0..layout.z {
+        cur_coord.x = -1.;
+        for _elem in 0..layout.x {
+            cur_coord.y = layout.floor;
+            for _i in 0..layout.height {
+                vertices.push(cur_coord.into());
+                cur_coord.y += y_step;
+            }
+            cur_coord.x += x_step;
+        }
+        cur_coord.z += z_step;
+    }
+
+    let end = Utc::now();
+    println!("Gen Water Vertex Grid taken: {} ms", (end - start).num_milliseconds());
+    vertices
+}
+
+impl uniform::HasUniform<MVP> for WaterRenderer {
+    fn apply_uniform(&self, gl: &gl::Gl, data: &MVP, name: &str) -> Result<(), failure::Error> {
+        self.program.use_it();
+        let name_cstr: CString = CString::new(name).map_err(err_msg)?;
+        let matrix: *const f32 = data.get_transform().as_slice().as_ptr();
+        unsafe {
+            let location = gl.GetUniformLocation(self.program.id(), name_cstr.as_ptr() as *const i8);
+            gl.UniformMatrix4fv(location, 1, gl::FALSE, matrix);
+        }
+        Ok(())
+    }
+}
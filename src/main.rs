@@ -11,27 +11,38 @@ use failure::err_msg;
 use sdl2::event::{Event, WindowEvent};
 use game_data::{controls::KeyStatus, GameData};
 use crate::initialization::{create_window, set_gl_attr};
+use config::Config;
 use std::env;
 
+mod config;
 mod debug;
 mod initialization;
 mod camera;
 mod game_data;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let grid_path = match args.len() {
-        1 => "grids/grid.mod1".to_owned(),
-        2 => "grids/".to_owned() + &args[1],
-        _ => { println!("Too much arguments"); return; }
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match Config::from_args(&args) {
+        Ok(config) => config,
+        Err(e) => { println!("{}\n{}", e, config::USAGE); return; }
     };
 
-    if let Err(e) = run(&grid_path) {
+    let result = match config.cross_validation {
+        true => cross_validate(&config),
+        false => run(&config),
+    };
+    if let Err(e) = result {
         println!("{}", debug::failure_to_string(e));
     }
 }
 
-fn run(grid_path: &str) -> Result<(), failure::Error> {
+// Report only, no window is opened
+fn cross_validate(config: &Config) -> Result<(), failure::Error> {
+    let res = resources::Resources::from_relative_exe_path(Path::new("assets"))?;
+    game_data::cross_validation::run(&res, config)
+}
+
+fn run(config: &Config) -> Result<(), failure::Error> {
     let sdl = sdl2::init().map_err(err_msg)?;
     let video_subsystem = sdl.video().map_err(err_msg)?;
     set_gl_attr(&video_subsystem);
@@ -42,7 +53,7 @@ fn run(grid_path: &str) -> Result<(), failure::Error> {
 
     let res = resources::Resources::from_relative_exe_path(Path::new("assets"))?;
 
-    let mut gd = GameData::new(&gl, &res, grid_path).map_err(err_msg)?;
+    let mut gd = GameData::new(&gl, &res, config).map_err(err_msg)?;
     gd.init();
 
     'main: loop {
@@ -65,6 +76,7 @@ fn run(grid_path: &str) -> Result<(), failure::Error> {
             break
         }
         gd.process_input()?;
+        gd.hot_reload()?;
         gd.render();
         window.gl_swap_window();
     }